@@ -0,0 +1,191 @@
+//! Derive macros for `ddb`. Use them through the re-exports in `ddb`.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, Lit, Meta, NestedMeta};
+
+///////////////////////////////////////////////////////////////////////////////
+// DERIVE FIELDS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Generates typed property handles for a model, see `ddb::HasFields`.
+#[proc_macro_derive(Fields, attributes(ddb))]
+pub fn derive_fields(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_fields(&input) {
+        Ok(x) => x.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_fields(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&input.generics, "ddb::Fields doesn't support generic types"));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(name, "ddb::Fields requires named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(name, "ddb::Fields only supports structs")),
+    };
+    let rename_all = serde_attr(&input.attrs, "rename_all")?;
+    let handles_name = Ident::new(&format!("{}Fields", name), Span::call_site());
+    let mut methods = Vec::new();
+    for field in fields.iter() {
+        let ident = field.ident.as_ref().expect("named field");
+        let ty = &field.ty;
+        // NEVER WRITTEN, SO NEVER FILTERABLE
+        if has_serde_flag(&field.attrs, "skip")? || has_serde_flag(&field.attrs, "skip_serializing")? {
+            continue;
+        }
+        if has_serde_flag(&field.attrs, "flatten")? {
+            methods.push(quote! {
+                pub fn #ident(&self) -> <#ty as ::ddb::HasFields>::Fields {
+                    <#ty as ::ddb::HasFields>::fields_at(self.prefix.clone())
+                }
+            });
+            continue;
+        }
+        let property = match serde_attr(&field.attrs, "rename")? {
+            Some(x) => x,
+            None => {
+                let ident = ident.to_string();
+                let ident = ident.trim_start_matches("r#");
+                match &rename_all {
+                    Some(rule) => apply_rename_rule(ident, rule)
+                        .ok_or_else(|| syn::Error::new_spanned(name, "unknown serde rename_all rule"))?,
+                    None => ident.to_owned(),
+                }
+            }
+        };
+        if has_ddb_flag(&field.attrs, "nested")? {
+            methods.push(quote! {
+                pub fn #ident(&self) -> <#ty as ::ddb::HasFields>::Fields {
+                    <#ty as ::ddb::HasFields>::fields_at(::ddb::field_path(&self.prefix, #property))
+                }
+            });
+        } else {
+            methods.push(quote! {
+                pub fn #ident(&self) -> ::ddb::Field<#ty> {
+                    ::ddb::Field::new(::ddb::field_path(&self.prefix, #property))
+                }
+            });
+        }
+    }
+    Ok(quote! {
+        #[derive(Debug, Clone)]
+        #vis struct #handles_name {
+            prefix: ::std::string::String,
+        }
+        impl #handles_name {
+            #(#methods)*
+        }
+        impl ::ddb::HasFields for #name {
+            type Fields = #handles_name;
+            fn fields_at(prefix: ::std::string::String) -> #handles_name {
+                #handles_name {prefix}
+            }
+        }
+        impl #name {
+            /// Typed handles for the properties of this model.
+            pub fn fields() -> #handles_name {
+                <#name as ::ddb::HasFields>::fields_at(::std::string::String::new())
+            }
+        }
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ATTRIBUTES
+///////////////////////////////////////////////////////////////////////////////
+
+fn nested_meta(attrs: &[syn::Attribute], path: &str) -> syn::Result<Vec<NestedMeta>> {
+    let mut result = Vec::new();
+    for attr in attrs.iter().filter(|x| x.path.is_ident(path)) {
+        if let Meta::List(list) = attr.parse_meta()? {
+            result.extend(list.nested);
+        }
+    }
+    Ok(result)
+}
+
+/// `#[serde(name = "value")]`, or the `serialize` name of
+/// `#[serde(name(serialize = "value"))]` since that's what is stored.
+fn serde_attr(attrs: &[syn::Attribute], name: &str) -> syn::Result<Option<String>> {
+    for meta in nested_meta(attrs, "serde")? {
+        match meta {
+            NestedMeta::Meta(Meta::NameValue(x)) if x.path.is_ident(name) => {
+                if let Lit::Str(x) = x.lit {
+                    return Ok(Some(x.value()));
+                }
+            }
+            // `#[serde(rename(serialize = "a", deserialize = "b"))]`
+            NestedMeta::Meta(Meta::List(x)) if x.path.is_ident(name) => {
+                for x in x.nested {
+                    if let NestedMeta::Meta(Meta::NameValue(x)) = x {
+                        if let (true, Lit::Str(x)) = (x.path.is_ident("serialize"), x.lit) {
+                            return Ok(Some(x.value()));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+fn has_flag(attrs: &[syn::Attribute], path: &str, name: &str) -> syn::Result<bool> {
+    let found = nested_meta(attrs, path)?
+        .into_iter()
+        .any(|x| match x {
+            NestedMeta::Meta(Meta::Path(x)) => x.is_ident(name),
+            _ => false,
+        });
+    Ok(found)
+}
+
+fn has_serde_flag(attrs: &[syn::Attribute], name: &str) -> syn::Result<bool> {
+    has_flag(attrs, "serde", name)
+}
+
+fn has_ddb_flag(attrs: &[syn::Attribute], name: &str) -> syn::Result<bool> {
+    has_flag(attrs, "ddb", name)
+}
+
+/// Same rules as serde's `rename_all`, applied to a snake_case field name.
+fn apply_rename_rule(field: &str, rule: &str) -> Option<String> {
+    let words = field.split('_').filter(|x| !x.is_empty());
+    let capitalize = |x: &str| {
+        let mut chars = x.chars();
+        match chars.next() {
+            Some(c) => c.to_uppercase().chain(chars).collect::<String>(),
+            None => String::new(),
+        }
+    };
+    let result = match rule {
+        "lowercase" => field.to_lowercase(),
+        "UPPERCASE" => field.to_uppercase(),
+        "PascalCase" => words.map(capitalize).collect(),
+        "camelCase" => {
+            let pascal = words.map(capitalize).collect::<String>();
+            let mut chars = pascal.chars();
+            match chars.next() {
+                Some(c) => c.to_lowercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        }
+        "snake_case" => field.to_owned(),
+        "SCREAMING_SNAKE_CASE" => field.to_uppercase(),
+        "kebab-case" => field.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => field.replace('_', "-").to_uppercase(),
+        _ => return None,
+    };
+    Some(result)
+}
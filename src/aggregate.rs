@@ -0,0 +1,139 @@
+use crate::db::{DatastoreClient, EntityKey, Error};
+use crate::query::{self, Filter};
+use crate::telemetry::{Operation, Rpc};
+
+static ALIAS: &str = "result";
+
+enum Aggregation<'a> {
+    Count,
+    Sum(&'a str),
+    Avg(&'a str),
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - AGGREGATION
+///////////////////////////////////////////////////////////////////////////////
+
+/// Aggregation queries run server side via `runAggregationQuery`. Where that
+/// endpoint isn't available (e.g. older emulators), they fall back to paging
+/// through the matching entities client side.
+impl DatastoreClient {
+    /// Number of `T` entities matching the filter.
+    ///
+    /// ```no_run
+    /// # use ddb::*;
+    /// # #[derive(serde::Serialize, serde::Deserialize)]
+    /// # struct TodoItem {name: String, done: bool}
+    /// # impl EntityKey for TodoItem {
+    /// #     fn entity_kind_key() -> String {String::from("TodoItem")}
+    /// #     fn entity_name_key(&self) -> String {self.name.clone()}
+    /// # }
+    /// let db = DatastoreClient::new().unwrap();
+    /// let open = db.count::<TodoItem, _>(Filter::eq("done", false)).unwrap();
+    /// let all = db.count::<TodoItem, _>(None).unwrap();
+    /// ```
+    pub fn count<T: EntityKey, F: Into<Option<Filter>>>(&self, filter: F) -> Result<u64, Error> {
+        self.count_kind(&T::entity_kind_key(), filter)
+    }
+    /// Same as `count`, for a kind with no Rust model.
+    pub fn count_kind<F: Into<Option<Filter>>>(&self, kind: &str, filter: F) -> Result<u64, Error> {
+        let mut query = query::kind_query(kind.to_owned(), filter.into().as_ref())?;
+        match self.aggregate(&query, Aggregation::Count)? {
+            Some(x) => Ok(to_f64(&x).unwrap_or_default() as u64),
+            None => {
+                query.projection = Some(query::projection(&["__key__"]));
+                self.run_query(query, None).map(|xs| xs.len() as u64)
+            }
+        }
+    }
+    /// Sum of a numeric property over the `T` entities matching the filter.
+    /// Entities where the property is missing or not a number are skipped.
+    pub fn sum<T: EntityKey, F: Into<Option<Filter>>>(&self, field: &str, filter: F) -> Result<f64, Error> {
+        let query = query::kind_query(T::entity_kind_key(), filter.into().as_ref())?;
+        match self.aggregate(&query, Aggregation::Sum(field))? {
+            Some(x) => Ok(to_f64(&x).unwrap_or_default()),
+            None => {
+                let xs = self.numeric_values(query, field)?;
+                Ok(xs.iter().sum())
+            }
+        }
+    }
+    /// Average of a numeric property over the `T` entities matching the
+    /// filter, or `None` if nothing matched.
+    pub fn avg<T: EntityKey, F: Into<Option<Filter>>>(&self, field: &str, filter: F) -> Result<Option<f64>, Error> {
+        let query = query::kind_query(T::entity_kind_key(), filter.into().as_ref())?;
+        match self.aggregate(&query, Aggregation::Avg(field))? {
+            Some(x) => Ok(to_f64(&x)),
+            None => {
+                let xs = self.numeric_values(query, field)?;
+                if xs.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(xs.iter().sum::<f64>() / xs.len() as f64))
+            }
+        }
+    }
+    /// `None` if the aggregation endpoint isn't available.
+    fn aggregate(&self, query: &google_datastore1::Query, aggregation: Aggregation) -> Result<Option<google_datastore1::Value>, Error> {
+        self.log_index(query);
+        let aggregation = match aggregation {
+            Aggregation::Count => serde_json::json!({
+                "alias": ALIAS,
+                "count": {},
+            }),
+            Aggregation::Sum(field) => serde_json::json!({
+                "alias": ALIAS,
+                "sum": {"property": {"name": field}},
+            }),
+            Aggregation::Avg(field) => serde_json::json!({
+                "alias": ALIAS,
+                "avg": {"property": {"name": field}},
+            }),
+        };
+        let body = serde_json::json!({
+            "partitionId": self.partition_id(None),
+            "aggregationQuery": {
+                "nestedQuery": query,
+                "aggregations": [aggregation],
+            }
+        });
+        let mut rpc = Rpc::new(Operation::RunAggregationQuery);
+        rpc.kind(query.kind.iter().flatten().next().and_then(|x| x.name.as_ref()));
+        let response = self.instrument(rpc, |_| self.call_raw("runAggregationQuery", body));
+        let response = match response {
+            Ok(x) => x,
+            Err(ref e) if is_unimplemented(e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let value = response
+            .pointer(&format!("/batch/aggregationResults/0/aggregateProperties/{}", ALIAS))
+            .cloned()
+            .and_then(|x| serde_json::from_value(x).ok())
+            .ok_or(Error::NoPayload)?;
+        Ok(Some(value))
+    }
+    fn numeric_values(&self, query: google_datastore1::Query, field: &str) -> Result<Vec<f64>, Error> {
+        let values = self.run_query(query, None)?
+            .into_iter()
+            .filter_map(|x| x.properties?.remove(field))
+            .filter_map(|x| to_f64(&x))
+            .collect();
+        Ok(values)
+    }
+}
+
+fn to_f64(value: &google_datastore1::Value) -> Option<f64> {
+    value.double_value
+        .or_else(|| value.integer_value.as_ref()?.parse().ok())
+}
+
+/// Only a 501 (or an `UNIMPLEMENTED` status) means there's no endpoint, a
+/// 404 is e.g. a wrong project or database and must not be hidden.
+fn is_unimplemented(error: &Error) -> bool {
+    match error {
+        Error::DatabaseResponse(google_datastore1::Error::BadRequest(e)) => {
+            e.error.code == 501 || e.error.errors.iter().any(|x| x.reason == "UNIMPLEMENTED")
+        }
+        _ => error.http_status() == Some(501),
+    }
+}
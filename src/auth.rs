@@ -1,6 +1,6 @@
 use std::rc::Rc;
 use std::cell::{Cell, RefCell, RefMut};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -15,7 +15,7 @@ pub type YupOAuth = yup_oauth2::ServiceAccountAccess<hyper::Client>;
 ///////////////////////////////////////////////////////////////////////////////
 
 /// RELATIVE TO THE HOME DIRECTORY
-pub static DEV_API_KEY_PATH: &'static str = ".config/gcloud-api-keys/dev.json";
+pub static DEV_API_KEY_PATH: &str = ".config/gcloud-api-keys/dev.json";
 
 
 #[derive(Debug, Clone, PartialEq)]
@@ -31,7 +31,7 @@ impl ApiKey {
         if output_path.exists() {
             Some(output_path)
         } else {
-            eprintln!("missing api keys at: ~/{}", DEV_API_KEY_PATH);
+            tracing::debug!("missing api keys at: ~/{}", DEV_API_KEY_PATH);
             None
         }
     }
@@ -70,14 +70,14 @@ impl ApiKey {
             std::env::var("GOOGLE_APPLICATION_CREDENTIALS")
                 .ok()
                 .and_then(|auth_file_path| {
-                    ApiKey::from_file(&PathBuf::from(auth_file_path))
+                    ApiKey::from_file(Path::new(&auth_file_path))
                 })
         };
         via_std_file_path()
             .or(via_env_var())
     }
-    pub fn from_file(file_path: &PathBuf) -> Option<Self> {
-        let file_path = file_path.clone();
+    pub fn from_file(file_path: &Path) -> Option<Self> {
+        let file_path = file_path.to_owned();
         let project_id = ApiKey::lookup_project_id(Some(file_path.clone()))?;
         Some(ApiKey {file_path, project_id})
     }
@@ -89,6 +89,30 @@ impl ApiKey {
 // EXTERNAL AUTH API
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Why `Auth::new` and friends found no usable credentials.
+#[derive(Debug)]
+pub enum AuthError {
+    /// No credentials file, see `Auth::new_via_api_key`.
+    NoCredentials,
+    /// The credentials file couldn't be read.
+    KeyFile(std::io::Error),
+    /// The GCP metadata server couldn't be reached, or answered with
+    /// something else than a project or token.
+    Metadata(reqwest::Error),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::NoCredentials => write!(f, "no credentials file found"),
+            AuthError::KeyFile(e) => write!(f, "could not read the credentials file: {}", e),
+            AuthError::Metadata(e) => write!(f, "metadata server error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
 #[derive(Debug, Clone)]
 pub struct Auth {
     pub(crate) project_id: String,
@@ -100,6 +124,18 @@ pub struct Auth {
 pub(crate) enum AuthInterface {
     ApiKey(YupOAuthInterface),
     Gcp(GcpAuthToken),
+    /// No credentials, e.g. for the emulator.
+    Anonymous,
+}
+
+impl AuthInterface {
+    fn name(&self) -> &'static str {
+        match self {
+            AuthInterface::ApiKey(_) => "api_key",
+            AuthInterface::Gcp(_) => "gcp",
+            AuthInterface::Anonymous => "anonymous",
+        }
+    }
 }
 
 
@@ -109,38 +145,34 @@ impl Auth {
     /// * `GOOGLE_APPLICATION_CREDENTIALS` environment variable
     /// 
     /// Expects the JSON file to contain a `project_id` field.
-    pub fn new_via_api_key() -> Result<Self, String> {
-        ApiKey::lookup()
-            .map(|auth| {
-                let project_id = auth.project_id.clone();
-                let key_file = auth.file_path
-                    .to_str()
-                    .expect("auth.file_path.to_str() failed");
-                let client_secret = yup_oauth2::service_account_key_from_file(&key_file.to_owned())
-                    .expect("yup_oauth2::service_account_key_from_file failed");
-                let client = hyper::Client::with_connector(
-                    hyper::net::HttpsConnector::new(hyper_rustls::TlsClient::new())
-                );
-                let access = yup_oauth2::ServiceAccountAccess::new(client_secret, client);
-                Auth {
-                    project_id: project_id,
-                    interface: AuthInterface::ApiKey(YupOAuthInterface(
-                        Rc::new(RefCell::new(access))
-                    )),
-                }
-            })
-            .ok_or(String::from("api key lookup failed"))
+    pub fn new_via_api_key() -> Result<Self, AuthError> {
+        let auth = ApiKey::lookup().ok_or(AuthError::NoCredentials)?;
+        let key_file = auth.file_path
+            .to_str()
+            .expect("auth.file_path.to_str() failed");
+        let client_secret = yup_oauth2::service_account_key_from_file(&key_file.to_owned())
+            .map_err(AuthError::KeyFile)?;
+        let client = hyper::Client::with_connector(
+            hyper::net::HttpsConnector::new(hyper_rustls::TlsClient::new())
+        );
+        let access = yup_oauth2::ServiceAccountAccess::new(client_secret, client);
+        Ok(Auth {
+            project_id: auth.project_id,
+            interface: AuthInterface::ApiKey(YupOAuthInterface(
+                Rc::new(RefCell::new(access))
+            )),
+        })
     }
     
     /// For instances running in google cloud platform.
     /// 
     /// Does not support refreshing. Should be fine if running from Google cloud run.
     /// Currently supports checking the metadata server for access tokens.
-    pub fn new_via_gcp() -> Result<Self, String> {
+    pub fn new_via_gcp() -> Result<Self, AuthError> {
         let project_id = GcpAuthToken::lookup_project_id()?;
         let access = GcpAuthToken::new()?;
         let result = Auth {
-            project_id: project_id,
+            project_id,
             interface: AuthInterface::Gcp(access),
         };
         Ok(result)
@@ -150,9 +182,28 @@ impl Auth {
     /// 
     /// See `Auth::new_via_api_key` and `Auth::new_via_gcp`
     /// for interface specific details.
-    pub fn new() -> Result<Self, String> {
+    pub fn new() -> Result<Self, AuthError> {
         Auth::new_via_api_key()
-            .or(Auth::new_via_gcp())
+            .or_else(|_| Auth::new_via_gcp())
+    }
+
+    /// No credentials at all, for the Datastore emulator.
+    pub fn anonymous<P: ToString>(project_id: P) -> Self {
+        Auth {
+            project_id: project_id.to_string(),
+            interface: AuthInterface::Anonymous,
+        }
+    }
+
+    /// Uses the credentials for a different project than the one they
+    /// were found with.
+    pub fn with_project_id<P: ToString>(mut self, project_id: P) -> Self {
+        self.project_id = project_id.to_string();
+        self
+    }
+
+    pub fn project_id(&self) -> &str {
+        &self.project_id
     }
 }
 
@@ -161,9 +212,10 @@ impl yup_oauth2::GetToken for Auth {
     fn api_key(&mut self) -> Option<String> {
         match &mut self.interface {
             AuthInterface::Gcp(x) => x.api_key(),
+            AuthInterface::Anonymous => None,
             AuthInterface::ApiKey(x) => x.0
                 .try_borrow_mut()
-                .map_err(|e| eprintln!("[warning] Auth (value already borrowed with a mutable reference!): {:?}", e))
+                .map_err(|e| tracing::warn!("Auth (value already borrowed with a mutable reference!): {:?}", e))
                 .ok()?
                 .api_key(),
         }
@@ -174,12 +226,49 @@ impl yup_oauth2::GetToken for Auth {
         T: AsRef<str> + Ord + 'b,
         I: IntoIterator<Item = &'b T>, 
     {
+        let span = tracing::debug_span!(
+            "datastore_token",
+            interface = self.interface.name(),
+            latency_ms = tracing::field::Empty,
+            outcome = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+        let start = std::time::Instant::now();
+        let result = self.fetch_token(scopes);
+        span.record("latency_ms", start.elapsed().as_secs_f64() * 1000.0);
+        match &result {
+            Ok(_) => {
+                span.record("outcome", "ok");
+            }
+            Err(e) => {
+                span.record("outcome", "error");
+                tracing::warn!(error = %e, "could not get an access token");
+            }
+        }
+        result
+    }
+}
+
+impl Auth {
+    fn fetch_token<'b, I, T>(&mut self, scopes: I) -> Result<yup_oauth2::Token, Box<dyn std::error::Error>>
+    where
+        T: AsRef<str> + Ord + 'b,
+        I: IntoIterator<Item = &'b T>, 
+    {
+        use yup_oauth2::GetToken;
         match &mut self.interface {
             AuthInterface::Gcp(x) => x.token(scopes),
+            AuthInterface::Anonymous => Ok(yup_oauth2::Token {
+                access_token: String::from("owner"),
+                refresh_token: String::new(),
+                token_type: String::from("Bearer"),
+                expires_in: None,
+                expires_in_timestamp: None,
+            }),
             AuthInterface::ApiKey(x) => x.0
                 .try_borrow_mut()
                 .map_err(|e| -> Box<dyn std::error::Error> {
-                    eprintln!("[warning] Auth (value already borrowed with a mutable reference!): {:?}", e);
+                    tracing::warn!("Auth (value already borrowed with a mutable reference!): {:?}", e);
                     Box::new(e)
                 })
                 .and_then(|mut x| x.token(scopes))
@@ -237,43 +326,42 @@ impl yup_oauth2::GetToken for GcpAuthToken {
 }
 
 impl GcpAuthToken {
-    pub fn lookup_project_id() -> Result<String, String> {
+    pub fn lookup_project_id() -> Result<String, AuthError> {
         let url = "http://metadata.google.internal/computeMetadata/v1/project/project-id";
         reqwest::Client::builder()
             .build()
-            .map_err(|e| format!("http client error: {:?}", e))
+            .map_err(AuthError::Metadata)
             .map(|x| x.get(url))
             .map(|x| x.header("Metadata-Flavor", "Google"))
             .and_then(|x| {
                 x
                     .send()
-                    .map_err(|e| format!("http client error: {:?}", e))
+                    .map_err(AuthError::Metadata)
             })
             .and_then(|mut x| {
                 x   .text()
-                    .map_err(|e| format!("http client error: {:?}", e))
+                    .map_err(AuthError::Metadata)
             })
     }
-    pub fn new() -> Result<Self, String> {
+    pub fn new() -> Result<Self, AuthError> {
         let url = format!(
             "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token?scopes={scopes}",
             scopes="https://www.googleapis.com/auth/cloud-platform",
         );
-        let result = reqwest::Client::builder()
+        reqwest::Client::builder()
             .build()
-            .map_err(|e| format!("http client error: {:?}", e))
+            .map_err(AuthError::Metadata)
             .map(|x| x.get(&url))
             .map(|x| x.header("Metadata-Flavor", "Google"))
             .and_then(|x| {
                 x
                     .send()
-                    .map_err(|e| format!("http client error: {:?}", e))
+                    .map_err(AuthError::Metadata)
             })
             .and_then(|mut x| {
                 x   .json::<GcpAuthToken>()
-                    .map_err(|e| format!("http client error: {:?}", e))
-            });
-        result
+                    .map_err(AuthError::Metadata)
+            })
     }
 }
 
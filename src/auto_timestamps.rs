@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::db::{DatastoreClient, EntityKey, Write};
+use crate::timestamp;
+
+///////////////////////////////////////////////////////////////////////////////
+// TIMESTAMPS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Properties the client keeps up to date on typed writes.
+///
+/// Once registered with `DatastoreClient::register_timestamps`, `insert`
+/// sets both `CREATED_AT` and `UPDATED_AT` to the current time, while
+/// `upsert` and `update` (and `BulkWriter`) only set `UPDATED_AT`, and
+/// write back the `CREATED_AT` the model was loaded with. Both are stored
+/// as real timestamps, so they can be filtered and ordered on, and load into
+/// `String` (RFC 3339) or `Option<String>` fields.
+///
+/// ```no_run
+/// # use ddb::*;
+/// # use serde::{Serialize, Deserialize};
+/// #[derive(Serialize, Deserialize)]
+/// struct TodoItem {
+///     name: String,
+///     created_at: Option<String>,
+///     modified: Option<String>,
+/// }
+/// # impl EntityKey for TodoItem {
+/// #     fn entity_kind_key() -> String {String::from("TodoItem")}
+/// #     fn entity_name_key(&self) -> String {self.name.clone()}
+/// # }
+/// impl Timestamps for TodoItem {
+///     const UPDATED_AT: Option<&'static str> = Some("modified");
+/// }
+/// let db = DatastoreClient::new().unwrap();
+/// db.register_timestamps::<TodoItem>();
+/// db.insert(TodoItem {name: String::from("test"), created_at: None, modified: None}).unwrap();
+/// let recent = db.query::<TodoItem>()
+///     .filter(Filter::gt("modified", Value::Timestamp(String::from("2020-01-01T00:00:00Z"))))
+///     .fetch()
+///     .unwrap();
+/// ```
+pub trait Timestamps {
+    /// `None` to leave it out.
+    const CREATED_AT: Option<&'static str> = Some("created_at");
+    /// `None` to leave it out.
+    const UPDATED_AT: Option<&'static str> = Some("updated_at");
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct TimestampFields {
+    created_at: Option<&'static str>,
+    updated_at: Option<&'static str>,
+}
+
+pub(crate) type TimestampRegistry = HashMap<String, TimestampFields>;
+
+fn timestamp_value(micros: i64) -> google_datastore1::Value {
+    google_datastore1::Value {
+        timestamp_value: Some(timestamp::format_micros(micros)),
+        ..google_datastore1::Value::default()
+    }
+}
+
+/// The existing time as a real timestamp, or `None` if it's missing, null
+/// or not a time.
+fn existing_time(value: Option<&google_datastore1::Value>) -> Option<google_datastore1::Value> {
+    let value = value?;
+    if value.timestamp_value.is_some() {
+        return Some(value.clone());
+    }
+    let micros = timestamp::parse_micros(value.string_value.as_ref()?)?;
+    Some(google_datastore1::Value {
+        exclude_from_indexes: value.exclude_from_indexes,
+        ..timestamp_value(micros)
+    })
+}
+
+fn micros(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_micros() as i64)
+        .unwrap_or(0)
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - TIMESTAMPS
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    /// Manages `T`'s timestamps on every typed write by this client (and its
+    /// clones).
+    pub fn register_timestamps<T: Timestamps + EntityKey>(&self) {
+        let fields = TimestampFields {
+            created_at: T::CREATED_AT,
+            updated_at: T::UPDATED_AT,
+        };
+        self.timestamps.borrow_mut().insert(T::entity_kind_key(), fields);
+    }
+    /// Where timestamps get the current time, e.g. a fixed sequence so
+    /// recorded cassettes replay.
+    pub fn with_clock(mut self, clock: fn() -> SystemTime) -> Self {
+        self.clock = clock;
+        self
+    }
+    /// Sets the timestamps of a model about to be written.
+    pub(crate) fn stamp_timestamps(&self, write: Write, entity: &mut google_datastore1::Entity) {
+        let kind = entity.key.as_ref()
+            .and_then(|x| x.path.as_ref())
+            .and_then(|x| x.last())
+            .and_then(|x| x.kind.as_ref());
+        let fields = match kind.and_then(|x| self.timestamps.borrow().get(x).copied()) {
+            Some(x) => x,
+            None => return,
+        };
+        let now = timestamp_value(micros((self.clock)()));
+        let properties = entity.properties.get_or_insert_with(HashMap::new);
+        if let Some(name) = fields.created_at {
+            let created = match write {
+                Write::Insert => Some(now.clone()),
+                // KEPT AS IT IS, JUST STORED AS A TIMESTAMP
+                Write::Upsert | Write::Update => existing_time(properties.get(name)),
+            };
+            if let Some(created) = created {
+                properties.insert(String::from(name), created);
+            }
+        }
+        if let Some(name) = fields.updated_at {
+            properties.insert(String::from(name), now);
+        }
+    }
+}
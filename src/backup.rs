@@ -0,0 +1,823 @@
+//! Datastore managed exports, read (and written) offline.
+//!
+//! A managed export (`gcloud datastore export`) is a directory of
+//! `.export_metadata` files and `output-N` files. The output files are
+//! LevelDB logs of serialized `EntityProto`s, which are decoded here into
+//! `Entity`s without going through BigQuery or an import.
+//!
+//! ```no_run
+//! use ddb::DatastoreClient;
+//! use ddb::backup::ExportReader;
+//!
+//! // ANALYZE
+//! let export = "2020-01-01T00:00:00_12345/all_namespaces/kind_TodoItem/all_namespaces_kind_TodoItem.export_metadata";
+//! for entity in ExportReader::open(export).unwrap() {
+//!     println!("{:?}", entity.unwrap().key);
+//! }
+//! // OR RESTORE INTO AN EMULATOR
+//! let db = DatastoreClient::new_emulator("localhost:8081", "dev").unwrap();
+//! db.restore(ExportReader::open(export).unwrap()).unwrap();
+//! ```
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use serde::de::DeserializeOwned;
+use crate::db::{DatastoreClient, Error};
+use crate::entity::{Entity, Key, KeyId, PathElement, Value};
+use crate::timestamp;
+
+///////////////////////////////////////////////////////////////////////////////
+// READER
+///////////////////////////////////////////////////////////////////////////////
+
+/// Entities from a single `output-N` file (or any LevelDB log of
+/// `EntityProto`s). Iteration stops after the first error.
+pub struct EntityReader<R> {
+    log: LogReader<R>,
+    failed: bool,
+}
+
+impl<R: Read> EntityReader<R> {
+    pub fn new(reader: R) -> Self {
+        EntityReader {log: LogReader::new(reader), failed: false}
+    }
+    /// Decodes every entity into a model, the same way
+    /// `DatastoreClient::get` would.
+    pub fn models<T: DeserializeOwned>(self) -> impl Iterator<Item = Result<T, Error>> {
+        self.map(|x| x?.to_model())
+    }
+}
+
+impl EntityReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = File::open(path).map_err(Error::Io)?;
+        Ok(EntityReader::new(BufReader::new(file)))
+    }
+}
+
+impl<R: Read> Iterator for EntityReader<R> {
+    type Item = Result<Entity, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.log
+            .next_record()
+            .transpose()?
+            .and_then(|x| decode_entity(&x));
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+/// Entities from every output file of an export.
+pub struct ExportReader {
+    files: std::vec::IntoIter<PathBuf>,
+    current: Option<EntityReader<BufReader<File>>>,
+}
+
+impl ExportReader {
+    /// Accepts a kind's `.export_metadata` file (reads the `output-N`
+    /// files next to it), an `.overall_export_metadata` file or a directory
+    /// (reads every `output-N` file below it), or a single output file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let files = export_files(path.as_ref())?;
+        Ok(ExportReader {files: files.into_iter(), current: None})
+    }
+    /// See `EntityReader::models`.
+    pub fn models<T: DeserializeOwned>(self) -> impl Iterator<Item = Result<T, Error>> {
+        self.map(|x| x?.to_model())
+    }
+}
+
+impl Iterator for ExportReader {
+    type Item = Result<Entity, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(x) = self.current.as_mut().and_then(|x| x.next()) {
+                return Some(x);
+            }
+            let path = self.files.next()?;
+            match EntityReader::open(&path) {
+                Ok(x) => self.current = Some(x),
+                Err(e) => {
+                    self.files = Vec::new().into_iter();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn export_files(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let name = path.file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default();
+    let parent = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut files = Vec::new();
+    if path.is_dir() {
+        find_output_files(path, true, &mut files)?;
+    } else if name.ends_with(".overall_export_metadata") {
+        find_output_files(parent, true, &mut files)?;
+    } else if name.ends_with(".export_metadata") {
+        find_output_files(parent, false, &mut files)?;
+    } else {
+        files.push(path.to_owned());
+    }
+    // OUTPUT-2 BEFORE OUTPUT-10
+    files.sort_by_key(|x| (x.parent().map(Path::to_owned), output_number(x)));
+    Ok(files)
+}
+
+fn find_output_files(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir).map_err(Error::Io)? {
+        let path = entry.map_err(Error::Io)?.path();
+        if path.is_dir() {
+            if recursive {
+                find_output_files(&path, recursive, files)?;
+            }
+        } else if output_number(&path).is_some() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn output_number(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("output-")?
+        .parse()
+        .ok()
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// WRITER
+///////////////////////////////////////////////////////////////////////////////
+
+/// Writes entities in the format of an `output-N` file, readable by
+/// `EntityReader` and other export tooling. The `.export_metadata` files
+/// Datastore needs to import a directory are not written.
+pub struct EntityWriter<W: Write> {
+    log: LogWriter<W>,
+    project_id: String,
+}
+
+impl<W: Write> EntityWriter<W> {
+    /// Keys are written with `project_id` as their app.
+    pub fn new<P: ToString>(writer: W, project_id: P) -> Self {
+        EntityWriter {
+            log: LogWriter::new(writer),
+            project_id: project_id.to_string(),
+        }
+    }
+    /// The entity needs a complete key.
+    pub fn write(&mut self, entity: &Entity) -> Result<(), Error> {
+        if !entity.key.as_ref().map(Key::is_complete).unwrap_or(false) {
+            return Err(Error::IncompleteKey);
+        }
+        let record = encode_entity(entity, &self.project_id)?;
+        self.log.write_record(&record).map_err(Error::Io)
+    }
+    /// Flushes and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.log.writer.flush().map_err(Error::Io)?;
+        Ok(self.log.writer)
+    }
+}
+
+impl EntityWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>, I: ToString>(path: P, project_id: I) -> Result<Self, Error> {
+        let file = File::create(path).map_err(Error::Io)?;
+        Ok(EntityWriter::new(BufWriter::new(file), project_id))
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - RESTORE
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    /// Upserts entities, e.g. from an `ExportReader`. Keys without a
+    /// namespace go to the client's namespace. Returns the number of
+    /// entities written.
+    pub fn restore<I>(&self, entities: I) -> Result<u64, Error>
+    where
+        I: IntoIterator<Item = Result<Entity, Error>>,
+    {
+        let mutations = entities.into_iter().map(|entity| {
+            let entity = entity?;
+            if !entity.key.as_ref().map(Key::is_complete).unwrap_or(false) {
+                return Err(Error::IncompleteKey);
+            }
+            Ok(google_datastore1::Mutation {
+                upsert: Some(entity.into_datastore()),
+                ..google_datastore1::Mutation::default()
+            })
+        });
+        self.commit_chunked(mutations)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// LEVELDB LOG
+///////////////////////////////////////////////////////////////////////////////
+
+static BLOCK_SIZE: usize = 32 * 1024;
+/// Checksum (4 bytes), length (2 bytes) and record type (1 byte).
+static HEADER_SIZE: usize = 7;
+
+const FULL: u8 = 1;
+const FIRST: u8 = 2;
+const MIDDLE: u8 = 3;
+const LAST: u8 = 4;
+
+struct LogReader<R> {
+    reader: R,
+    block: Vec<u8>,
+    offset: usize,
+}
+
+impl<R: Read> LogReader<R> {
+    fn new(reader: R) -> Self {
+        LogReader {reader, block: Vec::with_capacity(BLOCK_SIZE), offset: 0}
+    }
+    /// False at the end of the file.
+    fn read_block(&mut self) -> Result<bool, Error> {
+        self.block.clear();
+        self.offset = 0;
+        (&mut self.reader)
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut self.block)
+            .map_err(Error::Io)?;
+        Ok(!self.block.is_empty())
+    }
+    /// Reassembles the next record from its fragments.
+    fn next_record(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut record: Option<Vec<u8>> = None;
+        loop {
+            // ANYTHING SHORTER THAN A HEADER AT THE END OF A BLOCK IS PADDING
+            if self.block.len() - self.offset < HEADER_SIZE {
+                if self.read_block()? {
+                    continue;
+                }
+                return match record {
+                    Some(_) => Err(corrupt("file ends inside a record")),
+                    None => Ok(None),
+                };
+            }
+            let header = &self.block[self.offset..self.offset + HEADER_SIZE];
+            let checksum = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+            let kind = header[6];
+            // ZEROED SPACE OF A PREALLOCATED FILE
+            if kind == 0 && len == 0 {
+                self.offset = self.block.len();
+                continue;
+            }
+            let start = self.offset + HEADER_SIZE;
+            let end = start + len;
+            if end > self.block.len() {
+                return Err(corrupt("record runs past the end of its block"));
+            }
+            self.offset = end;
+            let data = &self.block[start..end];
+            if unmask(checksum) != crc32c(crc32c(0, &[kind]), data) {
+                return Err(corrupt("record checksum mismatch"));
+            }
+            match (kind, record.as_mut()) {
+                (FULL, None) => return Ok(Some(data.to_vec())),
+                (FIRST, None) => record = Some(data.to_vec()),
+                (MIDDLE, Some(x)) => x.extend_from_slice(data),
+                (LAST, Some(x)) => {
+                    x.extend_from_slice(data);
+                    return Ok(record);
+                }
+                _ => return Err(corrupt("unexpected record fragment")),
+            }
+        }
+    }
+}
+
+struct LogWriter<W> {
+    writer: W,
+    /// Bytes written to the current block.
+    offset: usize,
+}
+
+impl<W: Write> LogWriter<W> {
+    fn new(writer: W) -> Self {
+        LogWriter {writer, offset: 0}
+    }
+    /// Splits the record into fragments at block boundaries.
+    fn write_record(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let mut rest = data;
+        let mut is_first = true;
+        loop {
+            let left = BLOCK_SIZE - self.offset;
+            if left < HEADER_SIZE {
+                self.writer.write_all(&[0; 6][..left])?;
+                self.offset = 0;
+                continue;
+            }
+            let len = rest.len().min(left - HEADER_SIZE);
+            let is_last = len == rest.len();
+            let kind = match (is_first, is_last) {
+                (true, true) => FULL,
+                (true, false) => FIRST,
+                (false, true) => LAST,
+                (false, false) => MIDDLE,
+            };
+            let fragment = &rest[..len];
+            let checksum = mask(crc32c(crc32c(0, &[kind]), fragment));
+            self.writer.write_all(&checksum.to_le_bytes())?;
+            self.writer.write_all(&(len as u16).to_le_bytes())?;
+            self.writer.write_all(&[kind])?;
+            self.writer.write_all(fragment)?;
+            self.offset += HEADER_SIZE + len;
+            rest = &rest[len..];
+            is_first = false;
+            if is_last {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn corrupt(msg: &str) -> Error {
+    Error::Deserialization {msg: format!("corrupt export file: {}", msg)}
+}
+
+/// CRC-32C (Castagnoli), continuing from `crc`.
+fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {(crc >> 1) ^ 0x82f6_3b78} else {crc >> 1};
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// LevelDB stores masked checksums, since checksums of data containing
+/// checksums are weak.
+fn mask(crc: u32) -> u32 {
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+fn unmask(crc: u32) -> u32 {
+    crc.wrapping_sub(0xa282_ead8).rotate_left(15)
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// PROTOBUF
+///////////////////////////////////////////////////////////////////////////////
+
+enum Wire<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+    /// The fields between a start and end group tag.
+    Group(&'a [u8]),
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Decoder {bytes, offset: 0}
+    }
+    fn next(&mut self) -> Result<Option<(u32, Wire<'a>)>, Error> {
+        match self.next_tag()? {
+            Some((number, 4)) => Err(corrupt(&format!("unexpected end of group {}", number))),
+            Some((number, wire_type)) => self.value(number, wire_type).map(|x| Some((number, x))),
+            None => Ok(None),
+        }
+    }
+    fn next_tag(&mut self) -> Result<Option<(u32, u8)>, Error> {
+        if self.offset == self.bytes.len() {
+            return Ok(None);
+        }
+        let tag = self.varint()?;
+        Ok(Some(((tag >> 3) as u32, (tag & 7) as u8)))
+    }
+    fn value(&mut self, number: u32, wire_type: u8) -> Result<Wire<'a>, Error> {
+        match wire_type {
+            0 => self.varint().map(Wire::Varint),
+            1 => self.take(8).map(|x| {
+                Wire::Fixed64(u64::from_le_bytes([x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7]]))
+            }),
+            2 => {
+                let len = self.varint()? as usize;
+                self.take(len).map(Wire::Bytes)
+            }
+            3 => self.group(number).map(Wire::Group),
+            5 => self.take(4).map(|x| Wire::Fixed32(u32::from_le_bytes([x[0], x[1], x[2], x[3]]))),
+            x => Err(corrupt(&format!("unknown wire type {}", x))),
+        }
+    }
+    /// Skips to the end of the group, returning its contents.
+    fn group(&mut self, number: u32) -> Result<&'a [u8], Error> {
+        let start = self.offset;
+        loop {
+            let end = self.offset;
+            match self.next_tag()? {
+                Some((x, 4)) if x == number => return Ok(&self.bytes[start..end]),
+                Some((x, wire_type)) => {
+                    self.value(x, wire_type)?;
+                }
+                None => return Err(corrupt(&format!("unterminated group {}", number))),
+            }
+        }
+    }
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut result = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err(corrupt("varint too long"))
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self.offset
+            .checked_add(len)
+            .filter(|x| *x <= self.bytes.len())
+            .ok_or_else(|| corrupt("truncated protocol buffer"))?;
+        let result = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(result)
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+    fn tag(&mut self, number: u32, wire_type: u8) {
+        self.varint(((number as u64) << 3) | wire_type as u64);
+    }
+    fn int(&mut self, number: u32, value: i64) {
+        self.tag(number, 0);
+        self.varint(value as u64);
+    }
+    fn double(&mut self, number: u32, value: f64) {
+        self.tag(number, 1);
+        self.bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+    }
+    fn bytes(&mut self, number: u32, value: &[u8]) {
+        self.tag(number, 2);
+        self.varint(value.len() as u64);
+        self.bytes.extend_from_slice(value);
+    }
+    fn group<F: FnOnce(&mut Encoder)>(&mut self, number: u32, f: F) {
+        self.tag(number, 3);
+        f(self);
+        self.tag(number, 4);
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ENTITY PROTO
+///////////////////////////////////////////////////////////////////////////////
+
+// PROPERTY MEANINGS (SEE `Property.Meaning` IN `entity_pb`)
+const GD_WHEN: u64 = 7;
+const GEORSS_POINT: u64 = 9;
+const BLOB: u64 = 14;
+const BYTESTRING: u64 = 16;
+const ENTITY_PROTO: u64 = 19;
+const EMPTY_LIST: u64 = 24;
+
+fn decode_entity(bytes: &[u8]) -> Result<Entity, Error> {
+    let mut entity = Entity::default();
+    let mut decoder = Decoder::new(bytes);
+    while let Some(field) = decoder.next()? {
+        match field {
+            (13, Wire::Bytes(x)) => entity.key = decode_reference(x)?,
+            (14, Wire::Bytes(x)) => decode_property(x, false, &mut entity)?,
+            (15, Wire::Bytes(x)) => decode_property(x, true, &mut entity)?,
+            _ => {}
+        }
+    }
+    Ok(entity)
+}
+
+/// `None` for the empty path of an embedded entity.
+fn decode_reference(bytes: &[u8]) -> Result<Option<Key>, Error> {
+    let mut key = Key {namespace: None, path: Vec::new()};
+    let mut decoder = Decoder::new(bytes);
+    while let Some(field) = decoder.next()? {
+        match field {
+            (20, Wire::Bytes(x)) => key.namespace = Some(utf8(x)?).filter(|x| !x.is_empty()),
+            (14, Wire::Bytes(x)) => {
+                let mut path = Decoder::new(x);
+                while let Some(field) = path.next()? {
+                    if let (1, Wire::Group(x)) = field {
+                        key.path.push(decode_path_element(x, [2, 3, 4])?);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(key).filter(|x| !x.path.is_empty()))
+}
+
+/// Path elements are groups with different field numbers in keys and
+/// key values: `[type, id, name]`.
+fn decode_path_element(bytes: &[u8], numbers: [u32; 3]) -> Result<PathElement, Error> {
+    let mut element = PathElement {kind: String::new(), id: None};
+    let mut decoder = Decoder::new(bytes);
+    while let Some(field) = decoder.next()? {
+        match field {
+            (x, Wire::Bytes(kind)) if x == numbers[0] => element.kind = utf8(kind)?,
+            (x, Wire::Varint(id)) if x == numbers[1] => element.id = Some(KeyId::Id(id as i64)),
+            (x, Wire::Bytes(name)) if x == numbers[2] => element.id = Some(KeyId::Name(utf8(name)?)),
+            _ => {}
+        }
+    }
+    Ok(element)
+}
+
+fn decode_property(bytes: &[u8], unindexed: bool, entity: &mut Entity) -> Result<(), Error> {
+    let mut meaning = 0;
+    let mut name = String::new();
+    let mut multiple = false;
+    let mut value: &[u8] = &[];
+    let mut decoder = Decoder::new(bytes);
+    while let Some(field) = decoder.next()? {
+        match field {
+            (1, Wire::Varint(x)) => meaning = x,
+            (3, Wire::Bytes(x)) => name = utf8(x)?,
+            (4, Wire::Varint(x)) => multiple = x != 0,
+            (5, Wire::Bytes(x)) => value = x,
+            _ => {}
+        }
+    }
+    if unindexed {
+        entity.unindexed.insert(name.clone());
+    }
+    if meaning == EMPTY_LIST {
+        entity.properties.insert(name, Value::Array(Vec::new()));
+        return Ok(());
+    }
+    let value = decode_value(value, meaning)?;
+    if !multiple {
+        entity.properties.insert(name, value);
+        return Ok(());
+    }
+    // ONE PROPERTY PER ARRAY ELEMENT
+    match entity.properties.entry(name).or_insert_with(|| Value::Array(Vec::new())) {
+        Value::Array(xs) => xs.push(value),
+        _ => return Err(corrupt("property is both a single value and a list")),
+    }
+    Ok(())
+}
+
+fn decode_value(bytes: &[u8], meaning: u64) -> Result<Value, Error> {
+    let mut value = Value::Null;
+    let mut decoder = Decoder::new(bytes);
+    while let Some(field) = decoder.next()? {
+        value = match field {
+            (1, Wire::Varint(x)) if meaning == GD_WHEN => Value::Timestamp(timestamp::format_micros(x as i64)),
+            (1, Wire::Varint(x)) => Value::Integer(x as i64),
+            (2, Wire::Varint(x)) => Value::Boolean(x != 0),
+            (3, Wire::Bytes(x)) => decode_string(x, meaning)?,
+            (4, Wire::Fixed64(x)) => Value::Double(f64::from_bits(x)),
+            (5, Wire::Group(x)) => decode_point(x)?,
+            (8, Wire::Group(x)) => decode_user(x)?,
+            (12, Wire::Group(x)) => decode_reference_value(x)?,
+            _ => continue,
+        };
+    }
+    Ok(value)
+}
+
+fn decode_string(bytes: &[u8], meaning: u64) -> Result<Value, Error> {
+    match meaning {
+        BLOB | BYTESTRING => Ok(Value::Blob(bytes.to_vec())),
+        ENTITY_PROTO => decode_entity(bytes).map(Value::Entity),
+        // BYTE STRINGS OLDER CLIENTS WROTE WITHOUT A MEANING
+        _ => Ok(match std::str::from_utf8(bytes) {
+            Ok(x) => Value::String(x.to_owned()),
+            Err(_) => Value::Blob(bytes.to_vec()),
+        }),
+    }
+}
+
+fn decode_point(bytes: &[u8]) -> Result<Value, Error> {
+    let (mut latitude, mut longitude) = (0.0, 0.0);
+    let mut decoder = Decoder::new(bytes);
+    while let Some(field) = decoder.next()? {
+        match field {
+            (6, Wire::Fixed64(x)) => latitude = f64::from_bits(x),
+            (7, Wire::Fixed64(x)) => longitude = f64::from_bits(x),
+            _ => {}
+        }
+    }
+    Ok(Value::GeoPoint {latitude, longitude})
+}
+
+/// App Engine users, as the embedded entity Datastore's API shows them as.
+fn decode_user(bytes: &[u8]) -> Result<Value, Error> {
+    let mut user = Entity::default();
+    let mut decoder = Decoder::new(bytes);
+    while let Some(field) = decoder.next()? {
+        let name = match field.0 {
+            9 => "email",
+            10 => "auth_domain",
+            19 => "user_id",
+            _ => continue,
+        };
+        if let Wire::Bytes(x) = field.1 {
+            user.properties.insert(String::from(name), Value::String(utf8(x)?));
+        }
+    }
+    Ok(Value::Entity(user))
+}
+
+fn decode_reference_value(bytes: &[u8]) -> Result<Value, Error> {
+    let mut key = Key {namespace: None, path: Vec::new()};
+    let mut decoder = Decoder::new(bytes);
+    while let Some(field) = decoder.next()? {
+        match field {
+            (20, Wire::Bytes(x)) => key.namespace = Some(utf8(x)?).filter(|x| !x.is_empty()),
+            (14, Wire::Group(x)) => key.path.push(decode_path_element(x, [15, 16, 17])?),
+            _ => {}
+        }
+    }
+    Ok(Value::Key(key))
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, Error> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| corrupt("invalid utf-8 in string"))
+}
+
+fn encode_entity(entity: &Entity, project_id: &str) -> Result<Vec<u8>, Error> {
+    let path = entity.key
+        .as_ref()
+        .map(|x| x.path.as_slice())
+        .unwrap_or_default();
+    let mut encoder = Encoder::default();
+    // KEY
+    let mut reference = Encoder::default();
+    reference.bytes(13, project_id.as_bytes());
+    reference.bytes(14, &encode_path(path));
+    if let Some(namespace) = entity.key.as_ref().and_then(|x| x.namespace.as_ref()) {
+        reference.bytes(20, namespace.as_bytes());
+    }
+    encoder.bytes(13, &reference.bytes);
+    // PROPERTIES
+    for (name, value) in &entity.properties {
+        let unindexed = entity.unindexed.contains(name);
+        let number = if unindexed {15} else {14};
+        match value {
+            Value::Array(xs) if xs.is_empty() => {
+                encoder.bytes(number, &encode_property(name, EMPTY_LIST, false, &[]));
+            }
+            Value::Array(xs) => {
+                for x in xs {
+                    let (meaning, value) = encode_value(x, unindexed, project_id)?;
+                    encoder.bytes(number, &encode_property(name, meaning, true, &value));
+                }
+            }
+            x => {
+                let (meaning, value) = encode_value(x, unindexed, project_id)?;
+                encoder.bytes(number, &encode_property(name, meaning, false, &value));
+            }
+        }
+    }
+    // ENTITY GROUP (THE ROOT ANCESTOR)
+    encoder.bytes(16, &encode_path(&path[..path.len().min(1)]));
+    Ok(encoder.bytes)
+}
+
+fn encode_path(path: &[PathElement]) -> Vec<u8> {
+    let mut encoder = Encoder::default();
+    for x in path {
+        encoder.group(1, |encoder| encode_path_element(encoder, x, [2, 3, 4]));
+    }
+    encoder.bytes
+}
+
+fn encode_path_element(encoder: &mut Encoder, element: &PathElement, numbers: [u32; 3]) {
+    encoder.bytes(numbers[0], element.kind.as_bytes());
+    match &element.id {
+        Some(KeyId::Id(x)) => encoder.int(numbers[1], *x),
+        Some(KeyId::Name(x)) => encoder.bytes(numbers[2], x.as_bytes()),
+        None => {}
+    }
+}
+
+fn encode_property(name: &str, meaning: u64, multiple: bool, value: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::default();
+    if meaning != 0 {
+        encoder.int(1, meaning as i64);
+    }
+    encoder.bytes(3, name.as_bytes());
+    encoder.int(4, multiple as i64);
+    encoder.bytes(5, value);
+    encoder.bytes
+}
+
+/// The meaning and the encoded `PropertyValue`.
+fn encode_value(value: &Value, unindexed: bool, project_id: &str) -> Result<(u64, Vec<u8>), Error> {
+    let mut encoder = Encoder::default();
+    let meaning = match value {
+        Value::Null => 0,
+        Value::Boolean(x) => {
+            encoder.int(2, *x as i64);
+            0
+        }
+        Value::Integer(x) => {
+            encoder.int(1, *x);
+            0
+        }
+        Value::Double(x) => {
+            encoder.double(4, *x);
+            0
+        }
+        Value::Timestamp(x) => {
+            let micros = timestamp::parse_micros(x).ok_or_else(|| Error::Serialization {
+                msg: format!("invalid timestamp: {}", x)
+            })?;
+            encoder.int(1, micros);
+            GD_WHEN
+        }
+        Value::Key(x) => {
+            encoder.group(12, |encoder| {
+                encoder.bytes(13, project_id.as_bytes());
+                for element in &x.path {
+                    encoder.group(14, |encoder| encode_path_element(encoder, element, [15, 16, 17]));
+                }
+                if let Some(namespace) = &x.namespace {
+                    encoder.bytes(20, namespace.as_bytes());
+                }
+            });
+            0
+        }
+        Value::String(x) => {
+            encoder.bytes(3, x.as_bytes());
+            0
+        }
+        Value::Blob(x) => {
+            encoder.bytes(3, x);
+            // ONLY SHORT BLOBS CAN BE INDEXED
+            if unindexed {BLOB} else {BYTESTRING}
+        }
+        Value::GeoPoint {latitude, longitude} => {
+            encoder.group(5, |encoder| {
+                encoder.double(6, *latitude);
+                encoder.double(7, *longitude);
+            });
+            GEORSS_POINT
+        }
+        Value::Array(_) => {
+            return Err(Error::Serialization {
+                msg: String::from("arrays can't contain arrays")
+            });
+        }
+        Value::Entity(x) => {
+            encoder.bytes(3, &encode_entity(x, project_id)?);
+            ENTITY_PROTO
+        }
+    };
+    Ok((meaning, encoder.bytes))
+}
@@ -0,0 +1,426 @@
+//! Loading large numbers of rows with parallel workers.
+//!
+//! `BulkWriter` chunks rows into commit-sized batches of upserts and hands
+//! them to worker threads, each with its own client (clients aren't `Send`,
+//! so workers make theirs with the given factory). Failed batches are
+//! retried with backoff. With a checkpoint file, the number of rows written
+//! so far (counting from the start, without gaps) is saved after every
+//! batch, and a later run over the same rows skips them. Batches after a
+//! checkpoint may be written again on resume, which upserts make harmless.
+//!
+//! Rows with the same key are written in order, the last row wins: within a
+//! batch only the last is kept, and a batch waits for any batch still
+//! writing one of its keys.
+//!
+//! ```no_run
+//! use ddb::{DatastoreClient, EntityKey, Schedule};
+//! use ddb::bulk::BulkWriter;
+//! # #[derive(serde::Serialize)]
+//! # struct Row {id: u64}
+//! # impl EntityKey for Row {
+//! #     fn entity_kind_key() -> String {String::from("Row")}
+//! #     fn entity_name_key(&self) -> String {self.id.to_string()}
+//! # }
+//! let rows = (0..1_000_000).map(|id| Row {id});
+//! let progress = BulkWriter::new(DatastoreClient::new)
+//!     .workers(8)
+//!     .checkpoint("rows.checkpoint.json")
+//!     .schedule(Schedule::ramp_up())
+//!     .on_progress(|p| eprintln!("{} rows, {:.0}/s", p.written, p.rows_per_second()))
+//!     .run(rows)
+//!     .unwrap();
+//! ```
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use crate::db::{self, DatastoreClient, EntityKey, Error, Write};
+use crate::entity::Key;
+use crate::ratelimit::{Access, RateLimiter, Schedule};
+
+///////////////////////////////////////////////////////////////////////////////
+// PROGRESS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Progress {
+    /// Rows skipped because the checkpoint had them.
+    pub resumed: u64,
+    /// Rows written by this run.
+    pub written: u64,
+    pub batches: u64,
+    /// Commits that failed and were tried again.
+    pub retries: u64,
+    /// Rows written without gaps, including resumed ones. This is what the
+    /// checkpoint holds.
+    pub checkpoint: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn rows_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.written as f64 / seconds
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// BULK WRITER
+///////////////////////////////////////////////////////////////////////////////
+
+type Factory<'a> = Box<dyn Fn() -> Result<DatastoreClient, Error> + Send + Sync + 'a>;
+type ProgressFn<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+pub struct BulkWriter<'a> {
+    factory: Factory<'a>,
+    workers: usize,
+    batch_size: usize,
+    retries: u32,
+    retry_delay: Duration,
+    checkpoint: Option<PathBuf>,
+    schedule: Option<Schedule>,
+    on_progress: Option<ProgressFn<'a>>,
+}
+
+/// Rows `first_row..first_row + rows`, deduplicated by key.
+struct Batch {
+    index: u64,
+    first_row: u64,
+    rows: u64,
+    entities: Vec<google_datastore1::Entity>,
+    keys: Vec<Key>,
+}
+
+/// What `run` knows about the batches that are done.
+struct State<'a> {
+    progress: Progress,
+    /// Finished batches past the checkpoint, with their row counts.
+    finished: BTreeMap<u64, u64>,
+    /// The first batch not in the checkpoint.
+    next: u64,
+    failure: Option<Error>,
+    /// Keys of the batches handed to workers and not done yet.
+    writing: HashMap<u64, Vec<Key>>,
+    start: Instant,
+    checkpoint: Option<PathBuf>,
+    on_progress: Option<ProgressFn<'a>>,
+}
+
+struct Done {
+    index: u64,
+    first_row: u64,
+    rows: u64,
+    retries: u64,
+    /// The status and message, as `Error` can't cross threads.
+    result: Result<(), (Option<u16>, String)>,
+}
+
+impl<'a> BulkWriter<'a> {
+    /// `factory` makes each worker's client, e.g. `DatastoreClient::new`.
+    /// Namespaces, upcasters and so on set there apply to the rows.
+    pub fn new<F>(factory: F) -> Self
+    where
+        F: Fn() -> Result<DatastoreClient, Error> + Send + Sync + 'a,
+    {
+        BulkWriter {
+            factory: Box::new(factory),
+            workers: 4,
+            batch_size: db::COMMIT_LIMIT,
+            retries: 5,
+            retry_delay: Duration::from_millis(500),
+            checkpoint: None,
+            schedule: None,
+            on_progress: None,
+        }
+    }
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+    /// Rows per commit, at most 500 (the commit limit).
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.clamp(1, db::COMMIT_LIMIT);
+        self
+    }
+    /// Attempts after the first for contention, throttling, server and
+    /// connection errors. Other errors fail the batch at once.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+    /// Before the first retry, doubling for every retry after it.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+    /// Resumes from and saves progress to this file.
+    pub fn checkpoint<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.checkpoint = Some(path.as_ref().to_path_buf());
+        self
+    }
+    /// Rows per second across every worker, e.g. `Schedule::ramp_up()`
+    /// for a new kind.
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+    /// Called after every batch.
+    pub fn on_progress<F: FnMut(&Progress) + 'a>(mut self, f: F) -> Self {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+    /// Writes every row (after any in the checkpoint), stopping at the
+    /// first batch that fails for good. Rows must come in the same order
+    /// on every run for the checkpoint to make sense.
+    pub fn run<T, I>(mut self, rows: I) -> Result<Progress, Error>
+    where
+        T: Serialize + EntityKey,
+        I: IntoIterator<Item = T>,
+    {
+        let start = Instant::now();
+        let resumed = match &self.checkpoint {
+            Some(path) => load_checkpoint(path)?,
+            None => 0,
+        };
+        let mut state = State {
+            progress: Progress {
+                resumed,
+                checkpoint: resumed,
+                ..Progress::default()
+            },
+            finished: BTreeMap::new(),
+            next: 0,
+            failure: None,
+            writing: HashMap::new(),
+            start,
+            checkpoint: self.checkpoint.clone(),
+            on_progress: self.on_progress.take(),
+        };
+        let limiter = self.schedule.map(|x| RateLimiter::new().with_writes(x));
+        let (job_tx, job_rx) = mpsc::sync_channel::<Batch>(self.workers * 2);
+        let (done_tx, done_rx) = mpsc::channel::<Done>();
+        let jobs = Mutex::new(job_rx);
+        std::thread::scope(|scope| {
+            for _ in 0..self.workers {
+                let done_tx = done_tx.clone();
+                let (factory, jobs) = (&self.factory, &jobs);
+                let (retries, retry_delay) = (self.retries, self.retry_delay);
+                let setup: fn(&DatastoreClient) = DatastoreClient::register_hooks::<T>;
+                scope.spawn(move || work(factory, setup, jobs, done_tx, retries, retry_delay));
+            }
+            drop(done_tx);
+            let mut rows = rows.into_iter().skip(resumed as usize);
+            let mut next_row = resumed;
+            let mut index = 0;
+            while state.failure.is_none() {
+                let mut batch = match next_batch(&mut rows, self.batch_size, index, next_row) {
+                    Ok(Some(x)) => x,
+                    Ok(None) => break,
+                    Err(e) => {
+                        state.failure = Some(e);
+                        break;
+                    }
+                };
+                // A KEY IS WRITTEN BY ONE WORKER AT A TIME, SO THE LAST ROW WINS
+                while state.failure.is_none() && state.is_writing(&batch.keys) {
+                    match done_rx.recv() {
+                        Ok(done) => state.finish(done),
+                        Err(_) => break,
+                    }
+                }
+                if state.failure.is_some() {
+                    break;
+                }
+                if let Some(limiter) = &limiter {
+                    std::thread::sleep(limiter.reserve(Access::Write, batch.rows));
+                }
+                next_row += batch.rows;
+                index += 1;
+                state.writing.insert(batch.index, std::mem::take(&mut batch.keys));
+                if job_tx.send(batch).is_err() {
+                    break;
+                }
+                while let Ok(done) = done_rx.try_recv() {
+                    state.finish(done);
+                }
+            }
+            drop(job_tx);
+            for done in done_rx.iter() {
+                state.finish(done);
+            }
+        });
+        match state.failure {
+            Some(e) => Err(e),
+            None => Ok(state.progress),
+        }
+    }
+}
+
+impl State<'_> {
+    fn is_writing(&self, keys: &[Key]) -> bool {
+        let keys = keys.iter().collect::<HashSet<_>>();
+        self.writing.values().flatten().any(|x| keys.contains(x))
+    }
+    /// Counts a finished batch, and moves the checkpoint past every batch
+    /// that's done without gaps.
+    fn finish(&mut self, done: Done) {
+        self.writing.remove(&done.index);
+        let progress = &mut self.progress;
+        progress.retries += done.retries;
+        progress.elapsed = self.start.elapsed();
+        if let Err((status, msg)) = done.result {
+            if self.failure.is_none() {
+                self.failure = Some(Error::BatchFailed {first_row: done.first_row, status, msg});
+            }
+            return;
+        }
+        progress.written += done.rows;
+        progress.batches += 1;
+        self.finished.insert(done.index, done.rows);
+        let before = progress.checkpoint;
+        while let Some(rows) = self.finished.remove(&self.next) {
+            progress.checkpoint += rows;
+            self.next += 1;
+        }
+        if progress.checkpoint != before {
+            if let Some(path) = &self.checkpoint {
+                if let Err(e) = save_checkpoint(path, progress.checkpoint) {
+                    self.failure.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(f) = self.on_progress.as_mut() {
+            f(progress);
+        }
+    }
+}
+
+/// The next `size` rows, `None` once there are none.
+fn next_batch<T, I>(rows: &mut I, size: usize, index: u64, first_row: u64) -> Result<Option<Batch>, Error>
+where
+    T: Serialize + EntityKey,
+    I: Iterator<Item = T>,
+{
+    let mut entities = Vec::new();
+    let mut positions = HashMap::new();
+    let mut count = 0;
+    for row in rows.take(size) {
+        count += 1;
+        let entity = db::to_entity(row).map_err(|e| Error::BatchFailed {
+            first_row: first_row + count - 1,
+            status: None,
+            msg: e.to_string(),
+        })?;
+        // A COMMIT CAN'T WRITE A KEY TWICE, THE LAST ROW WINS
+        let key = entity.key.clone().map(Key::from_datastore);
+        match key.as_ref().and_then(|x| positions.get(x).copied()) {
+            Some(position) => entities[position] = entity,
+            None => {
+                positions.extend(key.map(|x| (x, entities.len())));
+                entities.push(entity);
+            }
+        }
+    }
+    if count == 0 {
+        return Ok(None);
+    }
+    let keys = positions.into_keys().collect();
+    Ok(Some(Batch {index, first_row, rows: count, entities, keys}))
+}
+
+/// A worker thread: commits batches until there are none left. `setup`
+/// turns on the model's hooks for the worker's client.
+fn work(factory: &Factory<'_>, setup: fn(&DatastoreClient), jobs: &Mutex<Receiver<Batch>>, done: Sender<Done>, retries: u32, retry_delay: Duration) {
+    let client = factory();
+    if let Ok(client) = &client {
+        setup(client);
+    }
+    loop {
+        let batch = match jobs.lock().map(|x| x.recv()) {
+            Ok(Ok(x)) => x,
+            _ => return,
+        };
+        let (attempts, result) = match &client {
+            Ok(client) => commit(client, &batch, retries, retry_delay),
+            Err(e) => (0, Err((None, e.to_string()))),
+        };
+        let done = done.send(Done {
+            index: batch.index,
+            first_row: batch.first_row,
+            rows: batch.rows,
+            retries: attempts,
+            result,
+        });
+        if done.is_err() {
+            return;
+        }
+    }
+}
+
+/// Returns the number of retries along with the result.
+fn commit(client: &DatastoreClient, batch: &Batch, retries: u32, retry_delay: Duration) -> (u64, Result<(), (Option<u16>, String)>) {
+    let mutations = batch.entities
+        .iter()
+        .map(|x| {
+            let mut entity = client.stamp_version(x.clone());
+            client.stamp_timestamps(Write::Upsert, &mut entity);
+            Write::Upsert.mutation(entity)
+        })
+        .collect::<Vec<_>>();
+    let mut attempt = 0;
+    loop {
+        match client.commit(mutations.clone()) {
+            Ok(()) => return (attempt as u64, Ok(())),
+            Err(e) if attempt < retries && is_retryable(&e) => {
+                tracing::debug!(error = %e, first_row = batch.first_row, "retrying batch");
+                std::thread::sleep(retry_delay * 2u32.saturating_pow(attempt));
+                attempt += 1;
+            }
+            Err(e) => return (attempt as u64, Err((e.http_status(), e.to_string()))),
+        }
+    }
+}
+
+fn is_retryable(error: &Error) -> bool {
+    match error {
+        Error::DatabaseResponse(google_datastore1::Error::HttpError(_)) => true,
+        Error::DatabaseResponse(_) => matches!(error.http_status(), Some(409) | Some(429) | Some(500..=504)),
+        _ => false,
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CHECKPOINTS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Rows written so far, 0 if there's no checkpoint yet.
+fn load_checkpoint(path: &Path) -> Result<u64, Error> {
+    let json = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    serde_json::from_str::<serde_json::Value>(&json)
+        .ok()
+        .and_then(|x| x["rows"].as_u64())
+        .ok_or_else(|| Error::Deserialization {
+            msg: format!("invalid checkpoint file {}", path.display())
+        })
+}
+
+/// Written to a temporary file first, so an interruption never leaves a
+/// half-written checkpoint.
+fn save_checkpoint(path: &Path, rows: u64) -> Result<(), Error> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let json = serde_json::json!({"rows": rows}).to_string();
+    std::fs::write(&temp, json).map_err(Error::Io)?;
+    std::fs::rename(&temp, path).map_err(Error::Io)
+}
@@ -0,0 +1,225 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use crate::db::{self, DatastoreClient};
+use crate::entity::Key;
+
+///////////////////////////////////////////////////////////////////////////////
+// CACHE
+///////////////////////////////////////////////////////////////////////////////
+
+/// Storage for entities read by a client, see `DatastoreClient::with_cache`.
+///
+/// Keys always carry their namespace (if not the default one), so a cache
+/// can be shared by clients using different namespaces. Entities are kept
+/// as Datastore returned them, so a hit reads exactly like a lookup
+/// (`meaning`, index exclusions and all).
+pub trait Cache {
+    fn get(&mut self, key: &Key) -> Option<google_datastore1::Entity>;
+    fn put(&mut self, key: Key, entity: google_datastore1::Entity);
+    fn remove(&mut self, key: &Key);
+    fn clear(&mut self);
+}
+
+/// An in-process cache that evicts the least recently used entity once
+/// `capacity` is reached, and (optionally) entities older than a TTL.
+pub struct LruCache {
+    capacity: usize,
+    ttl: Option<Duration>,
+    entries: HashMap<Key, Slot>,
+    /// Keys by last use, oldest first.
+    recent: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+struct Slot {
+    entity: google_datastore1::Entity,
+    stored: Instant,
+    tick: u64,
+}
+
+impl LruCache {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            ttl: None,
+            entries: HashMap::new(),
+            recent: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+    /// Entities are read again from Datastore once they're older than
+    /// `ttl`, e.g. to pick up writes by other processes.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl Cache for LruCache {
+    fn get(&mut self, key: &Key) -> Option<google_datastore1::Entity> {
+        let is_expired = {
+            let slot = self.entries.get(key)?;
+            self.ttl.is_some_and(|x| slot.stored.elapsed() >= x)
+        };
+        if is_expired {
+            self.remove(key);
+            return None;
+        }
+        let tick = self.next_tick();
+        let slot = self.entries.get_mut(key)?;
+        self.recent.remove(&slot.tick);
+        self.recent.insert(tick, key.clone());
+        slot.tick = tick;
+        Some(slot.entity.clone())
+    }
+    fn put(&mut self, key: Key, entity: google_datastore1::Entity) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        let tick = self.next_tick();
+        self.recent.insert(tick, key.clone());
+        self.entries.insert(key, Slot {entity, stored: Instant::now(), tick});
+        while self.entries.len() > self.capacity {
+            match self.recent.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+    fn remove(&mut self, key: &Key) {
+        if let Some(slot) = self.entries.remove(key) {
+            self.recent.remove(&slot.tick);
+        }
+    }
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.recent.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub(crate) struct CacheLayer {
+    cache: Box<dyn Cache>,
+    stats: CacheStats,
+}
+
+impl CacheLayer {
+    pub(crate) fn new<C: Cache + 'static>(cache: C) -> Self {
+        CacheLayer {cache: Box::new(cache), stats: CacheStats::default()}
+    }
+}
+
+/// Where a client's reads are cached. Writes evict from the client's
+/// cache whichever it is, see `Repository::with_cache`.
+#[derive(Clone, Default)]
+pub(crate) enum CacheReads {
+    /// The client's cache, see `DatastoreClient::with_cache`.
+    #[default]
+    Shared,
+    /// A repository's own cache, used instead of the client's.
+    Local(Rc<RefCell<CacheLayer>>),
+    /// Always from Datastore.
+    Bypass,
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - CACHING
+///////////////////////////////////////////////////////////////////////////////
+
+/// ```no_run
+/// # use ddb::*;
+/// # use std::time::Duration;
+/// # #[derive(serde::Deserialize)]
+/// # struct FeatureFlags {}
+/// # impl EntityKey for FeatureFlags {
+/// #     fn entity_kind_key() -> String {String::from("FeatureFlags")}
+/// #     fn entity_name_key(&self) -> String {String::from("global")}
+/// # }
+/// let db = DatastoreClient::new()
+///     .unwrap()
+///     .with_cache(LruCache::new(1000).with_ttl(Duration::from_secs(60)));
+/// // ONE LOOKUP, THEN SERVED FROM THE CACHE FOR A MINUTE
+/// for _ in 0..10 {
+///     let flags = db.get::<FeatureFlags, _>("global").unwrap();
+/// }
+/// assert_eq!(db.cache_stats().unwrap().hits, 9);
+/// ```
+impl DatastoreClient {
+    /// Serves non-transactional reads by key (`get`, `get_many`,
+    /// `get_entity`, ...) from the cache, falling back to Datastore on a
+    /// miss. Every write by this client (and its clones) evicts the
+    /// written keys. Writes by other processes are only seen once the
+    /// cache drops the entity, see `LruCache::with_ttl`.
+    pub fn with_cache<C: Cache + 'static>(mut self, cache: C) -> Self {
+        self.cache = Rc::new(RefCell::new(Some(CacheLayer::new(cache))));
+        self.cache_reads = CacheReads::Shared;
+        self
+    }
+    /// `None` without a cache.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.read_layer(|x| x.stats)
+    }
+    pub fn clear_cache(&self) {
+        self.read_layer(|x| x.cache.clear());
+    }
+    /// Runs `f` on the cache reads go through, if there is one.
+    fn read_layer<R>(&self, f: impl FnOnce(&mut CacheLayer) -> R) -> Option<R> {
+        match &self.cache_reads {
+            CacheReads::Shared => self.cache.borrow_mut().as_mut().map(f),
+            CacheReads::Local(layer) => Some(f(&mut layer.borrow_mut())),
+            CacheReads::Bypass => None,
+        }
+    }
+    /// Counts a hit or a miss.
+    pub(crate) fn cached(&self, key: &Key) -> Option<google_datastore1::Entity> {
+        self.read_layer(|layer| match layer.cache.get(key) {
+            Some(x) => {
+                layer.stats.hits += 1;
+                Some(x)
+            }
+            None => {
+                layer.stats.misses += 1;
+                None
+            }
+        })
+        .flatten()
+    }
+    pub(crate) fn store_cached(&self, key: Key, entity: &google_datastore1::Entity) {
+        self.read_layer(|layer| layer.cache.put(key, entity.clone()));
+    }
+    /// Evicts every key the mutations write, from the client's cache and
+    /// a repository's own. Keys must already be in their namespace.
+    pub(crate) fn invalidate_cached(&self, mutations: &[google_datastore1::Mutation]) {
+        let keys = mutations
+            .iter()
+            .filter_map(db::mutation_key)
+            .map(|x| Key::from_datastore(x.clone()))
+            .collect::<Vec<_>>();
+        if let Some(layer) = self.cache.borrow_mut().as_mut() {
+            keys.iter().for_each(|x| layer.cache.remove(x));
+        }
+        if let CacheReads::Local(layer) = &self.cache_reads {
+            let mut layer = layer.borrow_mut();
+            keys.iter().for_each(|x| layer.cache.remove(x));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Conversion between serde types and raw `google_datastore1` entities.
+//!
+//! This is the same conversion `DatastoreClient` uses, for code that already
+//! holds `google_datastore1` values (e.g. from Pub/Sub exports or direct hub
+//! calls).
+//!
+//! Enums are stored as serde represents them (so `#[serde(tag = "...")]` and
+//! friends work as usual), unless `Options::enums` says otherwise.
+use serde::{Serialize, de::DeserializeOwned};
+use crate::convert;
+use crate::enums;
+use crate::db::Error;
+use crate::entity::Key;
+
+///////////////////////////////////////////////////////////////////////////////
+// OPTIONS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Options {
+    pub large_integers: LargeIntegers,
+    /// Read whole-number doubles as integers, so e.g. an `i64` field can
+    /// load a `5.0` written by another client.
+    pub lenient_integers: bool,
+    pub nulls: Nulls,
+    pub enums: Enums,
+}
+
+/// What to do with unsigned integers above `i64::MAX`, which Datastore's
+/// signed 64-bit integers can't hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LargeIntegers {
+    #[default]
+    Error,
+    /// Store as a double, losing precision.
+    Double,
+}
+
+/// How null (e.g. `None`) properties are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Nulls {
+    /// Store a null value.
+    #[default]
+    Keep,
+    /// Leave the property out. Missing properties read back as `None`.
+    Omit,
+}
+
+/// How enum variants are stored, for every enum without a serde
+/// representation of its own (e.g. from a crate whose types you can't
+/// annotate). Under any setting, serde's own representation (e.g. from
+/// entities written before it was set) still reads back.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Enums {
+    /// As serde does by default: a unit variant as its name, other
+    /// variants as an embedded entity with the name as its only property,
+    /// e.g. `{"Card": {"last4": "4242"}}`.
+    #[default]
+    External,
+    /// The name under `tag` and the data under `content`, e.g.
+    /// `{"type": "Card", "data": {"last4": "4242"}}`, so queries can filter
+    /// on the variant.
+    Adjacent {
+        tag: String,
+        content: String,
+    },
+    /// The name under `tag`, next to the variant's fields, e.g.
+    /// `{"type": "Card", "last4": "4242"}`. Only for variants holding a
+    /// struct or map (or nothing), others fail to encode.
+    Internal {
+        tag: String,
+    },
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ENCODE
+///////////////////////////////////////////////////////////////////////////////
+
+pub fn to_entity<T: Serialize>(value: &T, key: Key) -> Result<google_datastore1::Entity, Error> {
+    to_entity_with(value, key, &Options::default())
+}
+
+pub fn to_entity_with<T: Serialize>(value: &T, key: Key, options: &Options) -> Result<google_datastore1::Entity, Error> {
+    let properties = to_value_with(value, options)?
+        .entity_value
+        .and_then(|x| x.properties)
+        .ok_or(Error::Serialization {
+            msg: String::from("expecting struct/map like input")
+        })?;
+    Ok(google_datastore1::Entity {
+        properties: Some(properties),
+        key: Some(key.to_datastore()),
+    })
+}
+
+pub fn to_value<T: Serialize>(value: &T) -> Result<google_datastore1::Value, Error> {
+    to_value_with(value, &Options::default())
+}
+
+pub fn to_value_with<T: Serialize>(value: &T, options: &Options) -> Result<google_datastore1::Value, Error> {
+    let json = enums::to_json(value, &options.enums)
+        .map_err(|e| Error::Serialization {msg: e.to_string()})?;
+    convert::to_datastore_value_with(json, options)
+        .ok_or(Error::Serialization {
+            msg: String::from("value not representable in datastore")
+        })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// DECODE
+///////////////////////////////////////////////////////////////////////////////
+
+pub fn from_entity<T: DeserializeOwned>(entity: google_datastore1::Entity) -> Result<T, Error> {
+    from_entity_with(entity, &Options::default())
+}
+
+pub fn from_entity_with<T: DeserializeOwned>(entity: google_datastore1::Entity, options: &Options) -> Result<T, Error> {
+    let value = google_datastore1::Value {
+        entity_value: Some(entity),
+        ..google_datastore1::Value::default()
+    };
+    from_value_with(value, options)
+}
+
+pub fn from_value<T: DeserializeOwned>(value: google_datastore1::Value) -> Result<T, Error> {
+    from_value_with(value, &Options::default())
+}
+
+pub fn from_value_with<T: DeserializeOwned>(value: google_datastore1::Value, options: &Options) -> Result<T, Error> {
+    let json: serde_json::Value = convert::from_datastore_value_with(value, options)
+        .ok_or(Error::Deserialization {
+            msg: String::from("unsupported datastore value")
+        })?;
+    enums::from_json(json, &options.enums)
+        .map_err(|e| Error::Deserialization {msg: e.to_string()})
+}
+
+/// The key of a raw entity, if it has one.
+pub fn entity_key(entity: &google_datastore1::Entity) -> Option<Key> {
+    entity.key.clone().map(Key::from_datastore)
+}
@@ -1,9 +1,40 @@
 use std::str::FromStr;
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
+use crate::entity::{Key, KeyRepr};
+use crate::codec::{Options, LargeIntegers, Nulls};
+use crate::enums;
+
+/// Objects of the form `{"__ddb_raw__": <Value>}` are passed through to
+/// Datastore as-is, so types serde_json can't express survive the trip.
+pub(crate) const RAW_VALUE_KEY: &str = "__ddb_raw__";
+
+/// Datastore doesn't allow arrays directly inside arrays, so inner arrays
+/// are stored as an embedded entity holding the array under this name.
+pub(crate) const NESTED_ARRAY_KEY: &str = "_ddb_array";
+
+pub(crate) fn raw_value_to_json(value: &google_datastore1::Value) -> serde_json::Value {
+    fn remove_nulls(value: &mut serde_json::Value) {
+        if let serde_json::Value::Object(xs) = value {
+            xs.retain(|_, v| !v.is_null());
+            xs.values_mut().for_each(remove_nulls);
+        } else if let serde_json::Value::Array(xs) = value {
+            xs.iter_mut().for_each(remove_nulls);
+        }
+    }
+    let mut value = serde_json::to_value(value).expect("google_datastore1::Value to json");
+    remove_nulls(&mut value);
+    let mut xs = serde_json::Map::new();
+    xs.insert(String::from(RAW_VALUE_KEY), value);
+    serde_json::Value::Object(xs)
+}
 
 pub fn to_datastore_value<T: Serialize>(x: T) -> Option<google_datastore1::Value> {
-    match serde_json::to_value(x).ok()? {
+    to_datastore_value_with(x, &Options::default())
+}
+
+pub fn to_datastore_value_with<T: Serialize>(x: T, options: &Options) -> Option<google_datastore1::Value> {
+    match enums::to_json(&x, &options.enums).ok()? {
         serde_json::Value::Null => {
             Some(google_datastore1::Value {
                 null_value: Some(String::from("NULL_VALUE")),
@@ -28,10 +59,14 @@ pub fn to_datastore_value<T: Serialize>(x: T) -> Option<google_datastore1::Value
                     ..google_datastore1::Value::default()
                 })
             } else if x.is_u64() {
-                Some(google_datastore1::Value {
-                    integer_value: Some(format!("{}", x.as_u64().expect("should be u64"))),
-                    ..google_datastore1::Value::default()
-                })
+                // DATASTORE INTEGERS ARE SIGNED 64-BIT
+                match options.large_integers {
+                    LargeIntegers::Error => None,
+                    LargeIntegers::Double => Some(google_datastore1::Value {
+                        double_value: Some(x.as_f64().expect("should be f64")),
+                        ..google_datastore1::Value::default()
+                    }),
+                }
             } else {
                 panic!("ddb from_datastore_value unreachable");
             }
@@ -43,40 +78,43 @@ pub fn to_datastore_value<T: Serialize>(x: T) -> Option<google_datastore1::Value
             })
         }
         serde_json::Value::Array(xs) => {
-            let mut any_invalid = false;
             let xs = xs
                 .into_iter()
-                .filter_map(|x| {
-                    let x = to_datastore_value(x);
-                    if x.is_none() {
-                        any_invalid = true;
+                .map(|x| {
+                    let x = to_datastore_value_with(x, options)?;
+                    if x.array_value.is_some() {
+                        let mut wrapper = HashMap::new();
+                        wrapper.insert(String::from(NESTED_ARRAY_KEY), x);
+                        return Some(google_datastore1::Value {
+                            entity_value: Some(google_datastore1::Entity {
+                                properties: Some(wrapper),
+                                key: None,
+                            }),
+                            ..google_datastore1::Value::default()
+                        });
                     }
-                    x
-                })
-                .collect::<Vec<_>>();
-            if !any_invalid {
-                Some(google_datastore1::Value {
-                    array_value: Some(google_datastore1::ArrayValue {
-                        values: Some(xs)
-                    }),
-                    ..google_datastore1::Value::default()
+                    Some(x)
                 })
-            } else {
-                None
-            }
+                .collect::<Option<Vec<_>>>()?;
+            Some(google_datastore1::Value {
+                array_value: Some(google_datastore1::ArrayValue {
+                    values: Some(xs)
+                }),
+                ..google_datastore1::Value::default()
+            })
+        }
+        serde_json::Value::Object(mut xs) if xs.len() == 1 && xs.contains_key(RAW_VALUE_KEY) => {
+            xs.remove(RAW_VALUE_KEY)
+                .and_then(|x| serde_json::from_value(x).ok())
         }
         serde_json::Value::Object(xs) => {
-            let mut any_invalid = false;
             let xs = xs
                 .into_iter()
-                .filter_map(|(k, v)| {
-                    let v = to_datastore_value(v);
-                    if v.is_none() {
-                        any_invalid = true;
-                    }
-                    v.map(|v| (k, v))
+                .filter(|(_, v)| !(v.is_null() && options.nulls == Nulls::Omit))
+                .map(|(k, v)| {
+                    to_datastore_value_with(v, options).map(|v| (k, v))
                 })
-                .collect::<HashMap<_, _>>();
+                .collect::<Option<HashMap<_, _>>>()?;
             Some(google_datastore1::Value {
                 entity_value: Some(google_datastore1::Entity {
                     properties: Some(xs),
@@ -89,89 +127,83 @@ pub fn to_datastore_value<T: Serialize>(x: T) -> Option<google_datastore1::Value
 }
 
 pub fn from_datastore_value<T: serde::de::DeserializeOwned>(value: google_datastore1::Value) -> Option<T> {
-    let mut serde_value: serde_json::Value;
+    from_datastore_value_with(value, &Options::default())
+}
+
+pub fn from_datastore_value_with<T: serde::de::DeserializeOwned>(value: google_datastore1::Value, options: &Options) -> Option<T> {
+    let serde_value: serde_json::Value;
     if let Some(xs) = value.entity_value {
-        let mut any_invalid = false;
-        let xs = xs
+        let mut xs = xs
             .properties
-            .unwrap_or(HashMap::default())
+            .unwrap_or_default()
             .into_iter()
-            .filter_map(|(k, v)| {
-                let v = from_datastore_value(v);
-                if v.is_none() {
-                    any_invalid = true;
-                }
-                v.map(|v| (k, v))
+            .map(|(k, v)| {
+                from_datastore_value_with(v, options).map(|v| (k, v))
             })
-            .collect::<serde_json::Map<_, _>>();
-        if !any_invalid {
-            serde_value = serde_json::Value::Object(xs);
+            .collect::<Option<serde_json::Map<_, _>>>()?;
+        let is_nested_array = xs.len() == 1 && xs
+            .get(NESTED_ARRAY_KEY)
+            .map(|x| x.is_array())
+            .unwrap_or(false);
+        if is_nested_array {
+            serde_value = xs.remove(NESTED_ARRAY_KEY).expect("checked above");
         } else {
-            return None;
+            serde_value = serde_json::Value::Object(xs);
         }
     } else if let Some(xs) = value.timestamp_value {
         serde_value = serde_json::Value::String(xs);
     } else if let Some(xs) = value.geo_point_value {
-        serde_value = unimplemented!();
+        serde_value = serde_json::json!({
+            "latitude": xs.latitude,
+            "longitude": xs.longitude,
+        });
     } else if let Some(xs) = value.blob_value {
-        serde_value = unimplemented!();
+        // ALREADY BASE64
+        serde_value = serde_json::Value::String(xs);
     } else if let Some(xs) = value.double_value {
-        serde_value = serde_json::Value::Number(serde_json::Number::from_f64(xs)?);
-    } else if let Some(xs) = value.meaning {
-        serde_value = unimplemented!();
-    } else if let Some(xs) = value.exclude_from_indexes {
-        serde_value = unimplemented!();
+        let is_whole = xs.fract() == 0.0 && xs.abs() < i64::MAX as f64;
+        if options.lenient_integers && is_whole {
+            serde_value = serde_json::Value::Number(From::from(xs as i64));
+        } else {
+            serde_value = serde_json::Value::Number(serde_json::Number::from_f64(xs)?);
+        }
     } else if let Some(xs) = value.string_value {
         serde_value = serde_json::Value::String(xs);
     } else if let Some(xs) = value.key_value {
-        serde_value = unimplemented!();
+        let key = KeyRepr::from_key(Key::from_datastore(xs));
+        serde_value = serde_json::to_value(key).ok()?;
     } else if let Some(xs) = value.boolean_value {
         serde_value = serde_json::Value::Bool(xs);
     } else if let Some(xs) = value.array_value {
-        let mut any_invalid = false;
+        // AN EMPTY ARRAY COMES BACK WITHOUT `values`
         let xs = xs
             .values
-            .unwrap_or(Vec::default())
+            .unwrap_or_default()
             .into_iter()
-            .filter_map(|x| {
-                let x = from_datastore_value(x);
-                any_invalid = true;
-                x
-            })
-            .collect::<Vec<_>>();
-        if !any_invalid {
-            serde_value = serde_json::Value::Array(xs);
-        } else {
-            return None;
-        }
+            .map(|x| from_datastore_value_with(x, options))
+            .collect::<Option<Vec<serde_json::Value>>>()?;
+        serde_value = serde_json::Value::Array(xs);
     } else if let Some(xs) = value.integer_value {
-        let as_u64: Option<u64> = FromStr::from_str(&xs).ok();
-        let as_i64: Option<i64> = FromStr::from_str(&xs).ok();
-        let number: serde_json::Number = as_u64
-            .map(|x| From::from(x))
-            .or(as_i64.map(|x| From::from(x)))?;
+        let number: serde_json::Number = i64::from_str(&xs)
+            .map(From::from)
+            .or_else(|_| u64::from_str(&xs).map(From::from))
+            .ok()?;
         serde_value = serde_json::Value::Number(number);
-    } else if let Some(xs) = value.null_value {
-        serde_value = serde_json::Value::Null;
     } else {
         serde_value = serde_json::Value::Null;
     }
-    serde_json::from_value(serde_value)
-        .map_err(|e| {
-            // eprintln!("error serde_json::from_value: {:#?}", e);
-            e
-        })
-        .ok()
+    enums::from_json(serde_value, &options.enums).ok()
 }
 
 
 pub fn from_datastore_entity<T: serde::de::DeserializeOwned>(value: google_datastore1::Entity) -> Option<T> {
+    from_datastore_entity_with(value, &Options::default())
+}
+
+pub fn from_datastore_entity_with<T: serde::de::DeserializeOwned>(value: google_datastore1::Entity, options: &Options) -> Option<T> {
     let value = google_datastore1::Value {
         entity_value: Some(value),
         ..Default::default()
     };
-    from_datastore_value(value)
+    from_datastore_value_with(value, options)
 }
-
-
-
@@ -1,13 +1,23 @@
 use std::rc::Rc;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::path::PathBuf;
 use std::string::ToString;
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use crate::convert;
-use crate::auth;
+use crate::tracked::Tracked;
+use crate::entity::{Entity, Key};
+use crate::index::IndexManifest;
+use crate::upcast::Upcasters;
+use crate::hooks::{HookRegistry, Hooks};
+use crate::auto_timestamps::TimestampRegistry;
+use crate::cache::{CacheLayer, CacheReads};
+use crate::telemetry::{Metrics, Operation, Rpc};
+use crate::ratelimit::RateLimiter;
+use crate::transport::{HttpRequest, HyperTransport, Replayer, Transport};
 
-pub use crate::auth::Auth;
+pub use crate::auth::{Auth, AuthError};
 
 ///////////////////////////////////////////////////////////////////////////////
 // HELPERS
@@ -16,6 +26,10 @@ pub use crate::auth::Auth;
 pub trait EntityKey {
     fn entity_kind_key() -> String;
     fn entity_name_key(&self) -> String;
+    /// `Some(Hooks::of::<Self>())` to run the model's `EntityHooks`.
+    fn entity_hooks() -> Option<Hooks> where Self: Sized {
+        None
+    }
 }
 
 
@@ -29,6 +43,55 @@ pub enum Error {
     },
     DatabaseResponse(google_datastore1::Error),
     NoPayload,
+    /// Reading or writing a file or stream, e.g. in `export_kind`.
+    Io(std::io::Error),
+    /// A `BulkWriter` batch that still failed after its retries.
+    BatchFailed {
+        first_row: u64,
+        status: Option<u16>,
+        msg: String,
+    },
+    /// A write or read aborted by an `EntityHooks` hook; downcast to get
+    /// its error back.
+    Hook(Box<dyn std::error::Error>),
+    /// No credentials, see `Auth::new`.
+    Auth(AuthError),
+    /// An entity or key without the complete key the call needs.
+    IncompleteKey,
+    /// Two migrations given to a `Migrator` with the same version.
+    DuplicateMigration(u32),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Serialization {msg} => write!(f, "serialization error: {}", msg),
+            Error::Deserialization {msg} => write!(f, "deserialization error: {}", msg),
+            Error::DatabaseResponse(e) => write!(f, "database error: {}", e),
+            Error::NoPayload => write!(f, "entity not found"),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::BatchFailed {first_row, msg, ..} => write!(f, "batch from row {} failed: {}", first_row, msg),
+            Error::Hook(e) => write!(f, "aborted by hook: {}", e),
+            Error::Auth(e) => write!(f, "auth error: {}", e),
+            Error::IncompleteKey => write!(f, "entity or key has no complete key"),
+            Error::DuplicateMigration(version) => write!(f, "duplicate migration version {}", version),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// The HTTP status of an error response from Datastore, e.g. 409 for
+    /// contention or 429 when throttled.
+    pub fn http_status(&self) -> Option<u16> {
+        match self {
+            Error::DatabaseResponse(google_datastore1::Error::BadRequest(e)) => Some(e.error.code),
+            Error::DatabaseResponse(google_datastore1::Error::Failure(res)) => Some(res.status.to_u16()),
+            Error::BatchFailed {status, ..} => *status,
+            _ => None,
+        }
+    }
 }
 
 
@@ -37,241 +100,597 @@ pub enum Error {
 // CLIENT
 ///////////////////////////////////////////////////////////////////////////////
 
-type Handle = google_datastore1::Datastore<hyper::Client, auth::Auth>;
+static BASE_URL: &str = "https://datastore.googleapis.com/";
+static SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+/// Most mutations Datastore takes in one commit.
+pub(crate) static COMMIT_LIMIT: usize = 500;
 
 #[derive(Clone)]
 pub struct DatastoreClient {
-    handle: Rc<Handle>,
-    project_id: String,
+    pub(crate) project_id: String,
+    auth: Auth,
+    /// See `with_transport`.
+    pub(crate) transport: Rc<dyn Transport>,
+    base_url: String,
+    /// See `record_indexes`.
+    pub(crate) index_log: Rc<RefCell<Option<IndexManifest>>>,
+    /// See `register_upcast`.
+    pub(crate) upcasters: Rc<RefCell<Upcasters>>,
+    /// See `register_hooks`.
+    pub(crate) hooks: Rc<RefCell<HookRegistry>>,
+    /// See `register_timestamps`.
+    pub(crate) timestamps: Rc<RefCell<TimestampRegistry>>,
+    /// See `with_clock`.
+    pub(crate) clock: fn() -> std::time::SystemTime,
+    /// See `with_namespace`.
+    pub(crate) namespace: Option<String>,
+    /// See `with_cache`.
+    pub(crate) cache: Rc<RefCell<Option<CacheLayer>>>,
+    /// See `Repository::with_cache`.
+    pub(crate) cache_reads: CacheReads,
+    /// See `with_metrics`.
+    pub(crate) metrics: Option<Rc<dyn Metrics>>,
+    /// See `with_rate_limit`.
+    pub(crate) rate_limit: Option<Rc<RateLimiter>>,
 }
 
 impl DatastoreClient {
     /// Automatically finds auth credentials.
     /// See `Auth::new()` for auth related details.
-    pub fn new() -> Result<Self, String> {
-        let auth = Auth::new()?;
+    pub fn new() -> Result<Self, Error> {
+        let auth = Auth::new().map_err(Error::Auth)?;
         DatastoreClient::new_with_auth(auth)
     }
-    pub fn new_with_auth(auth: Auth) -> Result<Self, String> {
+    pub fn new_with_auth(auth: Auth) -> Result<Self, Error> {
+        DatastoreClient::new_with_base_url(auth, String::from(BASE_URL))
+    }
+    /// Connects to a local Datastore emulator at `host` (e.g.
+    /// `localhost:8081`, as in `DATASTORE_EMULATOR_HOST`). No credentials
+    /// are needed.
+    pub fn new_emulator<H: ToString, P: ToString>(host: H, project_id: P) -> Result<Self, Error> {
+        let host = host.to_string();
+        let base_url = if host.starts_with("http://") || host.starts_with("https://") {
+            format!("{}/", host.trim_end_matches('/'))
+        } else {
+            format!("http://{}/", host.trim_end_matches('/'))
+        };
+        DatastoreClient::new_with_base_url(Auth::anonymous(project_id), base_url)
+    }
+    /// Serves every request from a cassette recorded with
+    /// `with_recording`, without a network or credentials.
+    pub fn new_replay<C: AsRef<std::path::Path>, P: ToString>(cassette: C, project_id: P) -> Result<Self, Error> {
+        let replayer = Replayer::open(cassette)?;
+        let client = DatastoreClient::new_with_base_url(Auth::anonymous(project_id), String::from(BASE_URL))?;
+        Ok(client.with_transport(Rc::new(replayer)))
+    }
+    fn new_with_base_url(auth: Auth, base_url: String) -> Result<Self, Error> {
         let project_id = auth.project_id.clone();
-        let client = hyper::Client::with_connector(
-            hyper::net::HttpsConnector::new(hyper_rustls::TlsClient::new())
-        );
-        let hub = google_datastore1::Datastore::new(client, auth);
         Ok(DatastoreClient {
-            handle: Rc::new(hub),
             project_id,
+            auth,
+            transport: Rc::new(HyperTransport::new()),
+            base_url,
+            index_log: Rc::new(RefCell::new(None)),
+            upcasters: Rc::new(RefCell::new(Upcasters::new())),
+            hooks: Rc::new(RefCell::new(HookRegistry::new())),
+            timestamps: Rc::new(RefCell::new(TimestampRegistry::new())),
+            clock: std::time::SystemTime::now,
+            namespace: None,
+            cache: Rc::new(RefCell::new(None)),
+            cache_reads: CacheReads::default(),
+            metrics: None,
+            rate_limit: None,
         })
     }
+    /// Keys and queries that don't name a namespace use this one instead of
+    /// the default namespace.
+    pub fn with_namespace<N: ToString>(mut self, namespace: N) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+    pub fn project_id(&self) -> &str {
+        &self.project_id
+    }
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
     pub fn insert<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
-        let kind_key = T::entity_kind_key();
-        let name_key = value.entity_name_key();
-        let properties = convert::to_datastore_value(value)
-            .and_then(|value| {
-                value.entity_value
+        self.write_model(Write::Insert, value, |_| {})
+    }
+    pub fn upsert<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
+        self.write_model(Write::Upsert, value, |_| {})
+    }
+    pub fn update<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
+        self.write_model(Write::Update, value, |_| {})
+    }
+    pub fn get<T: DeserializeOwned + EntityKey, K: ToString>(&self, name_key: K) -> Result<T, Error> {
+        self.register_hooks::<T>();
+        let payload = self.lookup_entity(to_name_key(T::entity_kind_key(), name_key.to_string()))?;
+        convert::from_datastore_entity(payload)
+            .ok_or_else(|| {
+                Error::Deserialization {
+                    msg: String::from("conversion or parser error")
+                }
             })
-            .and_then(|x| x.properties)
-            .ok_or(Error::Serialization {
-                msg: String::from("expecting struct/map like input")
-            })?;
-        let entity = google_datastore1::Entity {
-            properties: Some(properties),
-            key: Some(google_datastore1::Key {
-                path: Some(vec![
-                    google_datastore1::PathElement {
-                        kind: Some(kind_key.to_owned()),
-                        name: Some(name_key.to_owned()),
-                        id: None
+    }
+    /// Entities in the order of the keys, `None` for those that don't
+    /// exist. Looked up in as few requests as possible.
+    pub fn get_many<T, K, I>(&self, name_keys: I) -> Result<Vec<Option<T>>, Error>
+    where
+        T: DeserializeOwned + EntityKey,
+        K: ToString,
+        I: IntoIterator<Item = K>,
+    {
+        self.register_hooks::<T>();
+        let keys = name_keys
+            .into_iter()
+            .map(|x| to_name_key(T::entity_kind_key(), x.to_string()))
+            .collect();
+        self.lookup_entities(keys)?
+            .into_iter()
+            .map(|x| x.map(|payload| {
+                convert::from_datastore_entity(payload).ok_or_else(|| {
+                    Error::Deserialization {
+                        msg: String::from("conversion or parser error")
                     }
-                ]),
-                partition_id: None
-            })
-        };
-        let req = google_datastore1::CommitRequest {
-            transaction: None,
-            mutations: Some(vec![
-                google_datastore1::Mutation {
-                    insert: Some(entity),
-                    delete: None,
-                    update: None,
-                    base_version: None,
-                    upsert: None
+                })
+            }).transpose())
+            .collect()
+    }
+    /// Like `get`, but keeps stored properties that `T` doesn't know about,
+    /// so they survive a later write. See `Tracked`.
+    pub fn get_tracked<T: Serialize + DeserializeOwned + EntityKey, K: ToString>(&self, name_key: K) -> Result<Tracked<T>, Error> {
+        self.register_hooks::<T>();
+        let payload = self.lookup_entity(to_name_key(T::entity_kind_key(), name_key.to_string()))?;
+        Tracked::from_datastore_entity(payload)
+            .ok_or_else(|| {
+                Error::Deserialization {
+                    msg: String::from("conversion or parser error")
                 }
-            ]),
-            mode: Some(String::from("NON_TRANSACTIONAL"))
+            })
+    }
+    pub fn delete<T: EntityKey, K: ToString>(&self, name_key: K) -> Result<(), Error> {
+        self.register_hooks::<T>();
+        let entity_key = to_name_key(T::entity_kind_key(), name_key.to_string());
+        self.commit(vec![google_datastore1::Mutation {
+            delete: Some(entity_key),
+            ..google_datastore1::Mutation::default()
+        }])
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - UNTYPED
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    pub fn get_entity(&self, key: &Key) -> Result<Entity, Error> {
+        let payload = self.lookup_entity(key.to_datastore())?;
+        Entity::from_datastore(payload)
+    }
+    /// See `get_many`.
+    pub fn get_entities(&self, keys: &[Key]) -> Result<Vec<Option<Entity>>, Error> {
+        let keys = keys.iter().map(Key::to_datastore).collect();
+        self.lookup_entities(keys)?
+            .into_iter()
+            .map(|x| x.map(Entity::from_datastore).transpose())
+            .collect()
+    }
+    /// Inserts or replaces the entity. The key must be set.
+    pub fn put_entity(&self, entity: Entity) -> Result<(), Error> {
+        if entity.key.is_none() {
+            return Err(Error::IncompleteKey);
+        }
+        self.commit(vec![google_datastore1::Mutation {
+            upsert: Some(entity.into_datastore()),
+            ..google_datastore1::Mutation::default()
+        }])
+    }
+    pub fn delete_entity(&self, key: &Key) -> Result<(), Error> {
+        self.commit(vec![google_datastore1::Mutation {
+            delete: Some(key.to_datastore()),
+            ..google_datastore1::Mutation::default()
+        }])
+    }
+    /// All entities of the given kind (in the client's namespace).
+    pub fn query_kind(&self, kind: &str) -> Result<Vec<Entity>, Error> {
+        let query = google_datastore1::Query {
+            kind: Some(vec![google_datastore1::KindExpression {
+                name: Some(kind.to_owned()),
+            }]),
+            ..google_datastore1::Query::default()
         };
-        let result = self.handle
-            .projects()
-            .commit(req, &self.project_id)
-            .doit();
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::DatabaseResponse(e))
+        self.run_query(query, None)?
+            .into_iter()
+            .map(Entity::from_datastore)
+            .collect()
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - INTERNAL
+///////////////////////////////////////////////////////////////////////////////
+
+/// How a model is written, see `write_model`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Write {
+    Insert,
+    Upsert,
+    Update,
+}
+
+impl Write {
+    pub(crate) fn mutation(self, entity: google_datastore1::Entity) -> google_datastore1::Mutation {
+        let mut mutation = google_datastore1::Mutation::default();
+        match self {
+            Write::Insert => mutation.insert = Some(entity),
+            Write::Upsert => mutation.upsert = Some(entity),
+            Write::Update => mutation.update = Some(entity),
         }
+        mutation
     }
-    pub fn upsert<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
-        let kind_key = T::entity_kind_key();
-        let name_key = value.entity_name_key();
-        let properties = convert::to_datastore_value(value)
-            .and_then(|value| {
-                value.entity_value
-            })
-            .and_then(|x| x.properties)
-            .ok_or(Error::Serialization {
-                msg: String::from("expecting struct/map like input")
-            })?;
-        let entity = google_datastore1::Entity {
-            properties: Some(properties),
-            key: Some(google_datastore1::Key {
-                path: Some(vec![
-                    google_datastore1::PathElement {
-                        kind: Some(kind_key.to_owned()),
-                        name: Some(name_key.to_owned()),
-                        id: None
+}
+
+impl DatastoreClient {
+    /// Every typed write goes through here. `prepare` sees the entity
+    /// just before it's committed, e.g. to exclude properties from indexes.
+    pub(crate) fn write_model<T, F>(&self, write: Write, value: T, prepare: F) -> Result<(), Error>
+    where
+        T: Serialize + EntityKey,
+        F: FnOnce(&mut google_datastore1::Entity),
+    {
+        self.register_hooks::<T>();
+        let mut entity = self.stamp_version(to_entity(value)?);
+        self.stamp_timestamps(write, &mut entity);
+        prepare(&mut entity);
+        self.commit(vec![write.mutation(entity)])
+    }
+    pub(crate) fn commit(&self, mutations: Vec<google_datastore1::Mutation>) -> Result<(), Error> {
+        self.commit_with(mutations, None)
+    }
+    /// Commits the mutations as they come, `COMMIT_LIMIT` at a time, up to
+    /// the first error. Returns the number committed.
+    pub(crate) fn commit_chunked<I>(&self, mutations: I) -> Result<u64, Error>
+    where
+        I: IntoIterator<Item = Result<google_datastore1::Mutation, Error>>,
+    {
+        let mut chunk = Vec::with_capacity(COMMIT_LIMIT);
+        let mut count = 0;
+        for mutation in mutations {
+            chunk.push(mutation?);
+            if chunk.len() == COMMIT_LIMIT {
+                count += chunk.len() as u64;
+                self.commit(std::mem::take(&mut chunk))?;
+            }
+        }
+        if !chunk.is_empty() {
+            count += chunk.len() as u64;
+            self.commit(chunk)?;
+        }
+        Ok(count)
+    }
+    /// Commits the transaction if given, otherwise a non-transactional write.
+    pub(crate) fn commit_with(&self, mutations: Vec<google_datastore1::Mutation>, transaction: Option<String>) -> Result<(), Error> {
+        let mode = match transaction {
+            Some(_) => "TRANSACTIONAL",
+            None => "NON_TRANSACTIONAL",
+        };
+        let mutations = mutations
+            .into_iter()
+            .map(|mut x| {
+                for entity in x.insert.iter_mut().chain(x.update.iter_mut()).chain(x.upsert.iter_mut()) {
+                    if let Some(key) = entity.key.as_mut() {
+                        self.default_namespace(key);
                     }
-                ]),
-                partition_id: None
+                }
+                if let Some(key) = x.delete.as_mut() {
+                    self.default_namespace(key);
+                }
+                x
             })
-        };
+            .collect::<Vec<_>>();
+        let mutations = self.before_commit(mutations)?;
+        self.invalidate_cached(&mutations);
+        let mut rpc = Rpc::new(Operation::Commit);
+        for key in mutations.iter().filter_map(mutation_key) {
+            rpc.key(key);
+        }
         let req = google_datastore1::CommitRequest {
-            transaction: None,
-            mutations: Some(vec![
-                google_datastore1::Mutation {
-                    insert: None,
-                    delete: None,
-                    update: None,
-                    base_version: None,
-                    upsert: Some(entity),
-                }
-            ]),
-            mode: Some(String::from("NON_TRANSACTIONAL"))
+            transaction,
+            mutations: Some(mutations),
+            mode: Some(String::from(mode))
         };
-        let result = self.handle
-            .projects()
-            .commit(req, &self.project_id)
-            .doit();
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::DatabaseResponse(e))
-        }
+        self.instrument(rpc, |_| {
+            self.call::<_, google_datastore1::CommitResponse>("commit", &req).map(|_| ())
+        })
     }
-    pub fn update<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
-        let kind_key = T::entity_kind_key();
-        let name_key = value.entity_name_key();
-        let properties = convert::to_datastore_value(value)
-            .and_then(|value| {
-                value.entity_value
-            })
-            .and_then(|x| x.properties)
-            .ok_or(Error::Serialization {
-                msg: String::from("expecting struct/map like input")
-            })?;
-        let entity = google_datastore1::Entity {
-            properties: Some(properties),
-            key: Some(google_datastore1::Key {
-                path: Some(vec![
-                    google_datastore1::PathElement {
-                        kind: Some(kind_key.to_owned()),
-                        name: Some(name_key.to_owned()),
-                        id: None
-                    }
-                ]),
-                partition_id: None
-            })
+    pub(crate) fn begin_transaction(&self) -> Result<String, Error> {
+        let req = google_datastore1::BeginTransactionRequest::default();
+        self.instrument(Rpc::new(Operation::BeginTransaction), |_| {
+            self.call::<_, google_datastore1::BeginTransactionResponse>("beginTransaction", &req)?
+                .transaction
+                .ok_or(Error::NoPayload)
+        })
+    }
+    pub(crate) fn rollback(&self, transaction: String) -> Result<(), Error> {
+        let req = google_datastore1::RollbackRequest {
+            transaction: Some(transaction),
         };
-        let req = google_datastore1::CommitRequest {
-            transaction: None,
-            mutations: Some(vec![
-                google_datastore1::Mutation {
-                    insert: None,
-                    delete: None,
-                    update: Some(entity),
-                    base_version: None,
-                    upsert: None,
+        self.instrument(Rpc::new(Operation::Rollback), |_| {
+            self.call::<_, google_datastore1::RollbackResponse>("rollback", &req).map(|_| ())
+        })
+    }
+    /// Entities come back upcast (see `register_upcast`) and through their
+    /// `after_load` hook (see `register_hooks`).
+    pub(crate) fn lookup_entity(&self, key: google_datastore1::Key) -> Result<google_datastore1::Entity, Error> {
+        self.lookup_entities(vec![key])?
+            .pop()
+            .flatten()
+            .ok_or(Error::NoPayload)
+    }
+    /// Entities in the order of the keys, from the cache where possible
+    /// (see `with_cache`). Entities come back upcast and hooked.
+    pub(crate) fn lookup_entities(&self, keys: Vec<google_datastore1::Key>) -> Result<Vec<Option<google_datastore1::Entity>>, Error> {
+        let keys = keys
+            .into_iter()
+            .map(|mut x| {
+                self.default_namespace(&mut x);
+                Key::from_datastore(x)
+            })
+            .collect::<Vec<_>>();
+        let mut found = HashMap::new();
+        let mut pending = Vec::new();
+        let mut seen = HashSet::new();
+        for key in &keys {
+            if !seen.insert(key) {
+                continue;
+            }
+            match self.cached(key) {
+                Some(x) => {
+                    found.insert(key.clone(), x);
                 }
-            ]),
-            mode: Some(String::from("NON_TRANSACTIONAL"))
-        };
-        let result = self.handle
-            .projects()
-            .commit(req, &self.project_id)
-            .doit();
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::DatabaseResponse(e))
+                None => pending.push(key.clone()),
+            }
+        }
+        let mut fetched = Vec::new();
+        while !pending.is_empty() {
+            // AT MOST 1000 KEYS PER LOOKUP
+            let rest = pending.split_off(pending.len().min(1000));
+            let keys = pending.iter().map(Key::to_datastore).collect::<Vec<_>>();
+            let mut rpc = Rpc::new(Operation::Lookup);
+            keys.iter().for_each(|x| {
+                rpc.key(x);
+            });
+            let req = google_datastore1::LookupRequest {
+                keys: Some(keys),
+                read_options: None,
+            };
+            let response = self.instrument(rpc, |_| {
+                self.call::<_, google_datastore1::LookupResponse>("lookup", &req)
+            })?;
+            fetched.extend(response.found.unwrap_or_default().into_iter().filter_map(|x| x.entity));
+            // KEYS DATASTORE DIDN'T GET TO, TRY AGAIN
+            pending = response.deferred
+                .unwrap_or_default()
+                .into_iter()
+                .map(Key::from_datastore)
+                .chain(rest)
+                .collect();
+        }
+        for entity in self.upcast_all(fetched)? {
+            if let Some(key) = entity.key.clone().map(Key::from_datastore) {
+                self.store_cached(key.clone(), &entity);
+                found.insert(key, entity);
+            }
         }
+        // NOT CACHED, SO HOOKS SEE EVERY READ
+        keys.iter()
+            .map(|x| match found.get(x) {
+                Some(entity) => Ok(self.after_load_all(vec![entity.clone()])?.pop()),
+                None => Ok(None),
+            })
+            .collect()
     }
-    pub fn get<T: DeserializeOwned + EntityKey, K: ToString>(&self, name_key: K) -> Result<T, Error> {
-        let kind_key = T::entity_kind_key();
+    /// Reads within the transaction if given, bypassing the cache. The entity
+    /// comes back as stored: not upcast or hooked.
+    pub(crate) fn lookup_entity_with(&self, mut key: google_datastore1::Key, transaction: Option<String>) -> Result<google_datastore1::Entity, Error> {
+        self.default_namespace(&mut key);
+        let mut rpc = Rpc::new(Operation::Lookup);
+        rpc.key(&key);
         let req = google_datastore1::LookupRequest {
-            keys: Some(vec![
-                google_datastore1::Key {
-                    path: Some(vec![
-                        google_datastore1::PathElement {
-                            kind: Some(kind_key),
-                            name: Some(name_key.to_string()),
-                            id: None
-                        }
-                    ]),
-                    partition_id: None
-                }]),
-            read_options: None
+            keys: Some(vec![key]),
+            read_options: transaction.map(|x| google_datastore1::ReadOptions {
+                transaction: Some(x),
+                read_consistency: None,
+            }),
         };
-        let result = self.handle
-            .projects()
-            .lookup(req, &self.project_id)
-            .doit();
-        match result {
-            Ok((_, lookup_response)) => {
-                let payload = lookup_response.found
-                    .and_then(|entities| {
-                        entities.first().map(|x| x.clone())
-                    })
-                    .and_then(|x| x.entity)
-                    .ok_or(Error::NoPayload)?;
-                convert::from_datastore_entity(payload.clone())
-                    .ok_or_else(|| {
-                        Error::Deserialization {
-                            msg: String::from("conversion or parser error")
-                        }
-                    })
-            }
-            Err(e) => Err(Error::DatabaseResponse(e)),
+        let lookup_response = self.instrument(rpc, |_| {
+            self.call::<_, google_datastore1::LookupResponse>("lookup", &req)
+        })?;
+        lookup_response.found
+            .and_then(|entities| entities.into_iter().next())
+            .and_then(|x| x.entity)
+            .ok_or(Error::NoPayload)
+    }
+    /// Runs the query to completion, following cursors across batches.
+    /// Full entities come back upcast and hooked, see `lookup_entity`.
+    pub(crate) fn run_query(&self, query: google_datastore1::Query, namespace: Option<String>) -> Result<Vec<google_datastore1::Entity>, Error> {
+        self.log_index(&query);
+        let is_projection = query.projection.as_ref().is_some_and(|x| !x.is_empty());
+        let batch = self.run_query_batch(query.clone(), namespace.clone())?;
+        let results = self.page_query(query, namespace, batch)?;
+        if is_projection {
+            return Ok(results);
         }
+        let results = self.upcast_all(results)?;
+        self.after_load_all(results)
     }
-    pub fn delete<T: EntityKey, K: ToString>(&self, name_key: K) -> Result<(), Error> {
-        let kind_key = T::entity_kind_key();
-        let name_key = name_key.to_string();
-        let entity_key = google_datastore1::Key {
-            path: Some(vec![
-                google_datastore1::PathElement {
-                    kind: Some(kind_key.to_owned()),
-                    name: Some(name_key.to_owned()),
-                    id: None
+    /// The entities of `batch`, the first page of `query`, and of every page
+    /// after it. Each page is sent with what's left of the limit and offset.
+    pub(crate) fn page_query(&self, mut query: google_datastore1::Query, namespace: Option<String>, mut batch: google_datastore1::QueryResultBatch) -> Result<Vec<google_datastore1::Entity>, Error> {
+        let mut results = Vec::new();
+        loop {
+            let entity_results = batch.entity_results.unwrap_or_default();
+            let received = entity_results.len() as i32;
+            results.extend(entity_results.into_iter().filter_map(|x| x.entity));
+            // A PAGE CAN BE EMPTY WHILE THE OFFSET IS STILL BEING SKIPPED
+            if batch.more_results.as_deref() != Some("NOT_FINISHED") {
+                return Ok(results);
+            }
+            if let Some(limit) = query.limit {
+                if limit - received <= 0 {
+                    return Ok(results);
                 }
-            ]),
-            partition_id: None
+                query.limit = Some(limit - received);
+            }
+            if let Some(offset) = query.offset {
+                query.offset = Some(offset - batch.skipped_results.unwrap_or(0)).filter(|x| *x > 0);
+            }
+            query.start_cursor = match batch.end_cursor.or(batch.skipped_cursor) {
+                Some(x) => Some(x),
+                None => return Ok(results),
+            };
+            batch = self.run_query_batch(query.clone(), namespace.clone())?;
+        }
+    }
+    /// A single batch, for callers that page themselves.
+    pub(crate) fn run_query_batch(&self, query: google_datastore1::Query, namespace: Option<String>) -> Result<google_datastore1::QueryResultBatch, Error> {
+        let mut rpc = Rpc::new(Operation::RunQuery);
+        rpc.kind(query.kind.iter().flatten().next().and_then(|x| x.name.as_ref()));
+        let req = google_datastore1::RunQueryRequest {
+            query: Some(query),
+            partition_id: self.partition_id(namespace),
+            ..google_datastore1::RunQueryRequest::default()
         };
-        let req = google_datastore1::CommitRequest {
-            transaction: None,
-            mutations: Some(vec![
-                google_datastore1::Mutation {
-                    insert: None,
-                    delete: Some(entity_key),
-                    update: None,
-                    base_version: None,
-                    upsert: None,
-                }
-            ]),
-            mode: Some(String::from("NON_TRANSACTIONAL"))
+        rpc.namespace(req.partition_id.as_ref());
+        self.instrument(rpc, |rpc| {
+            let batch = self.call::<_, google_datastore1::RunQueryResponse>("runQuery", &req)?
+                .batch
+                .unwrap_or_default();
+            rpc.keys(batch.entity_results.as_ref().map(Vec::len).unwrap_or(0));
+            Ok(batch)
+        })
+    }
+    /// The partition for the given namespace, or the client's.
+    pub(crate) fn partition_id(&self, namespace: Option<String>) -> Option<google_datastore1::PartitionId> {
+        namespace
+            .or_else(|| self.namespace.clone())
+            .map(|x| google_datastore1::PartitionId {
+                namespace_id: Some(x),
+                project_id: None,
+            })
+    }
+    fn default_namespace(&self, key: &mut google_datastore1::Key) {
+        if key.partition_id.is_none() {
+            key.partition_id = self.partition_id(None);
+        }
+    }
+    /// `call_raw` for the hub's request and response types.
+    pub(crate) fn call<Req: Serialize, Res: DeserializeOwned>(&self, method: &str, req: &Req) -> Result<Res, Error> {
+        let body = serde_json::to_value(req)
+            .map_err(|e| Error::Serialization {msg: e.to_string()})?;
+        let response = self.call_raw(method, body)?;
+        serde_json::from_value(response.clone())
+            .map_err(|e| Error::DatabaseResponse(google_datastore1::Error::JsonDecodeError(response.to_string(), e)))
+    }
+    /// POSTs to `v1/projects/{project}:{method}` through the transport (see
+    /// `with_transport`). Every RPC goes through here.
+    pub(crate) fn call_raw(&self, method: &str, mut body: serde_json::Value) -> Result<serde_json::Value, Error> {
+        use yup_oauth2::GetToken;
+        let url = format!("{}v1/projects/{}:{}", self.base_url, self.project_id, method);
+        let token = self.auth
+            .clone()
+            .token(&[SCOPE])
+            .map_err(|e| Error::DatabaseResponse(google_datastore1::Error::MissingToken(e)))?;
+        google_datastore1::remove_json_null_values(&mut body);
+        let request = HttpRequest {
+            url,
+            headers: vec![
+                (String::from("Content-Type"), String::from("application/json")),
+                (String::from("User-Agent"), format!("ddb/{}", env!("CARGO_PKG_VERSION"))),
+                (String::from("Authorization"), format!("Bearer {}", token.access_token)),
+            ],
+            body: serde_json::to_string(&body).expect("json to string"),
         };
-        let result = self.handle
-            .projects()
-            .commit(req, &self.project_id)
-            .doit();
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::DatabaseResponse(e))
+        let response = self.transport.send(&request)?;
+        if !response.is_success() {
+            return Err(error_response(response.status, &response.body));
         }
+        serde_json::from_str(&response.body)
+            .map_err(|e| Error::DatabaseResponse(google_datastore1::Error::JsonDecodeError(response.body.clone(), e)))
+    }
+}
+
+/// Datastore errors look like `{"error": {"code": 409, "message": "...",
+/// "status": "ABORTED"}}`, which is close enough to the hub's
+/// `ErrorResponse` to keep using `BadRequest` for all of them.
+fn error_response(status: u16, body: &str) -> Error {
+    if let Ok(e) = serde_json::from_str::<google_datastore1::ErrorResponse>(body) {
+        return Error::DatabaseResponse(google_datastore1::Error::BadRequest(e));
+    }
+    let json = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+    let message = json["error"]["message"]
+        .as_str()
+        .map(String::from)
+        .unwrap_or_else(|| body.trim().to_owned());
+    let errors = json["error"]["status"]
+        .as_str()
+        .map(|x| google_datastore1::ServerMessage {
+            domain: String::from("datastore"),
+            reason: String::from(x),
+            message: message.clone(),
+            location_type: None,
+            location: None,
+        })
+        .into_iter()
+        .collect();
+    Error::DatabaseResponse(google_datastore1::Error::BadRequest(google_datastore1::ErrorResponse {
+        error: google_datastore1::ServerError {
+            errors,
+            code: status,
+            message,
+        },
+    }))
+}
+
+/// The key a mutation writes or deletes.
+pub(crate) fn mutation_key(mutation: &google_datastore1::Mutation) -> Option<&google_datastore1::Key> {
+    mutation.insert
+        .iter()
+        .chain(mutation.update.iter())
+        .chain(mutation.upsert.iter())
+        .filter_map(|x| x.key.as_ref())
+        .chain(mutation.delete.iter())
+        .next()
+}
+
+fn to_name_key(kind_key: String, name_key: String) -> google_datastore1::Key {
+    google_datastore1::Key {
+        path: Some(vec![
+            google_datastore1::PathElement {
+                kind: Some(kind_key),
+                name: Some(name_key),
+                id: None
+            }
+        ]),
+        partition_id: None
     }
 }
+
+pub(crate) fn to_entity<T: Serialize + EntityKey>(value: T) -> Result<google_datastore1::Entity, Error> {
+    let key = to_name_key(T::entity_kind_key(), value.entity_name_key());
+    let properties = convert::to_datastore_value(value)
+        .and_then(|value| {
+            value.entity_value
+        })
+        .and_then(|x| x.properties)
+        .ok_or(Error::Serialization {
+            msg: String::from("expecting struct/map like input")
+        })?;
+    Ok(google_datastore1::Entity {
+        properties: Some(properties),
+        key: Some(key),
+    })
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use crate::db::{DatastoreClient, EntityKey, Error, COMMIT_LIMIT};
+use crate::entity::Key;
+use crate::query::{self, Filter};
+
+///////////////////////////////////////////////////////////////////////////////
+// DELETION
+///////////////////////////////////////////////////////////////////////////////
+
+/// A pending delete of every entity a keys-only query finds, see
+/// `DatastoreClient::delete_all` and `DatastoreClient::delete_subtree`.
+/// Nothing happens until `run`.
+///
+/// Keys are read and deleted a page at a time, so an interrupted run
+/// leaves the rest for the next one.
+#[must_use = "nothing is deleted until `run`"]
+pub struct Deletion<'a> {
+    client: &'a DatastoreClient,
+    /// An error here is returned by `run`.
+    query: Result<google_datastore1::Query, Error>,
+    namespace: Option<String>,
+    batch_size: i32,
+    dry_run: bool,
+}
+
+/// What was (or, for a dry run, would be) deleted.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DeleteReport {
+    pub keys: Vec<Key>,
+    pub dry_run: bool,
+}
+
+impl DeleteReport {
+    pub fn count(&self) -> usize {
+        self.keys.len()
+    }
+    /// Number of keys per kind, e.g. for the descendants of a subtree.
+    pub fn kinds(&self) -> BTreeMap<String, usize> {
+        let mut kinds = BTreeMap::new();
+        for kind in self.keys.iter().filter_map(Key::kind) {
+            *kinds.entry(kind.to_owned()).or_insert(0) += 1;
+        }
+        kinds
+    }
+}
+
+impl<'a> Deletion<'a> {
+    /// Only finds the keys, without deleting anything.
+    pub fn dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+    /// Keys per page (and per commit), at most 500 (the commit limit).
+    pub fn batch_size(mut self, batch_size: i32) -> Self {
+        self.batch_size = batch_size.clamp(1, COMMIT_LIMIT as i32);
+        self
+    }
+    pub fn run(self) -> Result<DeleteReport, Error> {
+        let mut report = DeleteReport {
+            dry_run: self.dry_run,
+            ..DeleteReport::default()
+        };
+        let base = self.query?;
+        let mut cursor = None;
+        loop {
+            let mut query = base.clone();
+            query.projection = Some(query::projection(&["__key__"]));
+            query.limit = Some(self.batch_size);
+            query.start_cursor = cursor;
+            let mut batch = self.client.run_query_batch(query, self.namespace.clone())?;
+            let keys = batch.entity_results
+                .take()
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|x| x.entity?.key)
+                .collect::<Vec<_>>();
+            let count = keys.len();
+            if !self.dry_run && !keys.is_empty() {
+                let mutations = keys
+                    .iter()
+                    .map(|x| google_datastore1::Mutation {
+                        delete: Some(x.clone()),
+                        ..google_datastore1::Mutation::default()
+                    })
+                    .collect();
+                self.client.commit(mutations)?;
+            }
+            report.keys.extend(keys.into_iter().map(Key::from_datastore));
+            let has_more = query::has_more(&batch, count);
+            cursor = batch.end_cursor;
+            if !has_more || cursor.is_none() {
+                return Ok(report);
+            }
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - BULK DELETES
+///////////////////////////////////////////////////////////////////////////////
+
+/// ```no_run
+/// # use ddb::*;
+/// # struct Session {}
+/// # impl EntityKey for Session {
+/// #     fn entity_kind_key() -> String {String::from("Session")}
+/// #     fn entity_name_key(&self) -> String {String::new()}
+/// # }
+/// let db = DatastoreClient::new().unwrap();
+/// let report = db.delete_subtree(&Key::name("Account", "acme")).dry_run().run().unwrap();
+/// for (kind, count) in report.kinds() {
+///     println!("would delete {} {}", count, kind);
+/// }
+/// db.delete_all::<Session>().run().unwrap();
+/// ```
+impl DatastoreClient {
+    /// Every `T` entity in the client's namespace.
+    pub fn delete_all<T: EntityKey>(&self) -> Deletion<'_> {
+        self.register_hooks::<T>();
+        self.delete_kind(&T::entity_kind_key())
+    }
+    /// Same as `delete_all`, for a kind with no Rust model.
+    pub fn delete_kind(&self, kind: &str) -> Deletion<'_> {
+        let query = google_datastore1::Query {
+            kind: Some(vec![google_datastore1::KindExpression {
+                name: Some(kind.to_owned()),
+            }]),
+            ..google_datastore1::Query::default()
+        };
+        self.deletion(Ok(query), None)
+    }
+    /// The entity and every descendant of it, of any kind. The key's
+    /// namespace is used, or the client's if it has none.
+    pub fn delete_subtree(&self, key: &Key) -> Deletion<'_> {
+        let key = match (&key.namespace, &self.namespace) {
+            (None, Some(x)) => key.clone().with_namespace(x),
+            _ => key.clone(),
+        };
+        let query = if key.is_complete() {
+            Filter::has_ancestor(&key)
+                .to_datastore()
+                .map(|x| google_datastore1::Query {
+                    filter: Some(x),
+                    ..google_datastore1::Query::default()
+                })
+        } else {
+            Err(Error::IncompleteKey)
+        };
+        self.deletion(query, key.namespace)
+    }
+    fn deletion(&self, query: Result<google_datastore1::Query, Error>, namespace: Option<String>) -> Deletion<'_> {
+        Deletion {
+            client: self,
+            query,
+            namespace,
+            batch_size: COMMIT_LIMIT as i32,
+            dry_run: false,
+        }
+    }
+}
@@ -0,0 +1,480 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::marker::PhantomData;
+use serde::{Serialize, Deserialize, Serializer, Deserializer, de::DeserializeOwned};
+use crate::convert;
+use crate::db::Error;
+
+///////////////////////////////////////////////////////////////////////////////
+// KEY
+///////////////////////////////////////////////////////////////////////////////
+
+/// A full key path, from the root ancestor down to the entity itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key {
+    pub namespace: Option<String>,
+    pub path: Vec<PathElement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PathElement {
+    pub kind: String,
+    /// `None` for an incomplete key (the id is allocated by Datastore on insert).
+    pub id: Option<KeyId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyId {
+    Id(i64),
+    Name(String),
+}
+
+impl Key {
+    pub fn name<K: ToString, N: ToString>(kind: K, name: N) -> Self {
+        Key {namespace: None, path: Vec::new()}.child_name(kind, name)
+    }
+    pub fn id<K: ToString>(kind: K, id: i64) -> Self {
+        Key {namespace: None, path: Vec::new()}.child_id(kind, id)
+    }
+    pub fn child_name<K: ToString, N: ToString>(mut self, kind: K, name: N) -> Self {
+        self.path.push(PathElement {
+            kind: kind.to_string(),
+            id: Some(KeyId::Name(name.to_string())),
+        });
+        self
+    }
+    pub fn child_id<K: ToString>(mut self, kind: K, id: i64) -> Self {
+        self.path.push(PathElement {
+            kind: kind.to_string(),
+            id: Some(KeyId::Id(id)),
+        });
+        self
+    }
+    pub fn with_namespace<N: ToString>(mut self, namespace: N) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+    /// The kind of the entity this key points to.
+    pub fn kind(&self) -> Option<&str> {
+        self.path.last().map(|x| x.kind.as_str())
+    }
+    pub fn parent(&self) -> Option<Key> {
+        if self.path.len() < 2 {
+            return None;
+        }
+        let mut parent = self.clone();
+        parent.path.pop();
+        Some(parent)
+    }
+    pub fn is_complete(&self) -> bool {
+        !self.path.is_empty() && self.path.iter().all(|x| x.id.is_some())
+    }
+    pub(crate) fn to_datastore(&self) -> google_datastore1::Key {
+        let path = self.path
+            .iter()
+            .map(|x| google_datastore1::PathElement {
+                kind: Some(x.kind.clone()),
+                id: match &x.id {
+                    Some(KeyId::Id(id)) => Some(id.to_string()),
+                    _ => None,
+                },
+                name: match &x.id {
+                    Some(KeyId::Name(name)) => Some(name.clone()),
+                    _ => None,
+                },
+            })
+            .collect::<Vec<_>>();
+        google_datastore1::Key {
+            path: Some(path),
+            partition_id: self.namespace.as_ref().map(|x| google_datastore1::PartitionId {
+                namespace_id: Some(x.clone()),
+                project_id: None,
+            }),
+        }
+    }
+    pub(crate) fn from_datastore(key: google_datastore1::Key) -> Self {
+        let path = key.path
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| {
+                let id = x.id.and_then(|x| x.parse().ok()).map(KeyId::Id);
+                PathElement {
+                    kind: x.kind.unwrap_or_default(),
+                    id: x.name.map(KeyId::Name).or(id),
+                }
+            })
+            .collect::<Vec<_>>();
+        Key {
+            namespace: key.partition_id
+                .and_then(|x| x.namespace_id)
+                .filter(|x| !x.is_empty()),
+            path,
+        }
+    }
+}
+
+/// The plain JSON shape of a key, as seen by models and `Entity::to_json`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KeyRepr {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    path: Vec<PathElement>,
+}
+
+impl KeyRepr {
+    pub(crate) fn from_key(key: Key) -> Self {
+        KeyRepr {namespace: key.namespace, path: key.path}
+    }
+}
+
+impl Serialize for Key {
+    /// Serializes to a real Datastore key value when passed through the
+    /// converter (e.g. as a model field).
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = google_datastore1::Value {
+            key_value: Some(self.to_datastore()),
+            ..google_datastore1::Value::default()
+        };
+        convert::raw_value_to_json(&value).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let json = serde_json::Value::deserialize(deserializer)?;
+        if let Ok(x) = serde_json::from_value::<KeyRepr>(json.clone()) {
+            return Ok(Key {namespace: x.namespace, path: x.path});
+        }
+        match Value::from_json(json).map_err(D::Error::custom)? {
+            Value::Key(key) => Ok(key),
+            _ => Err(D::Error::custom("expecting a datastore key")),
+        }
+    }
+}
+
+
+/// A key known to point to an entity of the `T` kind.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TypedKey<T> {
+    key: Key,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedKey<T> {
+    pub(crate) fn new(key: Key) -> Self {
+        TypedKey {key, marker: PhantomData}
+    }
+    /// The name key, as used by `EntityKey` and `DatastoreClient::get`.
+    pub fn name(&self) -> Option<&str> {
+        match self.key.path.last().and_then(|x| x.id.as_ref()) {
+            Some(KeyId::Name(x)) => Some(x.as_str()),
+            _ => None,
+        }
+    }
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+    pub fn into_key(self) -> Key {
+        self.key
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// VALUE
+///////////////////////////////////////////////////////////////////////////////
+
+/// A typed Datastore property value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Double(f64),
+    /// RFC 3339, e.g. `2020-01-01T00:00:00Z`.
+    Timestamp(String),
+    Key(Key),
+    String(String),
+    Blob(Vec<u8>),
+    GeoPoint {
+        latitude: f64,
+        longitude: f64,
+    },
+    Array(Vec<Value>),
+    /// An embedded entity (usually without a key).
+    Entity(Entity),
+}
+
+impl Value {
+    pub(crate) fn into_datastore(self, exclude_from_indexes: bool) -> google_datastore1::Value {
+        let mut value = google_datastore1::Value::default();
+        match self {
+            Value::Null => value.null_value = Some(String::from("NULL_VALUE")),
+            Value::Boolean(x) => value.boolean_value = Some(x),
+            Value::Integer(x) => value.integer_value = Some(x.to_string()),
+            Value::Double(x) => value.double_value = Some(x),
+            Value::Timestamp(x) => value.timestamp_value = Some(x),
+            Value::Key(x) => value.key_value = Some(x.to_datastore()),
+            Value::String(x) => value.string_value = Some(x),
+            Value::Blob(x) => value.blob_value = Some(base64::encode(&x)),
+            Value::GeoPoint {latitude, longitude} => {
+                value.geo_point_value = Some(google_datastore1::LatLng {
+                    latitude: Some(latitude),
+                    longitude: Some(longitude),
+                });
+            }
+            Value::Array(xs) => {
+                // ARRAYS CAN'T BE EXCLUDED THEMSELVES, ONLY THEIR ELEMENTS
+                let xs = xs
+                    .into_iter()
+                    .map(|x| x.into_datastore(exclude_from_indexes))
+                    .collect::<Vec<_>>();
+                value.array_value = Some(google_datastore1::ArrayValue {values: Some(xs)});
+                return value;
+            }
+            Value::Entity(x) => value.entity_value = Some(x.into_datastore()),
+        }
+        if exclude_from_indexes {
+            value.exclude_from_indexes = Some(true);
+        }
+        value
+    }
+    pub(crate) fn from_datastore(value: google_datastore1::Value) -> Result<Self, Error> {
+        let invalid = |msg: &str| Error::Deserialization {msg: msg.to_owned()};
+        if let Some(x) = value.entity_value {
+            Entity::from_datastore(x).map(Value::Entity)
+        } else if let Some(x) = value.timestamp_value {
+            Ok(Value::Timestamp(x))
+        } else if let Some(x) = value.geo_point_value {
+            Ok(Value::GeoPoint {
+                latitude: x.latitude.unwrap_or_default(),
+                longitude: x.longitude.unwrap_or_default(),
+            })
+        } else if let Some(x) = value.blob_value {
+            base64::decode(&x)
+                .map(Value::Blob)
+                .map_err(|_| invalid("invalid base64 in blob value"))
+        } else if let Some(x) = value.double_value {
+            Ok(Value::Double(x))
+        } else if let Some(x) = value.string_value {
+            Ok(Value::String(x))
+        } else if let Some(x) = value.key_value {
+            Ok(Value::Key(Key::from_datastore(x)))
+        } else if let Some(x) = value.boolean_value {
+            Ok(Value::Boolean(x))
+        } else if let Some(xs) = value.array_value {
+            xs.values
+                .unwrap_or_default()
+                .into_iter()
+                .map(Value::from_datastore)
+                .collect::<Result<Vec<_>, _>>()
+                .map(Value::Array)
+        } else if let Some(x) = value.integer_value {
+            x.parse()
+                .map(Value::Integer)
+                .map_err(|_| invalid("invalid integer value"))
+        } else {
+            Ok(Value::Null)
+        }
+    }
+    /// Lossy, see `Entity::to_json`.
+    pub fn to_json(&self) -> serde_json::Value {
+        convert::from_datastore_value(self.clone().into_datastore(false))
+            .unwrap_or(serde_json::Value::Null)
+    }
+    /// Plain JSON values map to the closest Datastore type; objects become
+    /// embedded entities.
+    pub fn from_json(json: serde_json::Value) -> Result<Self, Error> {
+        convert::to_datastore_value(json)
+            .ok_or_else(|| Error::Serialization {
+                msg: String::from("unsupported json value")
+            })
+            .and_then(Value::from_datastore)
+    }
+}
+
+impl Serialize for Value {
+    /// Passes through the converter unchanged, so e.g. a `Value::Timestamp`
+    /// model field is stored as a real timestamp.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Null => serializer.serialize_unit(),
+            Value::Boolean(x) => serializer.serialize_bool(*x),
+            Value::Integer(x) => serializer.serialize_i64(*x),
+            Value::Double(x) => serializer.serialize_f64(*x),
+            Value::String(x) => serializer.serialize_str(x),
+            Value::Array(xs) => xs.serialize(serializer),
+            _ => convert::raw_value_to_json(&self.clone().into_datastore(false)).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let json = serde_json::Value::deserialize(deserializer)?;
+        Value::from_json(json).map_err(D::Error::custom)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(x: bool) -> Self {
+        Value::Boolean(x)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(x: i64) -> Self {
+        Value::Integer(x)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        Value::Double(x)
+    }
+}
+
+impl From<String> for Value {
+    fn from(x: String) -> Self {
+        Value::String(x)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(x: &str) -> Self {
+        Value::String(x.to_owned())
+    }
+}
+
+impl From<Key> for Value {
+    fn from(x: Key) -> Self {
+        Value::Key(x)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(x: Vec<u8>) -> Self {
+        Value::Blob(x)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ENTITY
+///////////////////////////////////////////////////////////////////////////////
+
+/// An untyped entity, for working with kinds that have no Rust model.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Entity {
+    /// Embedded entities usually have no key.
+    pub key: Option<Key>,
+    pub properties: BTreeMap<String, Value>,
+    /// Names of properties excluded from indexes.
+    pub unindexed: BTreeSet<String>,
+}
+
+impl Entity {
+    pub fn new(key: Key) -> Self {
+        Entity {key: Some(key), ..Entity::default()}
+    }
+    pub fn kind(&self) -> Option<&str> {
+        self.key.as_ref().and_then(|x| x.kind())
+    }
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.properties.get(name)
+    }
+    pub fn set<N: ToString, V: Into<Value>>(&mut self, name: N, value: V) -> &mut Self {
+        let name = name.to_string();
+        self.unindexed.remove(&name);
+        self.properties.insert(name, value.into());
+        self
+    }
+    /// Like `set`, but the property is excluded from indexes.
+    pub fn set_unindexed<N: ToString, V: Into<Value>>(&mut self, name: N, value: V) -> &mut Self {
+        let name = name.to_string();
+        self.unindexed.insert(name.clone());
+        self.properties.insert(name, value.into());
+        self
+    }
+    pub fn remove(&mut self, name: &str) -> Option<Value> {
+        self.unindexed.remove(name);
+        self.properties.remove(name)
+    }
+    /// Encodes a model the same way `DatastoreClient::upsert` would.
+    pub fn from_model<T: Serialize>(key: Key, value: &T) -> Result<Self, Error> {
+        let entity = convert::to_datastore_value(value)
+            .and_then(|x| x.entity_value)
+            .ok_or(Error::Serialization {
+                msg: String::from("expecting struct/map like input")
+            })?;
+        let mut entity = Entity::from_datastore(entity)?;
+        entity.key = Some(key);
+        Ok(entity)
+    }
+    /// Decodes into a model the same way `DatastoreClient::get` would.
+    pub fn to_model<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        convert::from_datastore_entity(self.clone().into_datastore())
+            .ok_or_else(|| Error::Deserialization {
+                msg: String::from("conversion or parser error")
+            })
+    }
+    pub fn from_json(key: Key, json: serde_json::Value) -> Result<Self, Error> {
+        Entity::from_model(key, &json)
+    }
+    /// Properties as plain JSON. This is lossy: timestamps become strings,
+    /// blobs base64 strings and keys `{"path": [...]}` objects.
+    pub fn to_json(&self) -> serde_json::Value {
+        let properties = self.properties
+            .iter()
+            .map(|(k, v)| (k.clone(), v.to_json()))
+            .collect();
+        serde_json::Value::Object(properties)
+    }
+    pub(crate) fn into_datastore(self) -> google_datastore1::Entity {
+        let unindexed = self.unindexed;
+        let properties = self.properties
+            .into_iter()
+            .map(|(k, v)| {
+                let v = v.into_datastore(unindexed.contains(&k));
+                (k, v)
+            })
+            .collect();
+        google_datastore1::Entity {
+            key: self.key.map(|x| x.to_datastore()),
+            properties: Some(properties),
+        }
+    }
+    pub(crate) fn from_datastore(entity: google_datastore1::Entity) -> Result<Self, Error> {
+        let mut result = Entity {
+            key: entity.key.map(Key::from_datastore),
+            ..Entity::default()
+        };
+        for (k, v) in entity.properties.unwrap_or_default() {
+            if is_excluded(&v) {
+                result.unindexed.insert(k.clone());
+            }
+            result.properties.insert(k, Value::from_datastore(v)?);
+        }
+        Ok(result)
+    }
+}
+
+pub(crate) fn is_excluded(value: &google_datastore1::Value) -> bool {
+    match &value.array_value {
+        Some(xs) => xs.values
+            .as_ref()
+            .map(|xs| xs.iter().any(is_excluded))
+            .unwrap_or(false),
+        None => value.exclude_from_indexes.unwrap_or(false),
+    }
+}
+
+/// Arrays can't be excluded themselves, only their elements.
+pub(crate) fn exclude_from_indexes(value: &mut google_datastore1::Value) {
+    match value.array_value.as_mut().and_then(|x| x.values.as_mut()) {
+        Some(values) => values.iter_mut().for_each(exclude_from_indexes),
+        None => value.exclude_from_indexes = Some(true),
+    }
+}
@@ -0,0 +1,490 @@
+//! The JSON step of the conversion, for `Options::enums` other than serde's
+//! own representation. Enums are re-tagged as they're serialized, and read
+//! back in either representation.
+use serde::{Serialize, de::{self, DeserializeOwned, IntoDeserializer, Visitor}, ser};
+use serde_json::{Map, Value};
+use crate::codec::Enums;
+
+pub(crate) fn to_json<T: Serialize + ?Sized>(value: &T, enums: &Enums) -> Result<Value, serde_json::Error> {
+    match enums {
+        Enums::External => serde_json::to_value(value),
+        _ => value.serialize(Encoder {enums}),
+    }
+}
+
+pub(crate) fn from_json<T: DeserializeOwned>(value: Value, enums: &Enums) -> Result<T, serde_json::Error> {
+    match enums {
+        Enums::External => serde_json::from_value(value),
+        _ => T::deserialize(Decoder {value, enums}),
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ENCODE
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy)]
+struct Encoder<'a> {
+    enums: &'a Enums,
+}
+
+impl Encoder<'_> {
+    /// The variant's name and data (`None` for unit variants), tagged.
+    fn tagged(self, variant: &'static str, content: Option<Value>) -> Result<Value, serde_json::Error> {
+        let mut result = Map::new();
+        match (self.enums, content) {
+            (Enums::External, None) => return Ok(Value::String(String::from(variant))),
+            (Enums::External, Some(x)) => {
+                result.insert(String::from(variant), x);
+            }
+            (Enums::Adjacent {tag, content: name}, content) => {
+                result.insert(tag.clone(), Value::String(String::from(variant)));
+                if let Some(x) = content {
+                    result.insert(name.clone(), x);
+                }
+            }
+            (Enums::Internal {tag}, content) => {
+                match content {
+                    None => (),
+                    Some(Value::Object(xs)) => result = xs,
+                    Some(_) => return Err(ser::Error::custom(format!(
+                        "variant {} holds no struct or map, so it can't be internally tagged",
+                        variant
+                    ))),
+                }
+                result.insert(tag.clone(), Value::String(String::from(variant)));
+            }
+        }
+        Ok(Value::Object(result))
+    }
+}
+
+macro_rules! forward_to_json {
+    ($($method:ident($ty:ty)),* $(,)?) => {
+        $(
+            fn $method(self, x: $ty) -> Result<Value, serde_json::Error> {
+                ser::Serializer::$method(serde_json::value::Serializer, x)
+            }
+        )*
+    };
+}
+
+impl<'a> ser::Serializer for Encoder<'a> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+    type SerializeSeq = Array<'a>;
+    type SerializeTuple = Array<'a>;
+    type SerializeTupleStruct = Array<'a>;
+    type SerializeTupleVariant = Array<'a>;
+    type SerializeMap = Object<'a>;
+    type SerializeStruct = Object<'a>;
+    type SerializeStructVariant = Object<'a>;
+    forward_to_json! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+    }
+    fn serialize_none(self) -> Result<Value, serde_json::Error> {
+        Ok(Value::Null)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, serde_json::Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Value, serde_json::Error> {
+        Ok(Value::Null)
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Value, serde_json::Error> {
+        Ok(Value::Null)
+    }
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<Value, serde_json::Error> {
+        self.tagged(variant, None)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Value, serde_json::Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, variant: &'static str, value: &T) -> Result<Value, serde_json::Error> {
+        let content = value.serialize(self)?;
+        self.tagged(variant, Some(content))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<Array<'a>, serde_json::Error> {
+        Ok(Array {encoder: self, variant: None, items: Vec::with_capacity(len.unwrap_or(0))})
+    }
+    fn serialize_tuple(self, len: usize) -> Result<Array<'a>, serde_json::Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<Array<'a>, serde_json::Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str, len: usize) -> Result<Array<'a>, serde_json::Error> {
+        Ok(Array {encoder: self, variant: Some(variant), items: Vec::with_capacity(len)})
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<Object<'a>, serde_json::Error> {
+        Ok(Object {encoder: self, variant: None, properties: Map::new(), key: None})
+    }
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Object<'a>, serde_json::Error> {
+        self.serialize_map(None)
+    }
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize) -> Result<Object<'a>, serde_json::Error> {
+        Ok(Object {encoder: self, variant: Some(variant), properties: Map::new(), key: None})
+    }
+}
+
+struct Array<'a> {
+    encoder: Encoder<'a>,
+    /// Set for tuple variants.
+    variant: Option<&'static str>,
+    items: Vec<Value>,
+}
+
+impl Array<'_> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        self.items.push(value.serialize(self.encoder)?);
+        Ok(())
+    }
+    fn finish(self) -> Result<Value, serde_json::Error> {
+        match self.variant {
+            Some(variant) => self.encoder.tagged(variant, Some(Value::Array(self.items))),
+            None => Ok(Value::Array(self.items)),
+        }
+    }
+}
+
+impl ser::SerializeSeq for Array<'_> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, serde_json::Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for Array<'_> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, serde_json::Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for Array<'_> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, serde_json::Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for Array<'_> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, serde_json::Error> {
+        self.finish()
+    }
+}
+
+struct Object<'a> {
+    encoder: Encoder<'a>,
+    /// Set for struct variants.
+    variant: Option<&'static str>,
+    properties: Map<String, Value>,
+    /// Between `serialize_key` and `serialize_value`.
+    key: Option<String>,
+}
+
+impl Object<'_> {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<(), serde_json::Error> {
+        self.properties.insert(key, value.serialize(self.encoder)?);
+        Ok(())
+    }
+    fn finish(self) -> Result<Value, serde_json::Error> {
+        match self.variant {
+            Some(variant) => self.encoder.tagged(variant, Some(Value::Object(self.properties))),
+            None => Ok(Value::Object(self.properties)),
+        }
+    }
+}
+
+impl ser::SerializeMap for Object<'_> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), serde_json::Error> {
+        // KEYS ARE NAMES, SO UNIT VARIANTS STAY STRINGS HERE
+        self.key = match serde_json::to_value(key)? {
+            Value::String(x) => Some(x),
+            Value::Number(x) => Some(x.to_string()),
+            Value::Bool(x) => Some(x.to_string()),
+            _ => return Err(ser::Error::custom("key must be a string")),
+        };
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        let key = self.key
+            .take()
+            .ok_or_else(|| ser::Error::custom("serialize_value called before serialize_key"))?;
+        self.insert(key, value)
+    }
+    fn end(self) -> Result<Value, serde_json::Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for Object<'_> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), serde_json::Error> {
+        self.insert(String::from(key), value)
+    }
+    fn end(self) -> Result<Value, serde_json::Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for Object<'_> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), serde_json::Error> {
+        self.insert(String::from(key), value)
+    }
+    fn end(self) -> Result<Value, serde_json::Error> {
+        self.finish()
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// DECODE
+///////////////////////////////////////////////////////////////////////////////
+
+struct Decoder<'a> {
+    value: Value,
+    enums: &'a Enums,
+}
+
+impl Decoder<'_> {
+    /// The variant's name and data, from any of the representations.
+    fn untag(self) -> Result<(String, Option<Value>), serde_json::Error> {
+        let mut xs = match self.value {
+            Value::String(variant) => return Ok((variant, None)),
+            Value::Object(xs) => xs,
+            _ => return Err(de::Error::custom("expected an enum")),
+        };
+        match self.enums {
+            Enums::Adjacent {tag, content} if xs.get(tag).is_some_and(Value::is_string) => {
+                let variant = xs.remove(tag).and_then(|x| x.as_str().map(String::from));
+                return Ok((variant.unwrap_or_default(), xs.remove(content)));
+            }
+            Enums::Internal {tag} if xs.get(tag).is_some_and(Value::is_string) => {
+                let variant = xs.remove(tag).and_then(|x| x.as_str().map(String::from));
+                return Ok((variant.unwrap_or_default(), Some(Value::Object(xs))));
+            }
+            _ => (),
+        }
+        // SERDE'S OWN, E.G. WRITTEN BEFORE THE OPTION WAS SET
+        match xs.len() {
+            1 => Ok(xs.into_iter().next().map(|(k, v)| (k, Some(v))).expect("one entry")),
+            _ => Err(de::Error::custom("expected an enum")),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Decoder<'_> {
+    type Error = serde_json::Error;
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, serde_json::Error> {
+        let enums = self.enums;
+        match self.value {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(x) => visitor.visit_bool(x),
+            Value::Number(x) => x.deserialize_any(visitor),
+            Value::String(x) => visitor.visit_string(x),
+            Value::Array(xs) => {
+                let len = xs.len();
+                let mut elements = Elements {items: xs.into_iter(), enums};
+                let result = visitor.visit_seq(&mut elements)?;
+                match elements.items.len() {
+                    0 => Ok(result),
+                    _ => Err(de::Error::invalid_length(len, &"fewer elements in array")),
+                }
+            }
+            Value::Object(xs) => visitor.visit_map(Properties {items: xs.into_iter(), value: None, enums}),
+        }
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, serde_json::Error> {
+        match self.value {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, serde_json::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str], visitor: V) -> Result<V::Value, serde_json::Error> {
+        let enums = self.enums;
+        let (variant, content) = self.untag()?;
+        visitor.visit_enum(Variant {variant, content, enums})
+    }
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct Elements<'a> {
+    items: std::vec::IntoIter<Value>,
+    enums: &'a Enums,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_> {
+    type Error = serde_json::Error;
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, serde_json::Error> {
+        let enums = self.enums;
+        self.items
+            .next()
+            .map(|value| seed.deserialize(Decoder {value, enums}))
+            .transpose()
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+struct Properties<'a> {
+    items: serde_json::map::IntoIter,
+    /// Between `next_key_seed` and `next_value_seed`.
+    value: Option<Value>,
+    enums: &'a Enums,
+}
+
+impl<'de> de::MapAccess<'de> for Properties<'_> {
+    type Error = serde_json::Error;
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, serde_json::Error> {
+        match self.items.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(Name(key)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, serde_json::Error> {
+        let value = self.value
+            .take()
+            .ok_or_else(|| de::Error::custom("next_value_seed called before next_key_seed"))?;
+        seed.deserialize(Decoder {value, enums: self.enums})
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+/// A map key, which may stand for a number or a bool (as serde_json
+/// writes them).
+struct Name(String);
+
+macro_rules! parse_name {
+    ($($method:ident => $visit:ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, serde_json::Error> {
+                match self.0.parse() {
+                    Ok(x) => visitor.$visit(x),
+                    Err(_) => visitor.visit_string(self.0),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Name {
+    type Error = serde_json::Error;
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, serde_json::Error> {
+        visitor.visit_string(self.0)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, serde_json::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str], visitor: V) -> Result<V::Value, serde_json::Error> {
+        visitor.visit_enum(IntoDeserializer::<serde_json::Error>::into_deserializer(self.0))
+    }
+    parse_name! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+    }
+    serde::forward_to_deserialize_any! {
+        i128 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct Variant<'a> {
+    variant: String,
+    content: Option<Value>,
+    enums: &'a Enums,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for Variant<'a> {
+    type Error = serde_json::Error;
+    type Variant = Self;
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), serde_json::Error> {
+        let variant = seed.deserialize(IntoDeserializer::<serde_json::Error>::into_deserializer(self.variant.clone()))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant<'_> {
+    type Error = serde_json::Error;
+    fn unit_variant(self) -> Result<(), serde_json::Error> {
+        match self.content {
+            None | Some(Value::Null) => Ok(()),
+            // INTERNALLY TAGGED, NOTHING BUT THE TAG
+            Some(Value::Object(xs)) if xs.is_empty() => Ok(()),
+            Some(_) => Err(de::Error::custom(format!("expected unit variant {}", self.variant))),
+        }
+    }
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, serde_json::Error> {
+        seed.deserialize(self.content())
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, serde_json::Error> {
+        de::Deserializer::deserialize_seq(self.content(), visitor)
+    }
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value, serde_json::Error> {
+        de::Deserializer::deserialize_map(self.content(), visitor)
+    }
+}
+
+impl<'a> Variant<'a> {
+    fn content(self) -> Decoder<'a> {
+        Decoder {value: self.content.unwrap_or(Value::Null), enums: self.enums}
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+use std::marker::PhantomData;
+use serde::Serialize;
+use crate::query::Filter;
+
+///////////////////////////////////////////////////////////////////////////////
+// FIELD
+///////////////////////////////////////////////////////////////////////////////
+
+/// A typed handle to a property of a model, generated by `#[derive(Fields)]`.
+///
+/// The property name follows serde's `rename`/`rename_all`, and fields marked
+/// `#[ddb(nested)]` give access to the properties of an embedded entity as
+/// dotted paths (e.g. `address.city`).
+///
+/// ```
+/// use ddb::{Fields, Filter};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize, Fields)]
+/// struct Address {
+///     city: String,
+/// }
+/// #[derive(Serialize, Deserialize, Fields)]
+/// #[serde(rename_all = "camelCase")]
+/// struct Person {
+///     display_name: String,
+///     age: i64,
+///     #[ddb(nested)]
+///     address: Address,
+/// }
+/// let adult_in_paris = Person::fields().age().ge(18)
+///     .and(Person::fields().address().city().eq("Paris"));
+/// assert_eq!(Person::fields().display_name().name(), "displayName");
+/// assert_eq!(Person::fields().address().city().name(), "address.city");
+/// ```
+///
+/// Fields that are never written (`skip`, `skip_serializing`) have no handle:
+///
+/// ```compile_fail
+/// # use ddb::Fields;
+/// #[derive(serde::Serialize, Fields)]
+/// struct Session {
+///     #[serde(skip_serializing)]
+///     token: String,
+/// }
+/// Session::fields().token();
+/// ```
+pub struct Field<T> {
+    name: String,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Field<T> {
+    #[doc(hidden)]
+    pub fn new(name: String) -> Self {
+        Field {name, marker: PhantomData}
+    }
+    /// The property name (or dotted path) as stored.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Operands convert into the field type, so e.g. a `Field<String>` takes a
+/// `&str` and a `Field<Option<T>>` takes a `T`.
+impl<T: Serialize> Field<T> {
+    pub fn eq<V: Into<T>>(&self, value: V) -> Filter {
+        Filter::eq(&self.name, value.into())
+    }
+    pub fn lt<V: Into<T>>(&self, value: V) -> Filter {
+        Filter::lt(&self.name, value.into())
+    }
+    pub fn le<V: Into<T>>(&self, value: V) -> Filter {
+        Filter::le(&self.name, value.into())
+    }
+    pub fn gt<V: Into<T>>(&self, value: V) -> Filter {
+        Filter::gt(&self.name, value.into())
+    }
+    pub fn ge<V: Into<T>>(&self, value: V) -> Filter {
+        Filter::ge(&self.name, value.into())
+    }
+    pub fn ne<V: Into<T>>(&self, value: V) -> Filter {
+        Filter::ne(&self.name, value.into())
+    }
+    pub fn is_in<V: Into<T>, I: IntoIterator<Item = V>>(&self, values: I) -> Filter {
+        Filter::is_in(&self.name, values.into_iter().map(Into::<T>::into))
+    }
+    pub fn not_in<V: Into<T>, I: IntoIterator<Item = V>>(&self, values: I) -> Filter {
+        Filter::not_in(&self.name, values.into_iter().map(Into::<T>::into))
+    }
+}
+
+impl<T: Serialize> Field<Vec<T>> {
+    /// The array has an element equal to the value.
+    pub fn contains<V: Into<T>>(&self, value: V) -> Filter {
+        Filter::contains(&self.name, value.into())
+    }
+}
+
+impl<T> Clone for Field<T> {
+    fn clone(&self) -> Self {
+        Field::new(self.name.clone())
+    }
+}
+
+impl<T> fmt::Debug for Field<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Field").field(&self.name).finish()
+    }
+}
+
+/// So fields can be passed wherever a property name is expected, e.g.
+/// `order_by`.
+impl<T> fmt::Display for Field<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// HAS FIELDS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Implemented by `#[derive(Fields)]`.
+pub trait HasFields {
+    type Fields;
+    /// The handles, with every property name prefixed by `prefix.` (if not
+    /// empty).
+    fn fields_at(prefix: String) -> Self::Fields;
+}
+
+// EMBEDDED ENTITIES ARE OFTEN OPTIONAL OR BOXED
+impl<T: HasFields> HasFields for Option<T> {
+    type Fields = T::Fields;
+    fn fields_at(prefix: String) -> Self::Fields {
+        T::fields_at(prefix)
+    }
+}
+
+impl<T: HasFields> HasFields for Box<T> {
+    type Fields = T::Fields;
+    fn fields_at(prefix: String) -> Self::Fields {
+        T::fields_at(prefix)
+    }
+}
+
+/// Datastore matches a filter on an array of entities against any element.
+impl<T: HasFields> HasFields for Vec<T> {
+    type Fields = T::Fields;
+    fn fields_at(prefix: String) -> Self::Fields {
+        T::fields_at(prefix)
+    }
+}
+
+#[doc(hidden)]
+pub fn field_path(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        String::from(name)
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::convert;
+use crate::db::{DatastoreClient, EntityKey, Error};
+use crate::entity::Entity;
+use crate::query;
+use crate::telemetry::{Operation, Rpc};
+
+///////////////////////////////////////////////////////////////////////////////
+// PARAMETERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Values for the `@name` and `@1`, `@2`, ... binding sites of a GQL query.
+///
+/// Values are encoded with the same converter as entity properties.
+#[derive(Debug, Clone, Default)]
+pub struct GqlParams {
+    named: HashMap<String, Result<google_datastore1::Value, String>>,
+    positional: Vec<Result<google_datastore1::Value, String>>,
+}
+
+impl GqlParams {
+    pub fn new() -> Self {
+        GqlParams::default()
+    }
+    /// Binds `@name`.
+    pub fn bind<N: ToString, V: Serialize>(mut self, name: N, value: V) -> Self {
+        let name = name.to_string();
+        let value = encode(&name, value);
+        self.named.insert(name, value);
+        self
+    }
+    /// Binds the next positional site, starting at `@1`.
+    pub fn push<V: Serialize>(mut self, value: V) -> Self {
+        let name = format!("@{}", self.positional.len() + 1);
+        self.positional.push(encode(&name, value));
+        self
+    }
+    fn to_datastore(&self, query_string: &str) -> Result<google_datastore1::GqlQuery, Error> {
+        let to_parameter = |x: &Result<google_datastore1::Value, String>| {
+            x.clone()
+                .map(|value| google_datastore1::GqlQueryParameter {
+                    value: Some(value),
+                    cursor: None,
+                })
+                .map_err(|msg| Error::Serialization {msg})
+        };
+        let named = self.named
+            .iter()
+            .map(|(k, v)| to_parameter(v).map(|v| (k.clone(), v)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        let positional = self.positional
+            .iter()
+            .map(to_parameter)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(google_datastore1::GqlQuery {
+            query_string: Some(query_string.to_owned()),
+            named_bindings: Some(named),
+            positional_bindings: Some(positional),
+            allow_literals: Some(true),
+        })
+    }
+}
+
+fn encode<V: Serialize>(name: &str, value: V) -> Result<google_datastore1::Value, String> {
+    convert::to_datastore_value(value)
+        .ok_or_else(|| format!("gql parameter {} not representable in datastore", name))
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - GQL
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    /// Runs a GQL query and decodes every result into `T`.
+    ///
+    /// ```no_run
+    /// # use ddb::*;
+    /// # #[derive(serde::Deserialize)]
+    /// # struct TodoItem {name: String}
+    /// # impl EntityKey for TodoItem {
+    /// #     fn entity_kind_key() -> String {String::from("TodoItem")}
+    /// #     fn entity_name_key(&self) -> String {self.name.clone()}
+    /// # }
+    /// let db = DatastoreClient::new().unwrap();
+    /// let items = db.gql::<TodoItem>(
+    ///     "SELECT * FROM TodoItem WHERE owner = @owner ORDER BY created DESC",
+    ///     GqlParams::new().bind("owner", "alice"),
+    /// );
+    /// ```
+    pub fn gql<T: DeserializeOwned + EntityKey>(&self, query_string: &str, params: GqlParams) -> Result<Vec<T>, Error> {
+        self.register_hooks::<T>();
+        query::decode_all(self.run_gql(query_string, params)?)
+    }
+    pub fn gql_entities(&self, query_string: &str, params: GqlParams) -> Result<Vec<Entity>, Error> {
+        self.run_gql(query_string, params)?
+            .into_iter()
+            .map(Entity::from_datastore)
+            .collect()
+    }
+    /// The first batch comes from the GQL query itself; the rest are fetched
+    /// by continuing the parsed query the server sends back. Without one,
+    /// more results are an error.
+    fn run_gql(&self, query_string: &str, params: GqlParams) -> Result<Vec<google_datastore1::Entity>, Error> {
+        let req = google_datastore1::RunQueryRequest {
+            gql_query: Some(params.to_datastore(query_string)?),
+            partition_id: self.partition_id(None),
+            ..google_datastore1::RunQueryRequest::default()
+        };
+        let mut rpc = Rpc::new(Operation::RunQuery);
+        rpc.namespace(req.partition_id.as_ref());
+        let response = self.instrument(rpc, |rpc| {
+            let result = self.call::<_, google_datastore1::RunQueryResponse>("runQuery", &req)?;
+            // THE KIND IS ONLY KNOWN ONCE THE SERVER HAS PARSED THE QUERY
+            let query = result.query.as_ref();
+            rpc.kind(query.and_then(|x| x.kind.as_ref()?.first()?.name.as_ref()));
+            rpc.keys(result.batch.as_ref().and_then(|x| x.entity_results.as_ref()).map(Vec::len).unwrap_or(0));
+            Ok(result)
+        })?;
+        if let Some(query) = &response.query {
+            self.log_index(query);
+        }
+        let batch = response.batch.unwrap_or_default();
+        let mut results = match response.query.clone() {
+            Some(query) => self.page_query(query, None, batch)?,
+            // NOTHING TO CONTINUE FROM, SO NEVER RETURN A PARTIAL RESULT
+            None if batch.more_results.as_deref() == Some("NOT_FINISHED") => {
+                return Err(Error::Deserialization {
+                    msg: String::from("gql results continue, but no parsed query came back to page with")
+                });
+            }
+            None => batch.entity_results
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|x| x.entity)
+                .collect(),
+        };
+        let is_projection = response.query
+            .as_ref()
+            .and_then(|x| x.projection.as_ref())
+            .is_some_and(|x| !x.is_empty());
+        if !is_projection {
+            results = self.upcast_all(results)?;
+            results = self.after_load_all(results)?;
+        }
+        Ok(results)
+    }
+}
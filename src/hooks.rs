@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::convert;
+use crate::db::{DatastoreClient, EntityKey, Error};
+use crate::entity::{self, Key};
+use crate::query;
+
+///////////////////////////////////////////////////////////////////////////////
+// HOOKS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Code that runs whenever a model is written, read or deleted, e.g. to
+/// validate or normalize it, or to fill in derived fields.
+///
+/// Hooks are turned on by `EntityKey::entity_hooks`, so every typed call
+/// runs them (`insert`, `get`, `delete`, queries, loaders, repositories,
+/// `BulkWriter`, `delete_all` and migrations). From then on the client and
+/// its clones also run them for untyped writes of the kind (`put_entity`,
+/// restores); `DatastoreClient::register_hooks` does that up front.
+///
+/// Every write goes through `before_save` and every delete through
+/// `before_delete`, including inside transactions. An `Err` aborts the
+/// whole commit, and comes back as `Error::Hook` (downcast it to get
+/// `Self::Error` back). Reads of full entities (not projections) go through
+/// `after_load`, after upcasting.
+///
+/// ```no_run
+/// # use ddb::*;
+/// # use serde::{Serialize, Deserialize};
+/// #[derive(Serialize, Deserialize)]
+/// struct Account {
+///     name: String,
+///     email: String,
+/// }
+/// impl EntityKey for Account {
+///     // ...
+/// #     fn entity_kind_key() -> String {String::from("Account")}
+/// #     fn entity_name_key(&self) -> String {self.name.clone()}
+///     fn entity_hooks() -> Option<Hooks> {
+///         Some(Hooks::of::<Self>())
+///     }
+/// }
+/// #[derive(Debug)]
+/// struct InvalidEmail(String);
+/// # impl std::fmt::Display for InvalidEmail {
+/// #     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {write!(f, "invalid email: {}", self.0)}
+/// # }
+/// # impl std::error::Error for InvalidEmail {}
+/// impl EntityHooks for Account {
+///     type Error = InvalidEmail;
+///     fn before_save(&mut self) -> Result<(), InvalidEmail> {
+///         self.email = self.email.trim().to_lowercase();
+///         if !self.email.contains('@') {
+///             return Err(InvalidEmail(self.email.clone()));
+///         }
+///         Ok(())
+///     }
+/// }
+/// let db = DatastoreClient::new().unwrap();
+/// let result = db.upsert(Account {name: String::from("acme"), email: String::from("nope")});
+/// if let Err(Error::Hook(e)) = result {
+///     assert!(e.downcast_ref::<InvalidEmail>().is_some());
+/// }
+/// ```
+pub trait EntityHooks: Sized {
+    type Error: std::error::Error + 'static;
+    /// Before the entity is inserted, upserted or updated.
+    fn before_save(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    /// After the entity is read, before it's returned.
+    fn after_load(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    /// Before the entity at `key` is deleted.
+    fn before_delete(_key: &Key) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// A model's `EntityHooks`, with the model type erased.
+#[derive(Clone, Copy)]
+pub struct Hooks {
+    before_save: fn(google_datastore1::Entity) -> Result<google_datastore1::Entity, Error>,
+    after_load: fn(google_datastore1::Entity) -> Result<google_datastore1::Entity, Error>,
+    before_delete: fn(&Key) -> Result<(), Error>,
+}
+
+impl Hooks {
+    pub fn of<T: EntityHooks + Serialize + DeserializeOwned>() -> Self {
+        Hooks {
+            before_save: before_save::<T>,
+            after_load: after_load::<T>,
+            before_delete: before_delete::<T>,
+        }
+    }
+}
+
+pub(crate) type HookRegistry = HashMap<String, Hooks>;
+
+fn before_save<T: EntityHooks + Serialize + DeserializeOwned>(entity: google_datastore1::Entity) -> Result<google_datastore1::Entity, Error> {
+    with_model::<T, _>(entity, T::before_save)
+}
+
+fn after_load<T: EntityHooks + Serialize + DeserializeOwned>(entity: google_datastore1::Entity) -> Result<google_datastore1::Entity, Error> {
+    with_model::<T, _>(entity, T::after_load)
+}
+
+fn before_delete<T: EntityHooks>(key: &Key) -> Result<(), Error> {
+    T::before_delete(key).map_err(|e| Error::Hook(Box::new(e)))
+}
+
+/// Runs `hook` on the entity decoded as `T`. Properties `T` has no field
+/// for are kept, and so are properties it left as they were (with their
+/// value type and index flag). A field the hook cleared (e.g. to a `None`
+/// skipped by `skip_serializing_if`) is removed.
+fn with_model<T, E>(entity: google_datastore1::Entity, hook: fn(&mut T) -> Result<(), E>) -> Result<google_datastore1::Entity, Error>
+where
+    T: Serialize + DeserializeOwned,
+    E: std::error::Error + 'static,
+{
+    let mut value: T = convert::from_datastore_entity(entity.clone()).ok_or(Error::Deserialization {
+        msg: String::from("conversion or parser error")
+    })?;
+    hook(&mut value).map_err(|e| Error::Hook(Box::new(e)))?;
+    let declared = query::struct_fields::<T>();
+    let mut properties = convert::to_datastore_value(value)
+        .and_then(|x| x.entity_value)
+        .and_then(|x| x.properties)
+        .ok_or(Error::Serialization {
+            msg: String::from("expecting struct/map like input")
+        })?;
+    for (name, old) in entity.properties.unwrap_or_default() {
+        match properties.get_mut(&name) {
+            Some(new) if same_value(&old, new) => *new = old,
+            Some(new) if entity::is_excluded(&old) => entity::exclude_from_indexes(new),
+            Some(_) => (),
+            None if declared.is_some_and(|x| x.contains(&name.as_str())) => (),
+            None => {
+                properties.insert(name, old);
+            }
+        }
+    }
+    Ok(google_datastore1::Entity {
+        key: entity.key,
+        properties: Some(properties),
+    })
+}
+
+fn same_value(old: &google_datastore1::Value, new: &google_datastore1::Value) -> bool {
+    let old = convert::from_datastore_value::<serde_json::Value>(old.clone());
+    old.is_some() && old == convert::from_datastore_value::<serde_json::Value>(new.clone())
+}
+
+fn entity_kind(entity: &google_datastore1::Entity) -> Option<&String> {
+    key_kind(entity.key.as_ref()?)
+}
+
+fn key_kind(key: &google_datastore1::Key) -> Option<&String> {
+    key.path.as_ref()?.last()?.kind.as_ref()
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - HOOKS
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    /// Runs `T`'s hooks (see `EntityKey::entity_hooks`) on untyped writes
+    /// of the kind by this client (and its clones) too, before `T` is used
+    /// in a typed call.
+    pub fn register_hooks<T: EntityKey>(&self) {
+        if let Some(hooks) = T::entity_hooks() {
+            self.add_hooks(T::entity_kind_key(), hooks);
+        }
+    }
+    pub(crate) fn add_hooks(&self, kind: String, hooks: Hooks) {
+        self.hooks.borrow_mut().insert(kind, hooks);
+    }
+    fn hooks_for(&self, kind: Option<&String>) -> Option<Hooks> {
+        self.hooks.borrow().get(kind?).copied()
+    }
+    /// Runs `before_save` and `before_delete` on mutations about to be
+    /// committed.
+    pub(crate) fn before_commit(&self, mutations: Vec<google_datastore1::Mutation>) -> Result<Vec<google_datastore1::Mutation>, Error> {
+        if self.hooks.borrow().is_empty() {
+            return Ok(mutations);
+        }
+        let mut results = Vec::with_capacity(mutations.len());
+        for mut mutation in mutations {
+            for slot in [&mut mutation.insert, &mut mutation.update, &mut mutation.upsert] {
+                if let Some(hooks) = slot.as_ref().and_then(|x| self.hooks_for(entity_kind(x))) {
+                    *slot = slot.take().map(hooks.before_save).transpose()?;
+                }
+            }
+            if let Some(key) = &mutation.delete {
+                if let Some(hooks) = self.hooks_for(key_kind(key)) {
+                    (hooks.before_delete)(&Key::from_datastore(key.clone()))?;
+                }
+            }
+            results.push(mutation);
+        }
+        Ok(results)
+    }
+    /// Runs `after_load` on entities read from the database (or the cache).
+    pub(crate) fn after_load_all(&self, entities: Vec<google_datastore1::Entity>) -> Result<Vec<google_datastore1::Entity>, Error> {
+        if self.hooks.borrow().is_empty() {
+            return Ok(entities);
+        }
+        entities
+            .into_iter()
+            .map(|x| match self.hooks_for(entity_kind(&x)) {
+                Some(hooks) => (hooks.after_load)(x),
+                None => Ok(x),
+            })
+            .collect()
+    }
+}
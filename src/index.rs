@@ -0,0 +1,340 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use serde::{Deserialize, Deserializer};
+use crate::db::{DatastoreClient, Error};
+use crate::query::Query;
+
+///////////////////////////////////////////////////////////////////////////////
+// INDEX
+///////////////////////////////////////////////////////////////////////////////
+
+/// A composite index, as declared in `index.yaml`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub struct Index {
+    pub kind: String,
+    #[serde(default, deserialize_with = "yes_no")]
+    pub ancestor: bool,
+    #[serde(default)]
+    pub properties: Vec<IndexProperty>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub struct IndexProperty {
+    pub name: String,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "- kind: {}", self.kind)?;
+        if self.ancestor {
+            writeln!(f, "  ancestor: yes")?;
+        }
+        writeln!(f, "  properties:")?;
+        for property in &self.properties {
+            writeln!(f, "  - name: {}", property.name)?;
+            if property.direction == Direction::Desc {
+                writeln!(f, "    direction: desc")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `ancestor:` is `yes`/`no` in the docs, but YAML 1.2 parsers only know
+/// `true`/`false`.
+fn yes_no<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum YesNo {
+        Bool(bool),
+        Str(String),
+    }
+    match YesNo::deserialize(deserializer)? {
+        YesNo::Bool(x) => Ok(x),
+        YesNo::Str(x) => match x.to_lowercase().as_str() {
+            "yes" | "true" => Ok(true),
+            "no" | "false" => Ok(false),
+            _ => Err(serde::de::Error::custom(format!("invalid ancestor value: {}", x))),
+        },
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// REQUIREMENTS
+///////////////////////////////////////////////////////////////////////////////
+
+/// An index a query needs. The first `equality` properties are matched by
+/// equality filters, so any index listing them in any order (and direction)
+/// works.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Requirement {
+    index: Index,
+    equality: usize,
+}
+
+impl Requirement {
+    fn is_supported_by(&self, index: &Index) -> bool {
+        let required = &self.index;
+        if required.kind != index.kind
+            || required.ancestor != index.ancestor
+            || required.properties.len() != index.properties.len() {
+            return false;
+        }
+        let (required_eq, required_rest) = required.properties.split_at(self.equality);
+        let (eq, rest) = index.properties.split_at(self.equality);
+        let names = |xs: &[IndexProperty]| xs
+            .iter()
+            .map(|x| x.name.clone())
+            .collect::<BTreeSet<_>>();
+        names(required_eq) == names(eq) && required_rest == rest
+    }
+}
+
+/// The composite indexes a query needs, one per `OR` branch. Queries served
+/// by the built-in single property indexes need none.
+fn requirements(query: &google_datastore1::Query) -> Vec<Requirement> {
+    let kind = match query.kind.as_ref().and_then(|x| x.first()).and_then(|x| x.name.clone()) {
+        Some(x) => x,
+        // KINDLESS QUERIES ONLY USE BUILT-IN INDEXES
+        None => return Vec::new(),
+    };
+    let branches = match &query.filter {
+        Some(filter) => disjunctive_branches(filter),
+        None => vec![Vec::new()],
+    };
+    let mut orders = Vec::new();
+    for order in query.order.iter().flatten() {
+        let name = order.property.as_ref().and_then(|x| x.name.clone()).unwrap_or_default();
+        let direction = match order.direction.as_deref() {
+            Some("DESCENDING") => Direction::Desc,
+            _ => Direction::Asc,
+        };
+        // ASCENDING KEY ORDER IS IMPLICIT IN EVERY INDEX
+        if name == "__key__" && direction == Direction::Asc {
+            continue;
+        }
+        orders.push(IndexProperty {name, direction});
+    }
+    let projection = query.projection
+        .iter()
+        .flatten()
+        .filter_map(|x| x.property.as_ref()?.name.clone())
+        .filter(|x| x != "__key__")
+        .collect::<Vec<_>>();
+    branches
+        .into_iter()
+        .filter_map(|branch| requirement(&kind, &branch, &orders, &projection))
+        .collect()
+}
+
+fn requirement(
+    kind: &str,
+    filters: &[(String, String)],
+    orders: &[IndexProperty],
+    projection: &[String],
+) -> Option<Requirement> {
+    let mut ancestor = false;
+    let mut equality = BTreeSet::new();
+    let mut inequality = Vec::new();
+    for (name, op) in filters {
+        match op.as_str() {
+            "HAS_ANCESTOR" => ancestor = true,
+            // KEY FILTERS ARE SERVED BY THE KEY ITSELF
+            _ if name == "__key__" => {}
+            "EQUAL" | "IN" => {
+                equality.insert(name.clone());
+            }
+            _ => {
+                if !inequality.contains(name) {
+                    inequality.push(name.clone());
+                }
+            }
+        }
+    }
+    // INEQUALITY PROPERTIES ARE SORTED FIRST, THEN THE REQUESTED ORDER
+    let mut rest = Vec::<IndexProperty>::new();
+    for name in inequality {
+        if !orders.iter().any(|x| x.name == name) {
+            rest.push(IndexProperty {name, direction: Direction::Asc});
+        }
+    }
+    rest.extend(orders.iter().cloned());
+    for name in projection {
+        if !equality.contains(name) && !rest.iter().any(|x| &x.name == name) {
+            rest.push(IndexProperty {name: name.clone(), direction: Direction::Asc});
+        }
+    }
+    if rest.is_empty() {
+        // EQUALITY (AND ANCESTOR) FILTERS ONLY, MERGED FROM BUILT-IN INDEXES
+        return None;
+    }
+    // DESCENDING KEY ORDER ALWAYS NEEDS A COMPOSITE INDEX
+    let uses_key_desc = rest.iter().any(|x| x.name == "__key__");
+    if !ancestor && equality.is_empty() && rest.len() == 1 && !uses_key_desc {
+        return None;
+    }
+    let mut properties = equality
+        .iter()
+        .map(|name| IndexProperty {name: name.clone(), direction: Direction::Asc})
+        .collect::<Vec<_>>();
+    let equality = properties.len();
+    properties.extend(rest);
+    Some(Requirement {
+        index: Index {kind: String::from(kind), ancestor, properties},
+        equality,
+    })
+}
+
+/// The filter as an `OR` of `AND`s of `(property, op)`.
+fn disjunctive_branches(filter: &google_datastore1::Filter) -> Vec<Vec<(String, String)>> {
+    if let Some(filter) = &filter.property_filter {
+        let name = filter.property.as_ref().and_then(|x| x.name.clone()).unwrap_or_default();
+        return vec![vec![(name, filter.op.clone().unwrap_or_default())]];
+    }
+    let composite = match &filter.composite_filter {
+        Some(x) => x,
+        None => return vec![Vec::new()],
+    };
+    let children = composite.filters
+        .iter()
+        .flatten()
+        .map(disjunctive_branches);
+    if composite.op.as_deref() == Some("OR") {
+        return children.flatten().collect();
+    }
+    children.fold(vec![Vec::new()], |acc, child| {
+        let mut result = Vec::new();
+        for left in &acc {
+            for right in &child {
+                result.push(left.iter().chain(right).cloned().collect());
+            }
+        }
+        result
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// MANIFEST
+///////////////////////////////////////////////////////////////////////////////
+
+/// A set of composite indexes: either the ones some queries need (see
+/// `add` and `DatastoreClient::record_indexes`) or the ones an `index.yaml`
+/// declares.
+///
+/// ```
+/// # use ddb::*;
+/// # #[derive(serde::Serialize, serde::Deserialize)]
+/// # struct TodoItem {name: String, done: bool, priority: i64}
+/// # impl EntityKey for TodoItem {
+/// #     fn entity_kind_key() -> String {String::from("TodoItem")}
+/// #     fn entity_name_key(&self) -> String {self.name.clone()}
+/// # }
+/// # fn check(db: &DatastoreClient) -> Result<(), Error> {
+/// let mut required = IndexManifest::new();
+/// required.add(&db.query::<TodoItem>()
+///     .filter(Filter::eq("done", false))
+///     .order_by_desc("priority"))?;
+/// let deployed = IndexManifest::from_yaml(&std::fs::read_to_string("index.yaml").unwrap()).unwrap();
+/// for index in deployed.missing(&required) {
+///     eprintln!("missing index:\n{}", index);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct IndexManifest {
+    requirements: BTreeSet<Requirement>,
+}
+
+impl IndexManifest {
+    pub fn new() -> Self {
+        IndexManifest::default()
+    }
+    /// Adds the indexes the query needs, if any.
+    pub fn add<T>(&mut self, query: &Query<'_, T>) -> Result<(), Error> {
+        self.add_raw(&query.to_datastore()?);
+        Ok(())
+    }
+    /// Same as `add`, for queries built directly against the hub.
+    pub fn add_raw(&mut self, query: &google_datastore1::Query) {
+        self.requirements.extend(requirements(query));
+    }
+    pub fn indexes(&self) -> Vec<Index> {
+        self.requirements
+            .iter()
+            .map(|x| x.index.clone())
+            .collect()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+    /// The indexes in `required` that nothing in `self` supports.
+    pub fn missing(&self, required: &IndexManifest) -> Vec<Index> {
+        required.requirements
+            .iter()
+            .filter(|x| !self.requirements.iter().any(|have| x.is_supported_by(&have.index)))
+            .map(|x| x.index.clone())
+            .collect()
+    }
+    pub fn from_yaml(source: &str) -> Result<Self, Error> {
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(default)]
+            indexes: Option<Vec<Index>>,
+        }
+        let file: File = serde_yaml::from_str(source)
+            .map_err(|e| Error::Deserialization {msg: format!("invalid index.yaml: {}", e)})?;
+        let requirements = file.indexes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|index| Requirement {index, equality: 0})
+            .collect();
+        Ok(IndexManifest {requirements})
+    }
+    /// In the `index.yaml` format `gcloud datastore indexes create` expects.
+    pub fn to_yaml(&self) -> String {
+        let mut result = String::from("indexes:\n");
+        for index in self.indexes() {
+            result.push('\n');
+            result.push_str(&index.to_string());
+        }
+        result
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - INDEX RECORDING
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    /// Starts recording the composite indexes of every query this client
+    /// (and its clones) runs, including projections and aggregations.
+    pub fn record_indexes(&self) {
+        let mut log = self.index_log.borrow_mut();
+        if log.is_none() {
+            *log = Some(IndexManifest::new());
+        }
+    }
+    /// What has been recorded since `record_indexes`.
+    pub fn recorded_indexes(&self) -> IndexManifest {
+        self.index_log.borrow().clone().unwrap_or_default()
+    }
+    pub(crate) fn log_index(&self, query: &google_datastore1::Query) {
+        if let Some(log) = self.index_log.borrow_mut().as_mut() {
+            log.add_raw(query);
+        }
+    }
+}
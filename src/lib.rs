@@ -1,4 +1,5 @@
 #![allow(unused)]
+#![allow(clippy::result_large_err)]
 //! ```
 //! use serde::{Serialize, Deserialize};
 //! 
@@ -30,6 +31,46 @@
 mod convert;
 mod db;
 mod auth;
+mod tracked;
+mod entity;
+pub mod codec;
+mod enums;
+mod query;
+mod aggregate;
+mod gql;
+mod fields;
+mod index;
+mod upcast;
+mod hooks;
+mod auto_timestamps;
+mod metadata;
+mod ndjson;
+pub mod migrate;
+pub mod backup;
+mod timestamp;
+mod cache;
+mod loader;
+pub mod telemetry;
+pub mod transport;
+mod ratelimit;
+pub mod bulk;
+mod delete;
+mod repository;
 
 pub use db::*;
+pub use tracked::{Tracked, ExtraProperties};
+pub use entity::{Entity, Key, KeyId, PathElement, TypedKey, Value};
+pub use query::{Filter, Query};
+pub use gql::GqlParams;
+pub use fields::{Field, HasFields, field_path};
+pub use ddb_derive::Fields;
+pub use index::{Index, IndexProperty, Direction, IndexManifest};
+pub use upcast::{Upcast, SCHEMA_VERSION_PROPERTY};
+pub use hooks::{EntityHooks, Hooks};
+pub use auto_timestamps::Timestamps;
+pub use cache::{Cache, LruCache, CacheStats};
+pub use loader::{Loader, Pending};
+pub use ratelimit::{RateLimiter, Schedule, Access, MAX_WAIT};
+pub use delete::{Deletion, DeleteReport};
+pub use repository::Repository;
 
@@ -0,0 +1,150 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use serde::de::DeserializeOwned;
+use crate::convert;
+use crate::db::{DatastoreClient, EntityKey, Error};
+use crate::entity::Key;
+
+///////////////////////////////////////////////////////////////////////////////
+// LOADER
+///////////////////////////////////////////////////////////////////////////////
+
+/// Coalesces gets into batched lookups, like a GraphQL dataloader.
+///
+/// `load` only queues a key. The first `Pending::get` looks up every queued
+/// key in one request (duplicates once), and later `get`s are served from
+/// its results. Results are kept for the loader's lifetime, so use one
+/// loader per request.
+///
+/// ```no_run
+/// # use ddb::*;
+/// # #[derive(serde::Deserialize)]
+/// # struct User {name: String}
+/// # impl EntityKey for User {
+/// #     fn entity_kind_key() -> String {String::from("User")}
+/// #     fn entity_name_key(&self) -> String {self.name.clone()}
+/// # }
+/// let db = DatastoreClient::new().unwrap();
+/// let users = db.loader::<User>();
+/// let author = users.load("alice");
+/// let reviewers = vec![users.load("bob"), users.load("alice")];
+/// // ONE LOOKUP FOR ALICE AND BOB
+/// let author = author.get().unwrap();
+/// for reviewer in reviewers {
+///     println!("{}", reviewer.get().unwrap().name);
+/// }
+/// ```
+pub struct Loader<T> {
+    shared: Rc<Shared>,
+    marker: PhantomData<fn() -> T>,
+}
+
+struct Shared {
+    client: DatastoreClient,
+    kind: String,
+    queued: RefCell<Vec<Key>>,
+    /// `None` for keys that don't exist.
+    loaded: RefCell<HashMap<Key, Option<google_datastore1::Entity>>>,
+}
+
+impl<T: DeserializeOwned> Loader<T> {
+    pub fn load<K: ToString>(&self, name_key: K) -> Pending<T> {
+        self.load_key(Key::name(&self.shared.kind, name_key))
+    }
+    /// Loads by full key, e.g. for entities with ancestors or numeric ids.
+    pub fn load_key(&self, key: Key) -> Pending<T> {
+        // DUPLICATES ARE LOOKED UP ONCE BY `lookup_entities`
+        if !self.shared.loaded.borrow().contains_key(&key) {
+            self.shared.queued.borrow_mut().push(key.clone());
+        }
+        Pending {
+            key,
+            shared: self.shared.clone(),
+            marker: PhantomData,
+        }
+    }
+    /// Looks up every queued key now, without waiting for a `get`.
+    pub fn dispatch(&self) -> Result<(), Error> {
+        self.shared.dispatch()
+    }
+    /// Forgets loaded entities, so they're looked up again.
+    pub fn clear(&self) {
+        self.shared.loaded.borrow_mut().clear();
+    }
+}
+
+impl Shared {
+    fn dispatch(&self) -> Result<(), Error> {
+        let keys = std::mem::take(&mut *self.queued.borrow_mut());
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let request = keys.iter().map(Key::to_datastore).collect();
+        match self.client.lookup_entities(request) {
+            Ok(entities) => {
+                self.loaded.borrow_mut().extend(keys.into_iter().zip(entities));
+                Ok(())
+            }
+            Err(e) => {
+                // LEFT FOR THE NEXT GET TO TRY AGAIN
+                self.queued.borrow_mut().extend(keys);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A queued get, see `Loader`.
+pub struct Pending<T> {
+    key: Key,
+    shared: Rc<Shared>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Pending<T> {
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+    /// Like `DatastoreClient::get`, `Error::NoPayload` if the entity
+    /// doesn't exist.
+    pub fn get(self) -> Result<T, Error> {
+        if !self.shared.loaded.borrow().contains_key(&self.key) {
+            self.shared.dispatch()?;
+        }
+        let payload = self.shared.loaded
+            .borrow()
+            .get(&self.key)
+            .cloned()
+            .flatten()
+            .ok_or(Error::NoPayload)?;
+        convert::from_datastore_entity(payload)
+            .ok_or_else(|| {
+                Error::Deserialization {
+                    msg: String::from("conversion or parser error")
+                }
+            })
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - LOADERS
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    /// A new `Loader` for `T` entities.
+    pub fn loader<T: DeserializeOwned + EntityKey>(&self) -> Loader<T> {
+        self.register_hooks::<T>();
+        Loader {
+            shared: Rc::new(Shared {
+                client: self.clone(),
+                kind: T::entity_kind_key(),
+                queued: RefCell::new(Vec::new()),
+                loaded: RefCell::new(HashMap::new()),
+            }),
+            marker: PhantomData,
+        }
+    }
+}
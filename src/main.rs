@@ -1,81 +1,439 @@
-#![allow(unused)]
+#![allow(clippy::result_large_err)]
 
-mod convert;
-mod db;
-mod auth;
+use std::collections::BTreeSet;
+use std::io::Read;
+use ddb::{Auth, DatastoreClient, Entity, Filter, GqlParams, Key, KeyId};
+use ddb::backup::ExportReader;
 
-use serde::{Serialize, Deserialize};
-pub use db::*;
+static USAGE: &str = "\
+usage: ddb [options] <command> [args]
+
+commands:
+    get <kind> <name>              print an entity
+    put <kind> <name> [json]       write an entity from a JSON object (or stdin)
+    delete <kind> <name>           delete an entity
+    delete-all <kind>              delete every entity of a kind
+    delete-tree <kind> <name>      delete an entity and all its descendants
+    query <kind> [query options]   print matching entities
+    gql <query> [--bind name=value]...
+    kinds                          list kinds in the namespace
+    namespaces                     list namespaces
+    count <kind> [--where ...]...  count matching entities
+    export <kind> [file]           write entities as NDJSON (to stdout by default)
+    import [file]                  upsert entities from NDJSON (from stdin by default)
+    restore <path>                 upsert entities from a managed export (its
+                                   directory, a metadata or an output-N file)
+
+options:
+    --project <id>                 project to use instead of the credentials' one
+    --namespace <namespace>        namespace to use instead of the default one
+    --emulator[=host]              use the emulator at host (default
+                                   $DATASTORE_EMULATOR_HOST or localhost:8081)
+    --output json|table            output format (default json)
+    --id                           <name> is a numeric id
+    --dry-run                      only print what delete-all/delete-tree
+                                   would delete
+
+query options:
+    --where '<property> <op> <value>'   op is one of = != < <= > >= in, not in
+    --order <property>                  prefix with - for descending
+    --limit <n>
+
+values are parsed as JSON, falling back to a plain string.";
 
 ///////////////////////////////////////////////////////////////////////////////
-// UTILS
+// ARGUMENTS
 ///////////////////////////////////////////////////////////////////////////////
 
-pub fn random_ascii_string(length: usize) -> String {
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
-    std::iter::repeat(())
-        .map(|()| rng.sample(rand::distributions::Alphanumeric))
-        .take(length)
-        .collect::<String>()
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    Json,
+    Table,
 }
 
-pub fn random_id_string() -> String {
-    random_ascii_string(7)
+#[derive(Debug, Default)]
+struct Args {
+    project: Option<String>,
+    namespace: Option<String>,
+    emulator: Option<String>,
+    output: Option<Output>,
+    numeric_id: bool,
+    filters: Vec<String>,
+    orders: Vec<String>,
+    limit: Option<i32>,
+    bindings: Vec<String>,
+    dry_run: bool,
+    positional: Vec<String>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut result = Args::default();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("missing value for {}", name))
+        };
+        match arg.as_str() {
+            "--project" => result.project = Some(value(&arg)?),
+            "--namespace" => result.namespace = Some(value(&arg)?),
+            "--emulator" => result.emulator = Some(emulator_host()),
+            "--output" => {
+                result.output = Some(match value(&arg)?.as_str() {
+                    "json" => Output::Json,
+                    "table" => Output::Table,
+                    x => return Err(format!("unknown output format: {}", x)),
+                });
+            }
+            "--id" => result.numeric_id = true,
+            "--where" => result.filters.push(value(&arg)?),
+            "--order" => result.orders.push(value(&arg)?),
+            "--limit" => {
+                let limit = value(&arg)?;
+                result.limit = Some(limit.parse().map_err(|_| format!("invalid limit: {}", limit))?);
+            }
+            "--bind" => result.bindings.push(value(&arg)?),
+            "--dry-run" => result.dry_run = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with("--emulator=") => {
+                result.emulator = Some(arg.trim_start_matches("--emulator=").to_owned());
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option: {}", arg)),
+            _ => result.positional.push(arg),
+        }
+    }
+    Ok(result)
+}
+
+fn emulator_host() -> String {
+    std::env::var("DATASTORE_EMULATOR_HOST").unwrap_or_else(|_| String::from("localhost:8081"))
+}
+
+/// JSON if it parses, otherwise a plain string.
+fn parse_value(source: &str) -> serde_json::Value {
+    serde_json::from_str(source).unwrap_or_else(|_| serde_json::Value::String(source.to_owned()))
+}
+
+/// `<property> <op> <value>`, e.g. `done = false` or `priority>=2`.
+fn parse_filter(source: &str) -> Result<Filter, String> {
+    let invalid = || format!("invalid filter: {}", source);
+    let ops = ["!=", "<=", ">=", "=", "<", ">", " in ", " not in "];
+    let (index, op) = ops
+        .iter()
+        .filter_map(|op| source.find(op).map(|i| (i, *op)))
+        // THE EARLIEST MATCH, PREFERRING THE LONGER OPERATOR AT THE SAME SPOT
+        .min_by_key(|(i, op)| (*i, std::cmp::Reverse(op.len())))
+        .ok_or_else(invalid)?;
+    let name = source[..index].trim();
+    let value = parse_value(source[index + op.len()..].trim());
+    if name.is_empty() {
+        return Err(invalid());
+    }
+    let values = || match &value {
+        serde_json::Value::Array(xs) => Ok(xs.clone()),
+        _ => Err(format!("expecting a JSON array in filter: {}", source)),
+    };
+    let filter = match op.trim() {
+        "=" => Filter::eq(name, value),
+        "!=" => Filter::ne(name, value),
+        "<" => Filter::lt(name, value),
+        "<=" => Filter::le(name, value),
+        ">" => Filter::gt(name, value),
+        ">=" => Filter::ge(name, value),
+        "in" => Filter::is_in(name, values()?),
+        _ => Filter::not_in(name, values()?),
+    };
+    Ok(filter)
 }
 
 
 ///////////////////////////////////////////////////////////////////////////////
-// MODEL
+// OUTPUT
 ///////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct TodoItem {
-    pub name: String,
-    pub title: Option<String>,
+fn format_key(key: &Key) -> String {
+    key.path
+        .iter()
+        .map(|x| match &x.id {
+            Some(KeyId::Name(name)) => format!("{}:{}", x.kind, name),
+            Some(KeyId::Id(id)) => format!("{}:{}", x.kind, id),
+            None => x.kind.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn entity_to_json(entity: &Entity) -> serde_json::Value {
+    let mut json = entity.to_json();
+    if let (Some(key), Some(object)) = (&entity.key, json.as_object_mut()) {
+        object.insert(String::from("__key__"), serde_json::Value::String(format_key(key)));
+    }
+    json
+}
+
+fn print_json(json: &serde_json::Value) {
+    println!("{}", serde_json::to_string_pretty(json).expect("json to string"));
 }
 
-impl EntityKey for TodoItem {
-    fn entity_kind_key() -> String {
-        String::from("TodoItem")
+fn print_table(columns: &[String], rows: &[Vec<String>]) {
+    let widths = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain(std::iter::once(column.chars().count()))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+    let print_row = |row: &[String]| {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    print_row(columns);
+    let rule = widths.iter().map(|x| "-".repeat(*x)).collect::<Vec<_>>();
+    print_row(&rule);
+    for row in rows {
+        print_row(row);
+    }
+}
+
+fn print_entities(entities: &[Entity], output: Output) {
+    match output {
+        Output::Json => {
+            let json = entities.iter().map(entity_to_json).collect();
+            print_json(&serde_json::Value::Array(json));
+        }
+        Output::Table => {
+            let properties = entities
+                .iter()
+                .flat_map(|x| x.properties.keys().cloned())
+                .collect::<BTreeSet<_>>();
+            let mut columns = vec![String::from("__key__")];
+            columns.extend(properties.iter().cloned());
+            let rows = entities
+                .iter()
+                .map(|entity| {
+                    let mut row = vec![entity.key.as_ref().map(format_key).unwrap_or_default()];
+                    row.extend(properties.iter().map(|name| match entity.get(name).map(|x| x.to_json()) {
+                        Some(serde_json::Value::String(x)) => x,
+                        Some(x) => x.to_string(),
+                        None => String::new(),
+                    }));
+                    row
+                })
+                .collect::<Vec<_>>();
+            print_table(&columns, &rows);
+        }
     }
-    fn entity_name_key(&self) -> String {
-        self.name.clone()
+}
+
+fn print_names(names: &[String], column: &str, output: Output) {
+    match output {
+        Output::Json => print_json(&serde_json::json!(names)),
+        Output::Table => {
+            let rows = names.iter().map(|x| vec![x.clone()]).collect::<Vec<_>>();
+            print_table(&[String::from(column)], &rows);
+        }
     }
 }
 
 
 ///////////////////////////////////////////////////////////////////////////////
-// MAIN
+// COMMANDS
 ///////////////////////////////////////////////////////////////////////////////
 
+fn connect(args: &Args) -> Result<DatastoreClient, String> {
+    let client = match &args.emulator {
+        Some(host) => {
+            let project = args.project
+                .clone()
+                .or_else(|| std::env::var("DATASTORE_PROJECT_ID").ok())
+                .unwrap_or_else(|| String::from("dev"));
+            DatastoreClient::new_emulator(host, project).map_err(|e| e.to_string())?
+        }
+        None => {
+            let mut auth = Auth::new()
+                .map_err(|e| format!("could not find auth credentials: {}", e))?;
+            if let Some(project) = &args.project {
+                auth = auth.with_project_id(project);
+            }
+            DatastoreClient::new_with_auth(auth).map_err(|e| e.to_string())?
+        }
+    };
+    Ok(match &args.namespace {
+        Some(x) => client.with_namespace(x),
+        None => client,
+    })
+}
 
-fn init_db() -> DatastoreClient {
-    DatastoreClient::new()
-        .expect("DatastoreClient init failed (probably could not find auth credentials)")
+fn key_arg(args: &Args) -> Result<Key, String> {
+    match &args.positional[1..] {
+        [kind, name, ..] if args.numeric_id => {
+            let id = name.parse().map_err(|_| format!("invalid id: {}", name))?;
+            Ok(Key::id(kind, id))
+        }
+        [kind, name, ..] => Ok(Key::name(kind, name)),
+        _ => Err(String::from("expecting <kind> <name>")),
+    }
 }
 
-fn insert_new_todo(db: &DatastoreClient) -> String {
-    let item = TodoItem {
-        name: random_id_string(),
-        // title: Some(String::from("lorem ipsum"))
-        title: None,
-    };
-    println!("new todo: {:#?}", item);
-    let result = db.upsert(item.clone());
-    println!("result [insert]: {:#?}", result);
-    item.name
+fn kind_arg(args: &Args) -> Result<&str, String> {
+    args.positional
+        .get(1)
+        .map(|x| x.as_str())
+        .ok_or_else(|| String::from("expecting <kind>"))
 }
 
-fn get_todo(db: &DatastoreClient, name: &str) {
-    let result = db.get::<TodoItem, _>(name);
-    //                    ^^^^^^^^ The type of the thing we are retrieving
-    println!("result [get]: {:#?}", result);
+fn filter_arg(args: &Args) -> Result<Option<Filter>, String> {
+    let filters = args.filters
+        .iter()
+        .map(|x| parse_filter(x))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(filters.into_iter().reduce(Filter::and))
 }
 
-fn main() {
-    let db = init_db();
-    let key = insert_new_todo(&db);
-    get_todo(&db, &key);
+fn run(args: Args) -> Result<(), String> {
+    let output = args.output.unwrap_or(Output::Json);
+    let command = args.positional
+        .first()
+        .cloned()
+        .ok_or_else(String::new)?;
+    let db = connect(&args)?;
+    let db_error = |e: ddb::Error| e.to_string();
+    match command.as_str() {
+        "get" => {
+            let entity = db.get_entity(&key_arg(&args)?).map_err(db_error)?;
+            match output {
+                Output::Json => print_json(&entity_to_json(&entity)),
+                Output::Table => print_entities(&[entity], output),
+            }
+        }
+        "put" => {
+            let key = key_arg(&args)?;
+            let source = match args.positional.get(3) {
+                Some(x) => x.clone(),
+                None => {
+                    let mut source = String::new();
+                    std::io::stdin()
+                        .read_to_string(&mut source)
+                        .map_err(|e| format!("could not read stdin: {}", e))?;
+                    source
+                }
+            };
+            let json: serde_json::Value = serde_json::from_str(&source)
+                .map_err(|e| format!("invalid JSON: {}", e))?;
+            let entity = Entity::from_json(key, json).map_err(db_error)?;
+            db.put_entity(entity).map_err(db_error)?;
+        }
+        "delete" => {
+            db.delete_entity(&key_arg(&args)?).map_err(db_error)?;
+        }
+        "delete-all" | "delete-tree" => {
+            let mut deletion = match command.as_str() {
+                "delete-all" => db.delete_kind(kind_arg(&args)?),
+                _ => db.delete_subtree(&key_arg(&args)?),
+            };
+            if args.dry_run {
+                deletion = deletion.dry_run();
+            }
+            let report = deletion.run().map_err(db_error)?;
+            if args.dry_run {
+                for key in &report.keys {
+                    println!("{}", format_key(key));
+                }
+            }
+            let verb = if args.dry_run {"would delete"} else {"deleted"};
+            eprintln!("{} {} entities", verb, report.count());
+        }
+        "query" => {
+            let mut query = db.query_entities(kind_arg(&args)?);
+            if let Some(filter) = filter_arg(&args)? {
+                query = query.filter(filter);
+            }
+            for order in &args.orders {
+                query = match order.strip_prefix('-') {
+                    Some(name) => query.order_by_desc(name),
+                    None => query.order_by(order),
+                };
+            }
+            if let Some(limit) = args.limit {
+                query = query.limit(limit);
+            }
+            print_entities(&query.entities().map_err(db_error)?, output);
+        }
+        "gql" => {
+            let query_string = args.positional
+                .get(1)
+                .ok_or_else(|| String::from("expecting <query>"))?;
+            let mut params = GqlParams::new();
+            for binding in &args.bindings {
+                let (name, value) = binding
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid binding: {}", binding))?;
+                params = params.bind(name, parse_value(value));
+            }
+            print_entities(&db.gql_entities(query_string, params).map_err(db_error)?, output);
+        }
+        "kinds" => print_names(&db.kinds().map_err(db_error)?, "kind", output),
+        "namespaces" => print_names(&db.namespaces().map_err(db_error)?, "namespace", output),
+        "count" => {
+            let count = db.count_kind(kind_arg(&args)?, filter_arg(&args)?).map_err(db_error)?;
+            println!("{}", count);
+        }
+        "export" => {
+            let kind = kind_arg(&args)?;
+            let count = match args.positional.get(2) {
+                Some(path) => {
+                    let file = std::fs::File::create(path)
+                        .map_err(|e| format!("could not create {}: {}", path, e))?;
+                    db.export_kind(kind, std::io::BufWriter::new(file))
+                }
+                None => db.export_kind(kind, std::io::stdout().lock()),
+            };
+            eprintln!("exported {} entities", count.map_err(db_error)?);
+        }
+        "import" => {
+            let count = match args.positional.get(1) {
+                Some(path) => {
+                    let file = std::fs::File::open(path)
+                        .map_err(|e| format!("could not open {}: {}", path, e))?;
+                    db.import(file)
+                }
+                None => db.import(std::io::stdin().lock()),
+            };
+            eprintln!("imported {} entities", count.map_err(db_error)?);
+        }
+        "restore" => {
+            let path = args.positional.get(1).ok_or_else(|| String::from("expecting <path>"))?;
+            let entities = ExportReader::open(path).map_err(db_error)?;
+            eprintln!("restored {} entities", db.restore(entities).map_err(db_error)?);
+        }
+        _ => return Err(format!("unknown command: {}", command)),
+    }
+    Ok(())
 }
 
+
+///////////////////////////////////////////////////////////////////////////////
+// MAIN
+///////////////////////////////////////////////////////////////////////////////
+
+fn main() {
+    let result = parse_args(std::env::args().skip(1)).and_then(run);
+    match result {
+        Ok(()) => {}
+        Err(e) if e.is_empty() => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
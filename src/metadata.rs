@@ -0,0 +1,35 @@
+use crate::db::{DatastoreClient, Error};
+use crate::entity::{Key, KeyId};
+use crate::query;
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - METADATA
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    /// Kinds with at least one entity in the client's namespace, including
+    /// the `__`-prefixed kinds `ddb` itself uses.
+    pub fn kinds(&self) -> Result<Vec<String>, Error> {
+        self.metadata_names("__kind__", None)
+    }
+    /// Namespaces with at least one entity. The default namespace is `""`.
+    pub fn namespaces(&self) -> Result<Vec<String>, Error> {
+        // THE NAMESPACE LIST LIVES IN THE DEFAULT NAMESPACE
+        self.metadata_names("__namespace__", Some(String::new()))
+    }
+    fn metadata_names(&self, kind: &str, namespace: Option<String>) -> Result<Vec<String>, Error> {
+        let mut query = query::kind_query(String::from(kind), None)?;
+        query.projection = Some(query::projection(&["__key__"]));
+        let names = self.run_query(query, namespace)?
+            .into_iter()
+            .filter_map(|x| x.key)
+            .map(Key::from_datastore)
+            .filter_map(|x| match x.path.last()?.id.clone()? {
+                KeyId::Name(name) => Some(name),
+                // THE DEFAULT NAMESPACE HAS ID 1
+                KeyId::Id(_) => Some(String::new()),
+            })
+            .collect();
+        Ok(names)
+    }
+}
@@ -0,0 +1,419 @@
+//! Versioned, resumable migrations over the entities of a kind.
+//!
+//! Each migration pages through its kind in key order and applies a
+//! transform to every entity. Progress (and completion) is recorded in the
+//! `__ddb_migrations` kind after every page, so an interrupted run resumes
+//! from the last recorded page. A page may be applied again after an
+//! interruption, so transforms should be idempotent.
+//!
+//! ```no_run
+//! use ddb::{DatastoreClient, Value};
+//! use ddb::migrate::{Change, Migration, Migrator};
+//!
+//! let db = DatastoreClient::new().unwrap();
+//! Migrator::new(&db)
+//!     .migration(Migration::new(1, "rename title to summary", "TodoItem", |mut entity| {
+//!         match entity.remove("title") {
+//!             Some(title) => {
+//!                 entity.set("summary", title);
+//!                 Ok(Change::Put(entity))
+//!             }
+//!             None => Ok(Change::Skip),
+//!         }
+//!     }))
+//!     .on_progress(|p| eprintln!("{} {}: {} processed", p.version, p.name, p.processed))
+//!     .run()
+//!     .unwrap();
+//! ```
+use std::time::Duration;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::db::{DatastoreClient, EntityKey, Error, COMMIT_LIMIT};
+use crate::hooks::Hooks;
+use crate::entity::{Entity, Key, Value};
+use crate::query;
+
+/// Where completed and in-progress migrations are recorded.
+pub static MIGRATIONS_KIND: &str = "__ddb_migrations";
+
+///////////////////////////////////////////////////////////////////////////////
+// MIGRATION
+///////////////////////////////////////////////////////////////////////////////
+
+/// What a transform does with an entity.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Leave it as it is.
+    Skip,
+    /// Write it back (the key may differ from the original).
+    Put(Entity),
+    Delete,
+}
+
+type Transform = Box<dyn Fn(Entity) -> Result<Change, Error>>;
+
+pub struct Migration {
+    pub version: u32,
+    pub name: String,
+    pub kind: String,
+    pub namespace: Option<String>,
+    transform: Transform,
+    /// The target model's, for `typed` migrations.
+    hooks: Option<Hooks>,
+}
+
+impl Migration {
+    pub fn new<N, K, F>(version: u32, name: N, kind: K, transform: F) -> Self
+    where
+        N: ToString,
+        K: ToString,
+        F: Fn(Entity) -> Result<Change, Error> + 'static,
+    {
+        Migration {
+            version,
+            name: name.to_string(),
+            kind: kind.to_string(),
+            namespace: None,
+            transform: Box::new(transform),
+            hooks: None,
+        }
+    }
+    /// A migration from the `From` model to the `To` model of the same kind.
+    /// Properties `From` doesn't know about are dropped.
+    pub fn typed<From, To, N, F>(version: u32, name: N, transform: F) -> Self
+    where
+        From: DeserializeOwned,
+        To: Serialize + EntityKey,
+        N: ToString,
+        F: Fn(From) -> Result<To, Error> + 'static,
+    {
+        let migration = Migration::new(version, name, To::entity_kind_key(), move |entity| {
+            let key = entity.key.clone().ok_or(Error::NoPayload)?;
+            let value = transform(entity.to_model::<From>()?)?;
+            Ok(Change::Put(Entity::from_model(key, &value)?))
+        });
+        Migration {hooks: To::entity_hooks(), ..migration}
+    }
+    pub fn with_namespace<N: ToString>(mut self, namespace: N) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+    fn record_key(&self) -> Key {
+        let key = Key::name(MIGRATIONS_KIND, format!("{}-{}", self.version, self.name));
+        match &self.namespace {
+            Some(x) => key.with_namespace(x),
+            None => key,
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// PROGRESS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Progress {
+    pub version: u32,
+    pub name: String,
+    pub kind: String,
+    /// Entities the transform has seen.
+    pub processed: u64,
+    pub written: u64,
+    pub deleted: u64,
+    pub done: bool,
+    /// Where to continue from.
+    cursor: Option<String>,
+}
+
+impl Progress {
+    fn new(migration: &Migration) -> Self {
+        Progress {
+            version: migration.version,
+            name: migration.name.clone(),
+            kind: migration.kind.clone(),
+            ..Progress::default()
+        }
+    }
+    fn to_entity(&self, key: Key) -> Entity {
+        let mut entity = Entity::new(key);
+        entity
+            .set("version", self.version as i64)
+            .set("name", self.name.as_str())
+            .set("kind", self.kind.as_str())
+            .set("processed", self.processed as i64)
+            .set("written", self.written as i64)
+            .set("deleted", self.deleted as i64)
+            .set("done", self.done);
+        match &self.cursor {
+            Some(x) => entity.set_unindexed("cursor", x.as_str()),
+            None => entity.set("cursor", Value::Null),
+        };
+        entity
+    }
+    fn from_entity(entity: &Entity) -> Self {
+        let integer = |name| match entity.get(name) {
+            Some(Value::Integer(x)) => *x,
+            _ => 0,
+        };
+        let string = |name| match entity.get(name) {
+            Some(Value::String(x)) => Some(x.clone()),
+            _ => None,
+        };
+        Progress {
+            version: integer("version") as u32,
+            name: string("name").unwrap_or_default(),
+            kind: string("kind").unwrap_or_default(),
+            processed: integer("processed") as u64,
+            written: integer("written") as u64,
+            deleted: integer("deleted") as u64,
+            done: entity.get("done") == Some(&Value::Boolean(true)),
+            cursor: string("cursor"),
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// MIGRATOR
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// One non-transactional commit per page.
+    #[default]
+    Batched,
+    /// Every entity is re-read, transformed and written in its own
+    /// transaction, so concurrent writes aren't overwritten. A transaction
+    /// that hits contention is retried, see `Migrator::retries`.
+    Transactional,
+}
+
+/// What happened to one entity in `WriteMode::Transactional`.
+enum Applied {
+    /// Deleted since the page was read.
+    Gone,
+    Skipped,
+    Written,
+    Deleted,
+}
+
+type ProgressFn<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+pub struct Migrator<'a> {
+    client: &'a DatastoreClient,
+    migrations: Vec<Migration>,
+    batch_size: i32,
+    mode: WriteMode,
+    retries: u32,
+    retry_delay: Duration,
+    on_progress: Option<ProgressFn<'a>>,
+}
+
+impl<'a> Migrator<'a> {
+    pub fn new(client: &'a DatastoreClient) -> Self {
+        Migrator {
+            client,
+            migrations: Vec::new(),
+            batch_size: 100,
+            mode: WriteMode::default(),
+            retries: 5,
+            retry_delay: Duration::from_millis(100),
+            on_progress: None,
+        }
+    }
+    pub fn migration(mut self, migration: Migration) -> Self {
+        self.migrations.push(migration);
+        self
+    }
+    /// Entities per page, at most 500 (the commit limit).
+    pub fn batch_size(mut self, batch_size: i32) -> Self {
+        self.batch_size = batch_size.clamp(1, COMMIT_LIMIT as i32);
+        self
+    }
+    pub fn mode(mut self, mode: WriteMode) -> Self {
+        self.mode = mode;
+        self
+    }
+    /// Attempts after the first for a `WriteMode::Transactional` write
+    /// aborted by contention (a 409). Other errors stop the run at once.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+    /// Before the first retry, doubling for every retry after it.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+    /// Called after every page.
+    pub fn on_progress<F: FnMut(&Progress) + 'a>(mut self, f: F) -> Self {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+    /// What has been recorded for every migration, without running
+    /// anything.
+    pub fn status(&self) -> Result<Vec<Progress>, Error> {
+        self.migrations
+            .iter()
+            .map(|x| self.load_progress(x))
+            .collect()
+    }
+    /// Runs every pending migration in version order, resuming any that
+    /// were interrupted. Returns the final progress of those that ran.
+    pub fn run(mut self) -> Result<Vec<Progress>, Error> {
+        self.migrations.sort_by_key(|x| x.version);
+        for pair in self.migrations.windows(2) {
+            if pair[0].version == pair[1].version {
+                return Err(Error::DuplicateMigration(pair[0].version));
+            }
+        }
+        let migrations = std::mem::take(&mut self.migrations);
+        let mut results = Vec::new();
+        for migration in &migrations {
+            let progress = self.load_progress(migration)?;
+            if progress.done {
+                continue;
+            }
+            results.push(self.run_migration(migration, progress)?);
+        }
+        Ok(results)
+    }
+    fn load_progress(&self, migration: &Migration) -> Result<Progress, Error> {
+        match self.client.get_entity(&migration.record_key()) {
+            Ok(x) => Ok(Progress::from_entity(&x)),
+            Err(Error::NoPayload) => Ok(Progress::new(migration)),
+            Err(e) => Err(e),
+        }
+    }
+    fn run_migration(&mut self, migration: &Migration, mut progress: Progress) -> Result<Progress, Error> {
+        if let Some(hooks) = migration.hooks {
+            self.client.add_hooks(migration.kind.clone(), hooks);
+        }
+        loop {
+            let mut query = query::kind_query(migration.kind.clone(), None)?;
+            query.limit = Some(self.batch_size);
+            query.start_cursor = progress.cursor.clone();
+            let mut batch = self.client.run_query_batch(query, migration.namespace.clone())?;
+            let entities = batch.entity_results
+                .take()
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|x| x.entity)
+                .collect::<Vec<_>>();
+            let count = entities.len();
+            match self.mode {
+                WriteMode::Batched => self.apply_batched(migration, entities, &mut progress)?,
+                WriteMode::Transactional => self.apply_transactional(migration, entities, &mut progress)?,
+            }
+            let has_more = query::has_more(&batch, count);
+            progress.cursor = batch.end_cursor;
+            progress.done = !has_more || progress.cursor.is_none();
+            self.client.put_entity(progress.to_entity(migration.record_key()))?;
+            if let Some(f) = self.on_progress.as_mut() {
+                f(&progress);
+            }
+            if progress.done {
+                return Ok(progress);
+            }
+        }
+    }
+    fn apply_batched(&self, migration: &Migration, entities: Vec<google_datastore1::Entity>, progress: &mut Progress) -> Result<(), Error> {
+        let mut mutations = Vec::new();
+        for entity in entities {
+            let entity = Entity::from_datastore(entity)?;
+            let key = entity.key.clone();
+            progress.processed += 1;
+            match (migration.transform)(entity)? {
+                Change::Skip => {}
+                Change::Put(entity) => {
+                    progress.written += 1;
+                    mutations.push(put(entity)?);
+                }
+                Change::Delete => {
+                    progress.deleted += 1;
+                    mutations.extend(key.map(delete));
+                }
+            }
+        }
+        if mutations.is_empty() {
+            return Ok(());
+        }
+        self.client.commit(mutations)
+    }
+    fn apply_transactional(&self, migration: &Migration, entities: Vec<google_datastore1::Entity>, progress: &mut Progress) -> Result<(), Error> {
+        for entity in entities {
+            let key = match entity.key {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut attempt = 0;
+            let applied = loop {
+                match self.apply_one(migration, key.clone()) {
+                    Err(e) if attempt < self.retries && e.http_status() == Some(409) => {
+                        tracing::debug!(error = %e, version = migration.version, "retrying contended migration write");
+                        std::thread::sleep(self.retry_delay * 2u32.saturating_pow(attempt));
+                        attempt += 1;
+                    }
+                    result => break result?,
+                }
+            };
+            match applied {
+                Applied::Gone => continue,
+                Applied::Skipped => (),
+                Applied::Written => progress.written += 1,
+                Applied::Deleted => progress.deleted += 1,
+            }
+            progress.processed += 1;
+        }
+        Ok(())
+    }
+    /// One attempt at re-reading, transforming and writing the entity at
+    /// `key` in a transaction.
+    fn apply_one(&self, migration: &Migration, key: google_datastore1::Key) -> Result<Applied, Error> {
+        let transaction = self.client.begin_transaction()?;
+        let current = match self.client.lookup_entity_with(key.clone(), Some(transaction.clone())) {
+            Ok(x) => Entity::from_datastore(x),
+            Err(Error::NoPayload) => {
+                self.client.rollback(transaction)?;
+                return Ok(Applied::Gone);
+            }
+            Err(e) => Err(e),
+        };
+        let mutation = match current.and_then(|x| (migration.transform)(x)) {
+            Ok(Change::Skip) => {
+                self.client.rollback(transaction)?;
+                return Ok(Applied::Skipped);
+            }
+            Ok(Change::Put(entity)) => put(entity).map(|x| (x, Applied::Written)),
+            Ok(Change::Delete) => Ok((delete(Key::from_datastore(key)), Applied::Deleted)),
+            Err(e) => Err(e),
+        };
+        match mutation {
+            Ok((mutation, applied)) => {
+                self.client.commit_with(vec![mutation], Some(transaction))?;
+                Ok(applied)
+            }
+            Err(e) => {
+                let _ = self.client.rollback(transaction);
+                Err(e)
+            }
+        }
+    }
+}
+
+fn put(entity: Entity) -> Result<google_datastore1::Mutation, Error> {
+    if entity.key.is_none() {
+        return Err(Error::IncompleteKey);
+    }
+    Ok(google_datastore1::Mutation {
+        upsert: Some(entity.into_datastore()),
+        ..google_datastore1::Mutation::default()
+    })
+}
+
+fn delete(key: Key) -> google_datastore1::Mutation {
+    google_datastore1::Mutation {
+        delete: Some(key.to_datastore()),
+        ..google_datastore1::Mutation::default()
+    }
+}
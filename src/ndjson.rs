@@ -0,0 +1,139 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use crate::db::{DatastoreClient, Error};
+use crate::query;
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - EXPORT & IMPORT
+///////////////////////////////////////////////////////////////////////////////
+
+/// Entities are written one per line in the JSON format of the Datastore
+/// REST API, which keeps full key paths and every value type (timestamps,
+/// keys, blobs, geo points, `excludeFromIndexes`, ...), so an import
+/// reproduces them exactly.
+///
+/// Project ids are left out of keys, so a file can be imported into another
+/// project. Namespaces are kept.
+///
+/// ```no_run
+/// # use ddb::*;
+/// let db = DatastoreClient::new().unwrap();
+/// let file = std::fs::File::create("todo-items.ndjson").unwrap();
+/// db.export_kind("TodoItem", std::io::BufWriter::new(file)).unwrap();
+///
+/// let staging = DatastoreClient::new_emulator("localhost:8081", "staging").unwrap();
+/// let file = std::fs::File::open("todo-items.ndjson").unwrap();
+/// staging.import(file).unwrap();
+/// ```
+impl DatastoreClient {
+    /// Streams every entity of the kind (in the client's namespace) to the
+    /// writer. Returns the number of entities written.
+    pub fn export_kind<W: Write>(&self, kind: &str, mut writer: W) -> Result<u64, Error> {
+        let mut query = query::kind_query(kind.to_owned(), None)?;
+        let mut count = 0;
+        loop {
+            let batch = self.run_query_batch(query.clone(), None)?;
+            for entity in batch.entity_results.unwrap_or_default().into_iter().filter_map(|x| x.entity) {
+                writeln!(writer, "{}", to_line(&entity)).map_err(Error::Io)?;
+                count += 1;
+            }
+            // A PAGE CAN BE EMPTY AND STILL NOT FINISHED
+            if batch.more_results.as_deref() != Some("NOT_FINISHED") || batch.end_cursor.is_none() {
+                break;
+            }
+            query.start_cursor = batch.end_cursor;
+        }
+        writer.flush().map_err(Error::Io)?;
+        Ok(count)
+    }
+    /// Upserts every entity read from the reader, as written by
+    /// `export_kind`. Keys without a namespace go to the client's
+    /// namespace. Returns the number of entities written.
+    pub fn import<R: Read>(&self, reader: R) -> Result<u64, Error> {
+        let mutations = BufReader::new(reader)
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.as_ref().map(|x| !x.trim().is_empty()).unwrap_or(true))
+            .map(|(number, line)| {
+                let line = line.map_err(Error::Io)?;
+                let entity = from_line(&line).map_err(|msg| Error::Deserialization {
+                    msg: format!("line {}: {}", number + 1, msg)
+                })?;
+                Ok(google_datastore1::Mutation {
+                    upsert: Some(entity),
+                    ..google_datastore1::Mutation::default()
+                })
+            });
+        self.commit_chunked(mutations)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+fn to_line(entity: &google_datastore1::Entity) -> String {
+    let mut entity = entity.clone();
+    restore_nulls(&mut entity);
+    let mut json = serde_json::to_value(&entity).expect("entity to json");
+    google_datastore1::remove_json_null_values(&mut json);
+    remove_project_ids(&mut json);
+    json.to_string()
+}
+
+fn from_line(line: &str) -> Result<google_datastore1::Entity, String> {
+    let mut entity: google_datastore1::Entity = serde_json::from_str(line)
+        .map_err(|e| e.to_string())?;
+    restore_nulls(&mut entity);
+    let is_complete = entity.key
+        .as_ref()
+        .and_then(|x| x.path.as_ref())
+        .and_then(|x| x.last())
+        .map(|x| x.name.is_some() || x.id.is_some())
+        .unwrap_or(false);
+    if !is_complete {
+        return Err(String::from("entity has no complete key"));
+    }
+    Ok(entity)
+}
+
+/// The hub reads `"nullValue": null` as no value at all, which Datastore
+/// rejects on the way back in.
+fn restore_nulls(entity: &mut google_datastore1::Entity) {
+    fn restore(value: &mut google_datastore1::Value) {
+        if let Some(x) = value.entity_value.as_mut() {
+            restore_nulls(x);
+        } else if let Some(xs) = value.array_value.as_mut() {
+            xs.values.iter_mut().flatten().for_each(restore);
+        } else if value.timestamp_value.is_none()
+            && value.geo_point_value.is_none()
+            && value.blob_value.is_none()
+            && value.double_value.is_none()
+            && value.string_value.is_none()
+            && value.key_value.is_none()
+            && value.boolean_value.is_none()
+            && value.integer_value.is_none() {
+            value.null_value = Some(String::from("NULL_VALUE"));
+        }
+    }
+    entity.properties.iter_mut().flat_map(|x| x.values_mut()).for_each(restore);
+}
+
+/// Drops `partitionId.projectId` from every key, including key values.
+/// Keys in the default namespace lose `partitionId` altogether.
+fn remove_project_ids(json: &mut serde_json::Value) {
+    match json {
+        serde_json::Value::Object(xs) => {
+            if let Some(serde_json::Value::Object(partition)) = xs.get_mut("partitionId") {
+                partition.remove("projectId");
+                // THE DEFAULT NAMESPACE, SO IMPORTS GO TO THE CLIENT'S
+                if partition.is_empty() {
+                    xs.remove("partitionId");
+                }
+            }
+            xs.values_mut().for_each(remove_project_ids);
+        }
+        serde_json::Value::Array(xs) => xs.iter_mut().for_each(remove_project_ids),
+        _ => {}
+    }
+}
@@ -0,0 +1,377 @@
+use std::marker::PhantomData;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::convert;
+use crate::db::{DatastoreClient, EntityKey, Error};
+use crate::entity::{Entity, Key, TypedKey};
+
+///////////////////////////////////////////////////////////////////////////////
+// FILTER
+///////////////////////////////////////////////////////////////////////////////
+
+/// A query filter over the properties of a kind.
+///
+/// Operands are encoded with the same converter as entity properties.
+///
+/// ```
+/// use ddb::Filter;
+/// let open_and_recent = Filter::eq("done", false)
+///     .and(Filter::gt("priority", 2));
+/// let urgent_or_tagged = Filter::is_in("priority", vec![4, 5])
+///     .or(Filter::contains("tags", "urgent"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Filter(FilterKind);
+
+#[derive(Debug, Clone)]
+pub(crate) enum FilterKind {
+    Property {
+        name: String,
+        op: &'static str,
+        /// Encoding errors are reported when the query runs.
+        value: Result<Box<google_datastore1::Value>, String>,
+    },
+    Composite {
+        op: &'static str,
+        filters: Vec<Filter>,
+    },
+}
+
+impl Filter {
+    pub fn eq<N: ToString, V: Serialize>(name: N, value: V) -> Self {
+        Filter::property(name, "EQUAL", value)
+    }
+    pub fn lt<N: ToString, V: Serialize>(name: N, value: V) -> Self {
+        Filter::property(name, "LESS_THAN", value)
+    }
+    pub fn le<N: ToString, V: Serialize>(name: N, value: V) -> Self {
+        Filter::property(name, "LESS_THAN_OR_EQUAL", value)
+    }
+    pub fn gt<N: ToString, V: Serialize>(name: N, value: V) -> Self {
+        Filter::property(name, "GREATER_THAN", value)
+    }
+    pub fn ge<N: ToString, V: Serialize>(name: N, value: V) -> Self {
+        Filter::property(name, "GREATER_THAN_OR_EQUAL", value)
+    }
+    pub fn ne<N: ToString, V: Serialize>(name: N, value: V) -> Self {
+        Filter::property(name, "NOT_EQUAL", value)
+    }
+    /// The property equals one of the given values.
+    pub fn is_in<N: ToString, V: Serialize, I: IntoIterator<Item = V>>(name: N, values: I) -> Self {
+        Filter::property(name, "IN", values.into_iter().collect::<Vec<_>>())
+    }
+    /// The property equals none of the given values.
+    pub fn not_in<N: ToString, V: Serialize, I: IntoIterator<Item = V>>(name: N, values: I) -> Self {
+        Filter::property(name, "NOT_IN", values.into_iter().collect::<Vec<_>>())
+    }
+    /// An array property has an element equal to the value. This is plain
+    /// equality, which Datastore matches against each element of an array.
+    pub fn contains<N: ToString, V: Serialize>(name: N, value: V) -> Self {
+        Filter::eq(name, value)
+    }
+    /// Entities that are descendants of (or equal to) the given key.
+    pub fn has_ancestor(key: &Key) -> Self {
+        Filter::property("__key__", "HAS_ANCESTOR", key)
+    }
+    /// All of the given filters must match.
+    pub fn all(filters: Vec<Filter>) -> Self {
+        Filter(FilterKind::Composite {op: "AND", filters})
+    }
+    /// Any of the given filters must match.
+    pub fn any(filters: Vec<Filter>) -> Self {
+        Filter(FilterKind::Composite {op: "OR", filters})
+    }
+    pub fn and(self, other: Filter) -> Self {
+        self.combine("AND", other)
+    }
+    pub fn or(self, other: Filter) -> Self {
+        self.combine("OR", other)
+    }
+    /// Extends an existing composite of the same kind rather than nesting.
+    fn combine(self, op: &'static str, other: Filter) -> Self {
+        match self.0 {
+            FilterKind::Composite {op: self_op, mut filters} if self_op == op => {
+                filters.push(other);
+                Filter(FilterKind::Composite {op, filters})
+            }
+            _ => Filter(FilterKind::Composite {op, filters: vec![self, other]}),
+        }
+    }
+    fn property<N: ToString, V: Serialize>(name: N, op: &'static str, value: V) -> Self {
+        let value = convert::to_datastore_value(value)
+            .map(Box::new)
+            .ok_or_else(|| format!("filter value for {} not representable in datastore", name.to_string()));
+        Filter(FilterKind::Property {name: name.to_string(), op, value})
+    }
+    pub(crate) fn to_datastore(&self) -> Result<google_datastore1::Filter, Error> {
+        match &self.0 {
+            FilterKind::Property {name, op, value} => {
+                let value = value
+                    .clone()
+                    .map_err(|msg| Error::Serialization {msg})?;
+                Ok(google_datastore1::Filter {
+                    property_filter: Some(google_datastore1::PropertyFilter {
+                        property: Some(google_datastore1::PropertyReference {
+                            name: Some(name.clone()),
+                        }),
+                        op: Some(String::from(*op)),
+                        value: Some(*value),
+                    }),
+                    composite_filter: None,
+                })
+            }
+            FilterKind::Composite {op, filters} => {
+                let filters = filters
+                    .iter()
+                    .map(Filter::to_datastore)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(google_datastore1::Filter {
+                    composite_filter: Some(google_datastore1::CompositeFilter {
+                        op: Some(String::from(*op)),
+                        filters: Some(filters),
+                    }),
+                    property_filter: None,
+                })
+            }
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// QUERY
+///////////////////////////////////////////////////////////////////////////////
+
+/// A query over the `T` kind, see `DatastoreClient::query`.
+///
+/// ```no_run
+/// # use ddb::*;
+/// # use serde::{Serialize, Deserialize};
+/// # #[derive(Serialize, Deserialize)]
+/// # struct TodoItem {name: String, title: String, done: bool}
+/// # impl EntityKey for TodoItem {
+/// #     fn entity_kind_key() -> String {String::from("TodoItem")}
+/// #     fn entity_name_key(&self) -> String {self.name.clone()}
+/// # }
+/// #[derive(Deserialize)]
+/// struct TodoTitle {
+///     title: String,
+/// }
+/// let db = DatastoreClient::new().unwrap();
+/// let titles = db.query::<TodoItem>()
+///     .filter(Filter::eq("done", false))
+///     .order_by("title")
+///     .project::<TodoTitle>()
+///     .unwrap();
+/// ```
+pub struct Query<'a, T> {
+    client: &'a DatastoreClient,
+    kind: String,
+    filter: Option<Filter>,
+    order: Vec<(String, &'static str)>,
+    distinct_on: Vec<String>,
+    limit: Option<i32>,
+    offset: Option<i32>,
+    marker: PhantomData<T>,
+}
+
+impl DatastoreClient {
+    pub fn query<T: EntityKey>(&self) -> Query<'_, T> {
+        self.register_hooks::<T>();
+        self.query_of_kind(T::entity_kind_key())
+    }
+    /// A query over a kind with no Rust model. Use `entities` to run it.
+    pub fn query_entities(&self, kind: &str) -> Query<'_, Entity> {
+        self.query_of_kind(kind.to_owned())
+    }
+    fn query_of_kind<T>(&self, kind: String) -> Query<'_, T> {
+        Query {
+            client: self,
+            kind,
+            filter: None,
+            order: Vec::new(),
+            distinct_on: Vec::new(),
+            limit: None,
+            offset: None,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T> Query<'a, T> {
+    /// Filters are combined with `AND`.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(match self.filter {
+            Some(x) => x.and(filter),
+            None => filter,
+        });
+        self
+    }
+    pub fn order_by<N: ToString>(mut self, name: N) -> Self {
+        self.order.push((name.to_string(), "ASCENDING"));
+        self
+    }
+    pub fn order_by_desc<N: ToString>(mut self, name: N) -> Self {
+        self.order.push((name.to_string(), "DESCENDING"));
+        self
+    }
+    /// Only the first result for each distinct combination of the given
+    /// properties. These must be part of the projection (see `project`).
+    pub fn distinct_on<N: ToString>(mut self, names: &[N]) -> Self {
+        self.distinct_on = names.iter().map(|x| x.to_string()).collect();
+        self
+    }
+    pub fn limit(mut self, limit: i32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+    pub fn offset(mut self, offset: i32) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+    pub fn fetch(self) -> Result<Vec<T>, Error> where T: DeserializeOwned {
+        let query = self.to_datastore()?;
+        decode_all(self.client.run_query(query, None)?)
+    }
+    /// Loads only the properties `P` is made of (by serde field name) and
+    /// decodes them into `P`. `P` must be a struct.
+    pub fn project<P: DeserializeOwned>(self) -> Result<Vec<P>, Error> {
+        let fields = struct_fields::<P>()
+            .ok_or(Error::Deserialization {
+                msg: String::from("projection type must be a struct")
+            })?;
+        let mut query = self.to_datastore()?;
+        query.projection = Some(projection(fields));
+        decode_all(self.client.run_query(query, None)?)
+    }
+    /// Runs the query without decoding into `T`.
+    pub fn entities(self) -> Result<Vec<Entity>, Error> {
+        let query = self.to_datastore()?;
+        self.client.run_query(query, None)?
+            .into_iter()
+            .map(Entity::from_datastore)
+            .collect()
+    }
+    /// Number of matching entities, see `DatastoreClient::count`.
+    pub fn count(self) -> Result<u64, Error> {
+        self.client.count_kind(&self.kind, self.filter)
+    }
+    pub fn keys_only(self) -> Result<Vec<TypedKey<T>>, Error> {
+        let mut query = self.to_datastore()?;
+        query.projection = Some(projection(&["__key__"]));
+        let keys = self.client.run_query(query, None)?
+            .into_iter()
+            .filter_map(|x| x.key)
+            .map(|x| TypedKey::new(Key::from_datastore(x)))
+            .collect();
+        Ok(keys)
+    }
+    pub(crate) fn to_datastore(&self) -> Result<google_datastore1::Query, Error> {
+        let mut query = kind_query(self.kind.clone(), self.filter.as_ref())?;
+        if !self.order.is_empty() {
+            let order = self.order
+                .iter()
+                .map(|(name, direction)| google_datastore1::PropertyOrder {
+                    property: Some(google_datastore1::PropertyReference {
+                        name: Some(name.clone()),
+                    }),
+                    direction: Some(String::from(*direction)),
+                })
+                .collect();
+            query.order = Some(order);
+        }
+        if !self.distinct_on.is_empty() {
+            let distinct_on = self.distinct_on
+                .iter()
+                .map(|x| google_datastore1::PropertyReference {
+                    name: Some(x.clone()),
+                })
+                .collect();
+            query.distinct_on = Some(distinct_on);
+        }
+        query.limit = self.limit;
+        query.offset = self.offset;
+        Ok(query)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+pub(crate) fn decode_all<T: DeserializeOwned>(entities: Vec<google_datastore1::Entity>) -> Result<Vec<T>, Error> {
+    entities
+        .into_iter()
+        .map(|x| {
+            convert::from_datastore_entity(x).ok_or_else(|| Error::Deserialization {
+                msg: String::from("conversion or parser error")
+            })
+        })
+        .collect()
+}
+
+/// The serde field names of a struct, found by asking it to deserialize
+/// itself from a deserializer that only records what it's asked for. `None`
+/// when `T` doesn't declare them, e.g. maps and structs with a `flatten`ed
+/// field.
+pub(crate) fn struct_fields<T: DeserializeOwned>() -> Option<&'static [&'static str]> {
+    use serde::de::{self, Deserializer, Visitor};
+    struct Introspect<'a>(&'a mut Option<&'static [&'static str]>);
+    impl<'de, 'a> Deserializer<'de> for Introspect<'a> {
+        type Error = de::value::Error;
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = Some(fields);
+            Err(de::Error::custom("done"))
+        }
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+    let mut fields = None;
+    let _ = T::deserialize(Introspect(&mut fields));
+    fields
+}
+
+pub(crate) fn kind_query(kind: String, filter: Option<&Filter>) -> Result<google_datastore1::Query, Error> {
+    Ok(google_datastore1::Query {
+        kind: Some(vec![google_datastore1::KindExpression {
+            name: Some(kind),
+        }]),
+        filter: filter.map(Filter::to_datastore).transpose()?,
+        ..google_datastore1::Query::default()
+    })
+}
+
+/// Whether a query paging itself (with a limit per page) should fetch the
+/// page after `batch`, which had `received` entities. An empty page can
+/// still be NOT_FINISHED, e.g. when the scan runs into the tombstones of
+/// recently deleted entities. MORE_RESULTS_AFTER_LIMIT doesn't mean there
+/// are any more, so it only counts after a full page.
+pub(crate) fn has_more(batch: &google_datastore1::QueryResultBatch, received: usize) -> bool {
+    match batch.more_results.as_deref() {
+        Some("NOT_FINISHED") => true,
+        Some("MORE_RESULTS_AFTER_LIMIT") => received > 0,
+        _ => false,
+    }
+}
+
+pub(crate) fn projection(names: &[&str]) -> Vec<google_datastore1::Projection> {
+    names
+        .iter()
+        .map(|x| google_datastore1::Projection {
+            property: Some(google_datastore1::PropertyReference {
+                name: Some(String::from(*x)),
+            }),
+        })
+        .collect()
+}
@@ -0,0 +1,202 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use crate::db::DatastoreClient;
+
+///////////////////////////////////////////////////////////////////////////////
+// SCHEDULES
+///////////////////////////////////////////////////////////////////////////////
+
+/// Operations per second a budget allows, over time.
+///
+/// A rate that isn't positive (e.g. a ramp with a negative `growth`) lets
+/// nothing through: every operation waits `MAX_WAIT`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    Constant(f64),
+    /// `initial` per second, multiplied by `1 + growth` after every
+    /// `every`, up to `max` (if given).
+    Ramp {
+        initial: f64,
+        growth: f64,
+        every: Duration,
+        max: Option<f64>,
+    },
+}
+
+/// The longest a budget makes an operation wait.
+pub static MAX_WAIT: Duration = Duration::from_secs(60);
+
+impl Schedule {
+    /// Panics unless `rate` is positive and finite.
+    pub fn per_second(rate: f64) -> Self {
+        Schedule::Constant(check_rate(rate))
+    }
+    /// Datastore's "500/50/5" rule for writing to a new kind or key range:
+    /// start at 500 operations per second, then go up by 50% every 5
+    /// minutes, so it has time to split hot tablets.
+    pub fn ramp_up() -> Self {
+        Schedule::Ramp {
+            initial: 500.0,
+            growth: 0.5,
+            every: Duration::from_secs(5 * 60),
+            max: None,
+        }
+    }
+    /// Caps a ramp, e.g. to leave room for other traffic. Panics unless
+    /// `rate` is positive and finite.
+    pub fn with_max(self, rate: f64) -> Self {
+        let rate = check_rate(rate);
+        match self {
+            Schedule::Constant(x) => Schedule::Constant(x.min(rate)),
+            Schedule::Ramp {initial, growth, every, ..} => Schedule::Ramp {initial, growth, every, max: Some(rate)},
+        }
+    }
+    /// The rate `elapsed` after the first operation.
+    pub fn rate_at(&self, elapsed: Duration) -> f64 {
+        match *self {
+            Schedule::Constant(x) => x,
+            Schedule::Ramp {initial, growth, every, max} => {
+                let steps = if every.is_zero() {
+                    0
+                } else {
+                    (elapsed.as_nanos() / every.as_nanos()).min(i32::MAX as u128) as i32
+                };
+                let rate = initial * (1.0 + growth).powi(steps);
+                max.map(|x| rate.min(x)).unwrap_or(rate)
+            }
+        }
+    }
+}
+
+fn check_rate(rate: f64) -> f64 {
+    assert!(rate.is_finite() && rate > 0.0, "rate must be positive and finite, got {}", rate);
+    rate
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// LIMITER
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Entities looked up or returned by a query.
+    Read,
+    /// Entities written or deleted.
+    Write,
+}
+
+/// Separate read and write budgets, in entities per second, see
+/// `DatastoreClient::with_rate_limit`.
+///
+/// Each budget is a token bucket holding up to a second's worth of
+/// operations, so short bursts go through at once.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    reads: Option<RefCell<Bucket>>,
+    writes: Option<RefCell<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    schedule: Schedule,
+    /// Set by the first operation.
+    started: Option<Instant>,
+    updated: Option<Instant>,
+    /// Negative while operations wait for their turn.
+    tokens: f64,
+}
+
+impl RateLimiter {
+    /// No limits until `with_reads` or `with_writes`.
+    pub fn new() -> Self {
+        RateLimiter::default()
+    }
+    pub fn with_reads(mut self, schedule: Schedule) -> Self {
+        self.reads = Some(RefCell::new(Bucket::new(schedule)));
+        self
+    }
+    pub fn with_writes(mut self, schedule: Schedule) -> Self {
+        self.writes = Some(RefCell::new(Bucket::new(schedule)));
+        self
+    }
+    /// Takes `units` from the budget and returns how long to wait before
+    /// going ahead. Taking more than is left is fine; the wait covers it.
+    pub fn reserve(&self, access: Access, units: u64) -> Duration {
+        let bucket = match access {
+            Access::Read => &self.reads,
+            Access::Write => &self.writes,
+        };
+        match bucket {
+            Some(x) => x.borrow_mut().reserve(units, Instant::now()),
+            None => Duration::from_secs(0),
+        }
+    }
+    /// The current rate of a budget, `None` if it's unlimited.
+    pub fn rate(&self, access: Access) -> Option<f64> {
+        let bucket = match access {
+            Access::Read => &self.reads,
+            Access::Write => &self.writes,
+        };
+        bucket.as_ref().map(|x| x.borrow().rate(Instant::now()))
+    }
+}
+
+impl Bucket {
+    fn new(schedule: Schedule) -> Self {
+        Bucket {
+            schedule,
+            started: None,
+            updated: None,
+            tokens: 0.0,
+        }
+    }
+    fn rate(&self, now: Instant) -> f64 {
+        let elapsed = self.started.map(|x| now - x).unwrap_or_default();
+        self.schedule.rate_at(elapsed)
+    }
+    fn reserve(&mut self, units: u64, now: Instant) -> Duration {
+        self.started.get_or_insert(now);
+        let rate = self.rate(now);
+        self.tokens = match self.updated {
+            Some(x) => (self.tokens + rate * (now - x).as_secs_f64()).min(rate),
+            None => rate,
+        };
+        self.updated = Some(now);
+        self.tokens -= units as f64;
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+        // INFINITE, NEGATIVE OR NAN FOR A RATE THAT ISN'T POSITIVE
+        Duration::try_from_secs_f64(-self.tokens / rate)
+            .unwrap_or(MAX_WAIT)
+            .min(MAX_WAIT)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - RATE LIMITING
+///////////////////////////////////////////////////////////////////////////////
+
+/// ```no_run
+/// # use ddb::*;
+/// let db = DatastoreClient::new()
+///     .unwrap()
+///     .with_rate_limit(
+///         RateLimiter::new()
+///             .with_reads(Schedule::per_second(2000.0))
+///             .with_writes(Schedule::ramp_up())
+///     );
+/// ```
+impl DatastoreClient {
+    /// Blocks RPCs until their budget allows them (shared with clones made
+    /// afterwards). Lookups and commits are charged a unit per key before
+    /// they're sent; queries are charged per result once they return, so
+    /// the next read waits for them.
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(Rc::new(limiter));
+        self
+    }
+}
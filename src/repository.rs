@@ -0,0 +1,144 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::cache::{Cache, CacheLayer, CacheReads};
+use crate::db::{DatastoreClient, EntityKey, Error, Write};
+use crate::entity::{self, Key};
+use crate::query::{Filter, Query};
+
+///////////////////////////////////////////////////////////////////////////////
+// REPOSITORY
+///////////////////////////////////////////////////////////////////////////////
+
+/// The `T` entities, with settings that only apply to them: a namespace,
+/// a cache policy and properties to keep out of indexes.
+///
+/// ```no_run
+/// # use ddb::*;
+/// # #[derive(serde::Serialize, serde::Deserialize)]
+/// # struct TodoItem {name: String, notes: String}
+/// # impl EntityKey for TodoItem {
+/// #     fn entity_kind_key() -> String {String::from("TodoItem")}
+/// #     fn entity_name_key(&self) -> String {self.name.clone()}
+/// # }
+/// let db = DatastoreClient::new().unwrap();
+/// let todos = db.repo::<TodoItem>()
+///     .with_namespace("tenant-42")
+///     .with_unindexed(&["notes"]);
+/// let mut item = todos.get("test").unwrap();
+/// item.notes.push_str(" (done)");
+/// todos.put(item).unwrap();
+/// assert!(todos.exists("test").unwrap());
+/// ```
+pub struct Repository<T> {
+    client: DatastoreClient,
+    unindexed: BTreeSet<String>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Repository<T> {
+    fn clone(&self) -> Self {
+        Repository {
+            client: self.client.clone(),
+            unindexed: self.unindexed.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: EntityKey> Repository<T> {
+    pub fn with_namespace<N: ToString>(mut self, namespace: N) -> Self {
+        self.client = self.client.with_namespace(namespace);
+        self
+    }
+    /// A cache for this kind only, read instead of the client's (see
+    /// `DatastoreClient::with_cache`). Writes through the repository evict
+    /// from both; writes through the client itself don't evict from it.
+    pub fn with_cache<C: Cache + 'static>(mut self, cache: C) -> Self {
+        self.client.cache_reads = CacheReads::Local(Rc::new(RefCell::new(CacheLayer::new(cache))));
+        self
+    }
+    /// Always reads from Datastore, even if the client has a cache. Writes
+    /// still evict from it.
+    pub fn without_cache(mut self) -> Self {
+        self.client.cache_reads = CacheReads::Bypass;
+        self
+    }
+    /// Properties written with `exclude_from_indexes`, e.g. long text that
+    /// is never filtered on (and may be over the 1500 byte index limit).
+    pub fn with_unindexed<N: ToString>(mut self, names: &[N]) -> Self {
+        self.unindexed.extend(names.iter().map(|x| x.to_string()));
+        self
+    }
+    /// The client with this repository's namespace and cache.
+    pub fn client(&self) -> &DatastoreClient {
+        &self.client
+    }
+    pub fn get<K: ToString>(&self, name_key: K) -> Result<T, Error> where T: DeserializeOwned {
+        self.client.get::<T, K>(name_key)
+    }
+    /// See `DatastoreClient::get_many`.
+    pub fn get_many<K, I>(&self, name_keys: I) -> Result<Vec<Option<T>>, Error>
+    where
+        T: DeserializeOwned,
+        K: ToString,
+        I: IntoIterator<Item = K>,
+    {
+        self.client.get_many::<T, K, I>(name_keys)
+    }
+    pub fn exists<K: ToString>(&self, name_key: K) -> Result<bool, Error> {
+        let key = Key::name(T::entity_kind_key(), name_key);
+        match self.client.lookup_entity(key.to_datastore()) {
+            Ok(_) => Ok(true),
+            Err(Error::NoPayload) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    /// Inserts or replaces the entity.
+    pub fn put(&self, value: T) -> Result<(), Error> where T: Serialize {
+        self.write(Write::Upsert, value)
+    }
+    /// Fails if the entity already exists.
+    pub fn insert(&self, value: T) -> Result<(), Error> where T: Serialize {
+        self.write(Write::Insert, value)
+    }
+    /// Fails if the entity doesn't exist.
+    pub fn update(&self, value: T) -> Result<(), Error> where T: Serialize {
+        self.write(Write::Update, value)
+    }
+    pub fn delete<K: ToString>(&self, name_key: K) -> Result<(), Error> {
+        self.client.delete::<T, K>(name_key)
+    }
+    pub fn query(&self) -> Query<'_, T> {
+        self.client.query::<T>()
+    }
+    pub fn count<F: Into<Option<Filter>>>(&self, filter: F) -> Result<u64, Error> {
+        self.client.count::<T, F>(filter)
+    }
+    fn write(&self, write: Write, value: T) -> Result<(), Error> where T: Serialize {
+        self.client.write_model(write, value, |entity| {
+            let properties = entity.properties.iter_mut().flatten();
+            for (_, value) in properties.filter(|(name, _)| self.unindexed.contains(*name)) {
+                entity::exclude_from_indexes(value);
+            }
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - REPOSITORIES
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    /// A `Repository` for `T`, starting with this client's settings.
+    pub fn repo<T: EntityKey>(&self) -> Repository<T> {
+        self.register_hooks::<T>();
+        Repository {
+            client: self.clone(),
+            unindexed: BTreeSet::new(),
+            marker: PhantomData,
+        }
+    }
+}
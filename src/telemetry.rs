@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use crate::db::{DatastoreClient, Error};
+use crate::entity::Key;
+use crate::ratelimit::Access;
+
+///////////////////////////////////////////////////////////////////////////////
+// EVENTS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Datastore RPCs, named as in the REST API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Operation {
+    Lookup,
+    Commit,
+    RunQuery,
+    RunAggregationQuery,
+    BeginTransaction,
+    Rollback,
+}
+
+impl Operation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Lookup => "lookup",
+            Operation::Commit => "commit",
+            Operation::RunQuery => "runQuery",
+            Operation::RunAggregationQuery => "runAggregationQuery",
+            Operation::BeginTransaction => "beginTransaction",
+            Operation::Rollback => "rollback",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    /// With the HTTP status, if Datastore answered.
+    Error(Option<u16>),
+}
+
+/// One finished RPC, as passed to `Metrics::record`. The client doesn't
+/// retry RPCs itself, so a retried one (e.g. by `BulkWriter`) is an event
+/// per attempt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcEvent {
+    pub operation: Operation,
+    /// Kinds involved, comma separated if there are several.
+    pub kind: Option<String>,
+    /// Keys looked up or written, or entities returned by a query.
+    pub keys: usize,
+    pub namespace: Option<String>,
+    pub latency: Duration,
+    pub outcome: Outcome,
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// METRICS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Receives every RPC a client makes, see `DatastoreClient::with_metrics`.
+pub trait Metrics {
+    fn record(&self, event: &RpcEvent);
+}
+
+/// Upper bounds of the latency histogram buckets, in milliseconds.
+static LATENCY_BUCKETS_MS: [u64; 12] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10_000, 30_000];
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Histogram {
+    /// Counts per bucket of `LATENCY_BUCKETS_MS`, plus one for anything
+    /// slower.
+    counts: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, latency: Duration) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; LATENCY_BUCKETS_MS.len() + 1];
+        }
+        let millis = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|x| millis <= *x)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+    /// `(upper bound, count)` per bucket. The last bucket has no bound.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        let bounds = LATENCY_BUCKETS_MS
+            .iter()
+            .map(|x| Some(Duration::from_millis(*x)))
+            .chain(std::iter::once(None));
+        bounds
+            .zip(self.counts.iter().copied().chain(std::iter::repeat(0)))
+            .collect()
+    }
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.sum / self.count as u32)
+    }
+    /// The upper bound of the bucket holding the `q` quantile (e.g. `0.99`),
+    /// `None` if that's the unbounded bucket or nothing was observed.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let target = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= target && self.count > 0 {
+                return bound;
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OperationStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub latency: Histogram,
+}
+
+/// Counters and latency histograms per operation.
+///
+/// ```no_run
+/// # use ddb::DatastoreClient;
+/// # use ddb::telemetry::MetricsRecorder;
+/// # use std::rc::Rc;
+/// let metrics = Rc::new(MetricsRecorder::new());
+/// let db = DatastoreClient::new().unwrap().with_metrics(metrics.clone());
+/// // ...
+/// for (operation, stats) in metrics.snapshot() {
+///     println!("{}: {} calls, p99 {:?}", operation.name(), stats.calls, stats.latency.quantile(0.99));
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct MetricsRecorder {
+    stats: RefCell<BTreeMap<Operation, OperationStats>>,
+}
+
+impl MetricsRecorder {
+    pub fn new() -> Self {
+        MetricsRecorder::default()
+    }
+    pub fn get(&self, operation: Operation) -> OperationStats {
+        self.stats.borrow().get(&operation).cloned().unwrap_or_default()
+    }
+    pub fn snapshot(&self) -> BTreeMap<Operation, OperationStats> {
+        self.stats.borrow().clone()
+    }
+    pub fn reset(&self) {
+        self.stats.borrow_mut().clear();
+    }
+}
+
+impl Metrics for MetricsRecorder {
+    fn record(&self, event: &RpcEvent) {
+        let mut stats = self.stats.borrow_mut();
+        let stats = stats.entry(event.operation).or_default();
+        stats.calls += 1;
+        if event.outcome != Outcome::Ok {
+            stats.errors += 1;
+        }
+        stats.latency.observe(event.latency);
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - INSTRUMENTATION
+///////////////////////////////////////////////////////////////////////////////
+
+/// What an RPC is about to do, for its span and `RpcEvent`.
+pub(crate) struct Rpc {
+    operation: Operation,
+    kinds: BTreeSet<String>,
+    keys: usize,
+    namespace: Option<String>,
+}
+
+impl Rpc {
+    pub(crate) fn new(operation: Operation) -> Self {
+        Rpc {operation, kinds: BTreeSet::new(), keys: 0, namespace: None}
+    }
+    pub(crate) fn key(&mut self, key: &google_datastore1::Key) -> &mut Self {
+        let key = Key::from_datastore(key.clone());
+        self.kinds.extend(key.kind().map(String::from));
+        if self.namespace.is_none() {
+            self.namespace = key.namespace;
+        }
+        self.keys += 1;
+        self
+    }
+    pub(crate) fn kind(&mut self, kind: Option<&String>) -> &mut Self {
+        self.kinds.extend(kind.cloned());
+        self
+    }
+    pub(crate) fn namespace(&mut self, partition: Option<&google_datastore1::PartitionId>) -> &mut Self {
+        self.namespace = partition
+            .and_then(|x| x.namespace_id.clone())
+            .filter(|x| !x.is_empty());
+        self
+    }
+    /// For results only known once the RPC is done.
+    pub(crate) fn keys(&mut self, keys: usize) -> &mut Self {
+        self.keys = keys;
+        self
+    }
+    fn kind_label(&self) -> Option<String> {
+        if self.kinds.is_empty() {
+            return None;
+        }
+        Some(self.kinds.iter().cloned().collect::<Vec<_>>().join(","))
+    }
+}
+
+impl DatastoreClient {
+    /// Reports RPCs to `metrics` (shared with clones made afterwards).
+    /// Every RPC also gets a `tracing` span, with or without this.
+    pub fn with_metrics(mut self, metrics: Rc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+    /// Runs an RPC in a `datastore` span and reports it to the metrics
+    /// hook. `f` can fill in the key count once it has results.
+    pub(crate) fn instrument<T, F>(&self, mut rpc: Rpc, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Rpc) -> Result<T, Error>,
+    {
+        if rpc.namespace.is_none() {
+            rpc.namespace = self.namespace.clone().filter(|x| !x.is_empty());
+        }
+        let span = tracing::info_span!(
+            "datastore",
+            operation = rpc.operation.name(),
+            kind = tracing::field::Empty,
+            keys = tracing::field::Empty,
+            namespace = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+            throttled_ms = tracing::field::Empty,
+            outcome = tracing::field::Empty,
+            status = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+        let throttled = self.throttle(&rpc);
+        if !throttled.is_zero() {
+            span.record("throttled_ms", throttled.as_secs_f64() * 1000.0);
+        }
+        let start = Instant::now();
+        let result = f(&mut rpc);
+        let latency = start.elapsed();
+        if let Some(limiter) = &self.rate_limit {
+            // QUERY RESULTS ARE ONLY KNOWN NOW, THE NEXT READ WAITS FOR THEM
+            if let Operation::RunQuery | Operation::RunAggregationQuery = rpc.operation {
+                limiter.reserve(Access::Read, rpc.keys.max(1) as u64);
+            }
+        }
+        let outcome = match &result {
+            Ok(_) => Outcome::Ok,
+            Err(e) => Outcome::Error(e.http_status()),
+        };
+        let kind = rpc.kind_label();
+        if let Some(x) = &kind {
+            span.record("kind", x.as_str());
+        }
+        if let Some(x) = &rpc.namespace {
+            span.record("namespace", x.as_str());
+        }
+        span.record("keys", rpc.keys as u64);
+        span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+        match &result {
+            Ok(_) => {
+                span.record("outcome", "ok");
+            }
+            Err(e) => {
+                span.record("outcome", "error");
+                if let Outcome::Error(Some(x)) = outcome {
+                    span.record("status", x);
+                }
+                tracing::debug!(error = %e, "datastore {} failed", rpc.operation.name());
+            }
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.record(&RpcEvent {
+                operation: rpc.operation,
+                kind,
+                keys: rpc.keys,
+                namespace: rpc.namespace,
+                latency,
+                outcome,
+            });
+        }
+        result
+    }
+    /// Waits for the rate limiter (see `with_rate_limit`), returning how
+    /// long that took.
+    fn throttle(&self, rpc: &Rpc) -> Duration {
+        let limiter = match &self.rate_limit {
+            Some(x) => x,
+            None => return Duration::from_secs(0),
+        };
+        let (access, units) = match rpc.operation {
+            Operation::Lookup => (Access::Read, rpc.keys),
+            Operation::Commit => (Access::Write, rpc.keys),
+            // CHARGED ONCE THEY'RE DONE, BUT STILL WAIT FOR EARLIER READS
+            Operation::RunQuery | Operation::RunAggregationQuery => (Access::Read, 0),
+            _ => return Duration::from_secs(0),
+        };
+        let wait = limiter.reserve(access, units as u64);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+        wait
+    }
+}
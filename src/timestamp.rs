@@ -0,0 +1,99 @@
+//! RFC 3339 timestamps, for formats that store them as microseconds since
+//! the epoch.
+
+static MICROS_PER_SECOND: i64 = 1_000_000;
+static SECONDS_PER_DAY: i64 = 86_400;
+
+/// E.g. `2020-01-01T00:00:00Z`, with six fractional digits when the time
+/// isn't a whole second.
+pub(crate) fn format_micros(micros: i64) -> String {
+    let seconds = micros.div_euclid(MICROS_PER_SECOND);
+    let fraction = micros.rem_euclid(MICROS_PER_SECOND);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(SECONDS_PER_DAY));
+    let time = seconds.rem_euclid(SECONDS_PER_DAY);
+    let mut result = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year, month, day, time / 3600, time % 3600 / 60, time % 60
+    );
+    if fraction != 0 {
+        result.push_str(&format!(".{:06}", fraction));
+    }
+    result.push('Z');
+    result
+}
+
+/// Accepts any number of fractional digits (beyond microseconds they are
+/// dropped) and `Z` or a `+hh:mm`/`-hh:mm` offset.
+pub(crate) fn parse_micros(source: &str) -> Option<i64> {
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = source.get(range)?;
+        if !digits.bytes().all(|x| x.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    let bytes = source.as_bytes();
+    if bytes.len() < 20
+        || bytes[4] != b'-' || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let mut rest = &source[19..];
+    let mut fraction = 0;
+    if let Some(digits) = rest.strip_prefix('.') {
+        let len = digits.bytes().take_while(|x| x.is_ascii_digit()).count();
+        if len == 0 {
+            return None;
+        }
+        let padded = format!("{:0<6}", &digits[..len.min(6)]);
+        fraction = padded.parse::<i64>().ok()?;
+        rest = &digits[len..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let hours: i64 = rest[1..3].parse().ok()?;
+            let minutes: i64 = rest[4..6].parse().ok()?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+        _ => return None,
+    };
+    let days = days_from_civil(year, month, day);
+    let seconds = days * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second - offset;
+    Some(seconds * MICROS_PER_SECOND + fraction)
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 {year - 1} else {year};
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 {mp + 3} else {mp - 9};
+    let year = year_of_era + era * 400 + if month <= 2 {1} else {0};
+    (year, month, day)
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use serde::{Serialize, Serializer, de::DeserializeOwned};
+use crate::convert;
+use crate::db::EntityKey;
+use crate::query;
+
+///////////////////////////////////////////////////////////////////////////////
+// EXTRA PROPERTIES
+///////////////////////////////////////////////////////////////////////////////
+
+/// Stored properties that are not part of a model, keyed by property name.
+///
+/// Values are kept exactly as Datastore returned them, including their value
+/// type (timestamp, key, blob, etc.) and `exclude_from_indexes` flag.
+pub type ExtraProperties = HashMap<String, google_datastore1::Value>;
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TRACKED
+///////////////////////////////////////////////////////////////////////////////
+
+/// A typed model along with any stored properties it doesn't know about.
+///
+/// Load with `DatastoreClient::get_tracked` and write back with `insert`,
+/// `upsert` or `update` as usual; the unknown properties are written back
+/// unchanged instead of being erased. Useful while different versions of a
+/// model are running side by side.
+#[derive(Debug, Clone)]
+pub struct Tracked<T> {
+    pub value: T,
+    pub extra: ExtraProperties,
+}
+
+impl<T> Tracked<T> {
+    pub fn new(value: T) -> Self {
+        Tracked {value, extra: ExtraProperties::default()}
+    }
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Tracked<T> {
+    /// Splits the entity into the fields `T` declares and everything else.
+    /// A declared field `T` doesn't write back (e.g. a `None` skipped by
+    /// `skip_serializing_if`) isn't extra, so saving removes it.
+    pub(crate) fn from_datastore_entity(entity: google_datastore1::Entity) -> Option<Self> {
+        let mut extra = entity.properties.clone().unwrap_or_default();
+        let value: T = convert::from_datastore_entity(entity)?;
+        match query::struct_fields::<T>() {
+            Some(fields) => extra.retain(|k, _| !fields.contains(&k.as_str())),
+            // E.G. A `flatten`ED FIELD, GO BY WHAT `T` WRITES
+            None => {
+                let known = convert::to_datastore_value(&value)
+                    .and_then(|x| x.entity_value)
+                    .and_then(|x| x.properties)
+                    .unwrap_or_default();
+                extra.retain(|k, _| !known.contains_key(k));
+            }
+        }
+        Some(Tracked {value, extra})
+    }
+}
+
+impl<T> Deref for Tracked<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Tracked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T: EntityKey> EntityKey for Tracked<T> {
+    fn entity_kind_key() -> String {
+        T::entity_kind_key()
+    }
+    fn entity_name_key(&self) -> String {
+        self.value.entity_name_key()
+    }
+    fn entity_hooks() -> Option<crate::Hooks> {
+        T::entity_hooks()
+    }
+}
+
+impl<T: Serialize> Serialize for Tracked<T> {
+    /// Fields of `T` take precedence over extra properties of the same name.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+        let mut properties = match serde_json::to_value(&self.value).map_err(S::Error::custom)? {
+            serde_json::Value::Object(xs) => xs,
+            _ => return Err(S::Error::custom("expecting struct/map like input")),
+        };
+        for (k, v) in self.extra.iter() {
+            if !properties.contains_key(k) {
+                properties.insert(k.clone(), convert::raw_value_to_json(v));
+            }
+        }
+        properties.serialize(serializer)
+    }
+}
@@ -0,0 +1,252 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use serde::{Serialize, Deserialize};
+use crate::db::{DatastoreClient, Error};
+
+///////////////////////////////////////////////////////////////////////////////
+// TRANSPORT
+///////////////////////////////////////////////////////////////////////////////
+
+/// A POST to the Datastore REST API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Sends every request a client makes, see `DatastoreClient::with_transport`.
+///
+/// Error statuses are responses, not errors; `send` only fails if there's
+/// no response at all.
+pub trait Transport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Error>;
+}
+
+/// The default transport, over HTTPS (or plain HTTP for the emulator).
+pub struct HyperTransport {
+    client: hyper::Client,
+}
+
+impl HyperTransport {
+    pub fn new() -> Self {
+        HyperTransport {
+            client: hyper::Client::with_connector(
+                hyper::net::HttpsConnector::new(hyper_rustls::TlsClient::new())
+            ),
+        }
+    }
+}
+
+impl Default for HyperTransport {
+    fn default() -> Self {
+        HyperTransport::new()
+    }
+}
+
+impl Transport for HyperTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Error> {
+        use std::io::Read;
+        let mut headers = hyper::header::Headers::new();
+        for (name, value) in &request.headers {
+            headers.set_raw(name.clone(), vec![value.clone().into_bytes()]);
+        }
+        let mut res = self.client
+            .post(&request.url)
+            .headers(headers)
+            .body(request.body.as_str())
+            .send()
+            .map_err(|e| Error::DatabaseResponse(google_datastore1::Error::HttpError(e)))?;
+        let mut body = String::new();
+        res.read_to_string(&mut body)
+            .map_err(|e| Error::DatabaseResponse(google_datastore1::Error::HttpError(e.into())))?;
+        Ok(HttpResponse {
+            status: res.status.to_u16(),
+            body,
+        })
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CASSETTES
+///////////////////////////////////////////////////////////////////////////////
+
+/// Request/response pairs in the order they were made, saved as JSON.
+///
+/// Requests are kept without their headers (so without credentials) and
+/// without the host, so a cassette recorded against the emulator replays
+/// for any base URL with the same project id.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    /// E.g. `/v1/projects/dev:lookup`.
+    pub path: String,
+    pub request: serde_json::Value,
+    pub status: u16,
+    /// A string if the body wasn't JSON.
+    pub response: serde_json::Value,
+}
+
+impl Cassette {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = std::fs::File::open(path).map_err(Error::Io)?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| Error::Deserialization {msg: e.to_string()})
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        if let Some(dir) = path.as_ref().parent().filter(|x| !x.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(Error::Io)?;
+        }
+        let mut json = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Serialization {msg: e.to_string()})?;
+        json.push('\n');
+        std::fs::write(path, json).map_err(Error::Io)
+    }
+}
+
+/// Passes requests on to another transport, saving each pair to a
+/// cassette file as it goes.
+pub struct Recorder {
+    inner: Rc<dyn Transport>,
+    path: PathBuf,
+    cassette: RefCell<Cassette>,
+}
+
+impl Recorder {
+    /// Starts a new cassette at `path`, replacing any existing one once
+    /// the first request is made.
+    pub fn new<P: AsRef<Path>>(inner: Rc<dyn Transport>, path: P) -> Self {
+        Recorder {
+            inner,
+            path: path.as_ref().to_path_buf(),
+            cassette: RefCell::new(Cassette::default()),
+        }
+    }
+    pub fn cassette(&self) -> Cassette {
+        self.cassette.borrow().clone()
+    }
+}
+
+impl Transport for Recorder {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Error> {
+        let response = self.inner.send(request)?;
+        let mut cassette = self.cassette.borrow_mut();
+        cassette.interactions.push(Interaction {
+            path: url_path(&request.url).to_owned(),
+            request: to_json(&request.body),
+            status: response.status,
+            response: to_json(&response.body),
+        });
+        cassette.save(&self.path)?;
+        Ok(response)
+    }
+}
+
+/// Serves responses from a cassette, without a network.
+///
+/// Each request gets the first unused interaction with the same path and
+/// body, so repeated requests (e.g. reading an entity before and after a
+/// write) get their responses in the recorded order. A request that wasn't
+/// recorded fails with `Error::Io` (`NotFound`).
+pub struct Replayer {
+    /// `None` once served.
+    interactions: RefCell<Vec<Option<Interaction>>>,
+}
+
+impl Replayer {
+    pub fn new(cassette: Cassette) -> Self {
+        Replayer {
+            interactions: RefCell::new(cassette.interactions.into_iter().map(Some).collect()),
+        }
+    }
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Cassette::open(path).map(Replayer::new)
+    }
+    /// Interactions no request has asked for (yet).
+    pub fn remaining(&self) -> usize {
+        self.interactions.borrow().iter().filter(|x| x.is_some()).count()
+    }
+}
+
+impl Transport for Replayer {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Error> {
+        let path = url_path(&request.url);
+        let body = to_json(&request.body);
+        let mut interactions = self.interactions.borrow_mut();
+        let interaction = interactions
+            .iter_mut()
+            .find(|x| x.as_ref().is_some_and(|x| x.path == path && x.request == body))
+            .and_then(Option::take)
+            .ok_or_else(|| {
+                Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no recorded response for POST {} {}", path, body),
+                ))
+            })?;
+        let body = match interaction.response {
+            serde_json::Value::String(x) => x,
+            x => x.to_string(),
+        };
+        Ok(HttpResponse {
+            status: interaction.status,
+            body,
+        })
+    }
+}
+
+/// The URL without its scheme and host.
+fn url_path(url: &str) -> &str {
+    let rest = url.split_once("://").map(|x| x.1).unwrap_or(url);
+    rest.find('/').map(|x| &rest[x..]).unwrap_or("/")
+}
+
+fn to_json(body: &str) -> serde_json::Value {
+    serde_json::from_str(body).unwrap_or_else(|_| serde_json::Value::String(body.to_owned()))
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - TRANSPORT
+///////////////////////////////////////////////////////////////////////////////
+
+/// ```no_run
+/// # use ddb::*;
+/// // RECORD ONCE AGAINST THE EMULATOR...
+/// let db = DatastoreClient::new_emulator("localhost:8081", "dev")
+///     .unwrap()
+///     .with_recording("tests/data/cassettes/todos.json");
+/// // ...THEN REPLAY IN CI
+/// let db = DatastoreClient::new_replay("tests/data/cassettes/todos.json", "dev").unwrap();
+/// ```
+impl DatastoreClient {
+    /// Sends requests through `transport` (shared with clones made
+    /// afterwards) instead of HTTP.
+    pub fn with_transport(mut self, transport: Rc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+    /// Records every request and response to a cassette at `path`, for
+    /// `new_replay`.
+    pub fn with_recording<P: AsRef<Path>>(self, path: P) -> Self {
+        let recorder = Recorder::new(self.transport.clone(), path);
+        self.with_transport(Rc::new(recorder))
+    }
+}
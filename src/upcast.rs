@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use crate::db::{DatastoreClient, EntityKey, Error};
+use crate::entity::Entity;
+
+/// Property holding the schema version an entity was written with.
+pub static SCHEMA_VERSION_PROPERTY: &str = "_ddb_schema_version";
+
+///////////////////////////////////////////////////////////////////////////////
+// UPCAST
+///////////////////////////////////////////////////////////////////////////////
+
+/// Loads entities written by older layouts of a model.
+///
+/// Once registered with `DatastoreClient::register_upcast`, typed writes
+/// (`insert`, `upsert`, `update`) store `VERSION` in the
+/// `_ddb_schema_version` property, and every read of an older entity runs it
+/// through `upcast` one version at a time before decoding. Entities written
+/// before versioning are version 0. Projections are never upcast.
+///
+/// ```no_run
+/// # use ddb::*;
+/// # use serde::{Serialize, Deserialize};
+/// #[derive(Serialize, Deserialize)]
+/// struct TodoItem {
+///     name: String,
+///     // WAS `title` IN VERSION 0
+///     summary: String,
+/// }
+/// # impl EntityKey for TodoItem {
+/// #     fn entity_kind_key() -> String {String::from("TodoItem")}
+/// #     fn entity_name_key(&self) -> String {self.name.clone()}
+/// # }
+/// impl Upcast for TodoItem {
+///     const VERSION: u32 = 1;
+///     fn upcast(version: u32, mut raw: Entity) -> Result<Entity, Error> {
+///         if version == 0 {
+///             if let Some(title) = raw.remove("title") {
+///                 raw.set("summary", title);
+///             }
+///         }
+///         Ok(raw)
+///     }
+/// }
+/// let db = DatastoreClient::new().unwrap();
+/// db.register_upcast::<TodoItem>(true);
+/// let item = db.get::<TodoItem, _>("old-item").unwrap();
+/// ```
+pub trait Upcast {
+    const VERSION: u32;
+    /// Upgrades an entity from `version` to `version + 1`.
+    fn upcast(version: u32, raw: Entity) -> Result<Entity, Error>;
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Upcaster {
+    version: u32,
+    upcast: fn(u32, Entity) -> Result<Entity, Error>,
+    write_back: bool,
+}
+
+pub(crate) type Upcasters = HashMap<String, Upcaster>;
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT - UPCASTING
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    /// Upcasts `T` entities on every read by this client (and its clones).
+    /// With `write_back`, upgraded entities are also stored in their new
+    /// shape as they're read, unless they were written again in between. A
+    /// failed write-back is logged, and doesn't fail the read.
+    pub fn register_upcast<T: Upcast + EntityKey>(&self, write_back: bool) {
+        let upcaster = Upcaster {
+            version: T::VERSION,
+            upcast: T::upcast,
+            write_back,
+        };
+        self.upcasters.borrow_mut().insert(T::entity_kind_key(), upcaster);
+    }
+    fn upcaster(&self, entity: &google_datastore1::Entity) -> Option<Upcaster> {
+        let kind = entity.key.as_ref()?
+            .path.as_ref()?
+            .last()?
+            .kind.as_ref()?;
+        self.upcasters.borrow().get(kind).copied()
+    }
+    /// Stamps the current schema version on an entity about to be written.
+    pub(crate) fn stamp_version(&self, mut entity: google_datastore1::Entity) -> google_datastore1::Entity {
+        if let Some(upcaster) = self.upcaster(&entity) {
+            entity.properties
+                .get_or_insert_with(HashMap::new)
+                .insert(String::from(SCHEMA_VERSION_PROPERTY), version_value(upcaster.version));
+        }
+        entity
+    }
+    /// Brings entities read from the database up to the current version.
+    pub(crate) fn upcast_all(&self, entities: Vec<google_datastore1::Entity>) -> Result<Vec<google_datastore1::Entity>, Error> {
+        if self.upcasters.borrow().is_empty() {
+            return Ok(entities);
+        }
+        let mut results = Vec::with_capacity(entities.len());
+        for stored in entities {
+            let (entity, upgraded) = self.upcast_entity(stored.clone())?;
+            if upgraded {
+                if let Err(e) = self.write_back(&stored, &entity) {
+                    // THE READ STILL HAS THE UPGRADED ENTITY, THE NEXT ONE TRIES AGAIN
+                    tracing::warn!(error = %e, "upcast write-back failed");
+                }
+            }
+            results.push(entity);
+        }
+        Ok(results)
+    }
+    /// Stores the upgraded entity, in a transaction that only commits if
+    /// the entity is still as it was read.
+    fn write_back(&self, stored: &google_datastore1::Entity, upgraded: &google_datastore1::Entity) -> Result<(), Error> {
+        let key = stored.key.clone().ok_or(Error::NoPayload)?;
+        let transaction = self.begin_transaction()?;
+        let current = match self.lookup_entity_with(key, Some(transaction.clone())) {
+            Ok(x) => Some(x),
+            Err(Error::NoPayload) => None,
+            Err(e) => {
+                let _ = self.rollback(transaction);
+                return Err(e);
+            }
+        };
+        let unchanged = current.is_some_and(|x| same_properties(&x, stored));
+        if !unchanged {
+            return self.rollback(transaction);
+        }
+        let mutation = google_datastore1::Mutation {
+            upsert: Some(upgraded.clone()),
+            ..google_datastore1::Mutation::default()
+        };
+        self.commit_with(vec![mutation], Some(transaction))
+    }
+    /// The entity at the current version, and whether it should be written
+    /// back.
+    fn upcast_entity(&self, entity: google_datastore1::Entity) -> Result<(google_datastore1::Entity, bool), Error> {
+        let upcaster = match self.upcaster(&entity) {
+            Some(x) => x,
+            None => return Ok((entity, false)),
+        };
+        let stored = entity.properties
+            .as_ref()
+            .and_then(|x| x.get(SCHEMA_VERSION_PROPERTY))
+            .and_then(|x| x.integer_value.as_ref())
+            .and_then(|x| x.parse::<u32>().ok())
+            .unwrap_or(0);
+        // CURRENT, OR WRITTEN BY A NEWER LAYOUT THAN WE KNOW
+        if stored >= upcaster.version {
+            return Ok((entity, false));
+        }
+        let mut raw = Entity::from_datastore(entity)?;
+        for version in stored..upcaster.version {
+            raw = (upcaster.upcast)(version, raw)?;
+        }
+        let entity = self.stamp_version(raw.into_datastore());
+        Ok((entity, upcaster.write_back))
+    }
+}
+
+fn same_properties(a: &google_datastore1::Entity, b: &google_datastore1::Entity) -> bool {
+    let json = |x: &google_datastore1::Entity| serde_json::to_value(&x.properties).ok();
+    json(a) == json(b)
+}
+
+fn version_value(version: u32) -> google_datastore1::Value {
+    google_datastore1::Value {
+        integer_value: Some(version.to_string()),
+        ..google_datastore1::Value::default()
+    }
+}
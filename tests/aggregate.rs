@@ -0,0 +1,119 @@
+mod common;
+
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use ddb::{EntityKey, Filter};
+use common::Script;
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Todo {
+    name: String,
+    done: bool,
+}
+
+impl EntityKey for Todo {
+    fn entity_kind_key() -> String {
+        String::from("Todo")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+fn error(code: u16, status: &str) -> (u16, serde_json::Value) {
+    (code, json!({"error": {"code": code, "message": status.to_lowercase(), "status": status}}))
+}
+
+fn keys(names: &[&str]) -> (u16, serde_json::Value) {
+    let results = names
+        .iter()
+        .map(|x| json!({"entity": {"key": {"path": [{"kind": "Todo", "name": x}]}}}))
+        .collect::<Vec<_>>();
+    (200, json!({"batch": {"entityResults": results, "moreResults": "NO_MORE_RESULTS"}}))
+}
+
+/// Todos with these `points`, `None` for a todo without them.
+fn points(values: &[Option<serde_json::Value>]) -> (u16, serde_json::Value) {
+    let results = values
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let mut properties = json!({"name": {"stringValue": i.to_string()}});
+            if let Some(x) = x {
+                properties["points"] = x.clone();
+            }
+            json!({"entity": {"key": {"path": [{"kind": "Todo", "name": i.to_string()}]}, "properties": properties}})
+        })
+        .collect::<Vec<_>>();
+    (200, json!({"batch": {"entityResults": results, "moreResults": "NO_MORE_RESULTS"}}))
+}
+
+/// Two numbers, a string and a missing value: only the numbers count.
+fn mixed() -> (u16, serde_json::Value) {
+    points(&[
+        Some(json!({"integerValue": "3"})),
+        Some(json!({"doubleValue": 1.5})),
+        Some(json!({"stringValue": "many"})),
+        None,
+    ])
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn counts_server_side() {
+    let script = Script::new(vec![json!({
+        "batch": {"aggregationResults": [{"aggregateProperties": {"result": {"integerValue": "7"}}}]},
+    })]);
+    let db = script.client();
+    assert_eq!(db.count::<Todo, _>(Filter::eq("done", false)).expect("count"), 7);
+    assert_eq!(script.methods(), vec!["runAggregationQuery"]);
+}
+
+#[test]
+fn unimplemented_aggregations_fall_back_to_paging() {
+    let script = Script::with_status(vec![error(501, "UNIMPLEMENTED"), keys(&["a", "b"])]);
+    let db = script.client();
+    assert_eq!(db.count::<Todo, _>(None).expect("count"), 2);
+    assert_eq!(script.methods(), vec!["runAggregationQuery", "runQuery"]);
+}
+
+#[test]
+fn not_found_is_an_error() {
+    let script = Script::with_status(vec![error(404, "NOT_FOUND"), keys(&["a", "b"])]);
+    let db = script.client();
+    let result = db.count::<Todo, _>(None);
+    assert_eq!(result.err().and_then(|e| e.http_status()), Some(404));
+    assert_eq!(script.methods(), vec!["runAggregationQuery"]);
+}
+
+#[test]
+fn sums_fall_back_to_paging() {
+    let script = Script::with_status(vec![error(501, "UNIMPLEMENTED"), mixed()]);
+    let db = script.client();
+    assert_eq!(db.sum::<Todo, _>("points", None).expect("sum"), 4.5);
+    assert_eq!(script.methods(), vec!["runAggregationQuery", "runQuery"]);
+    assert_eq!(script.bodies("runAggregationQuery")[0]["aggregationQuery"]["aggregations"][0]["sum"], json!({"property": {"name": "points"}}));
+}
+
+#[test]
+fn averages_fall_back_to_paging() {
+    let script = Script::with_status(vec![
+        error(501, "UNIMPLEMENTED"),
+        mixed(),
+        error(501, "UNIMPLEMENTED"),
+        points(&[]),
+    ]);
+    let db = script.client();
+    assert_eq!(db.avg::<Todo, _>("points", None).expect("avg"), Some(2.25));
+    // NO ROWS, NOTHING TO AVERAGE
+    assert_eq!(db.avg::<Todo, _>("points", Filter::eq("done", true)).expect("avg"), None);
+    assert_eq!(script.methods(), vec!["runAggregationQuery", "runQuery", "runAggregationQuery", "runQuery"]);
+}
@@ -0,0 +1,137 @@
+use serde::Deserialize;
+use ddb::{Entity, Key, Value};
+use ddb::backup::{EntityReader, EntityWriter, ExportReader};
+
+/// Written by `gcloud beta emulators datastore` (`projects/dev:export`).
+static EXPORT: &str = "tests/data/export/export.overall_export_metadata";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+fn parent_key() -> Key {
+    Key::name("Parent", "p")
+}
+
+/// The entity stored as `Parent:p/Thing:a` in the export.
+fn exported_entity() -> Entity {
+    let mut inner = Entity::default();
+    inner.set_unindexed("inner", "v");
+    let mut entity = Entity::new(parent_key().child_name("Thing", "a"));
+    entity
+        .set("s", "héllo")
+        .set("i", -5)
+        .set("d", 1.5)
+        .set("b", true)
+        .set("n", Value::Null)
+        .set("t", Value::Timestamp(String::from("2020-01-02T03:04:05.123456Z")))
+        .set("g", Value::GeoPoint {latitude: 1.25, longitude: -2.5})
+        .set("k", Key::id("Other", 42))
+        .set_unindexed("bl", vec![0u8, 1, 2])
+        .set("arr", Value::Array(vec![Value::Integer(1), Value::from("x")]))
+        .set("empty", Value::Array(Vec::new()))
+        .set("e", Value::Entity(inner))
+        .set_unindexed("long", "unindexed");
+    entity
+}
+
+fn write_all(entities: &[Entity]) -> Vec<u8> {
+    let mut writer = EntityWriter::new(Vec::new(), "dev");
+    for entity in entities {
+        writer.write(entity).expect("write");
+    }
+    writer.finish().expect("finish")
+}
+
+fn read_all(bytes: &[u8]) -> Vec<Entity> {
+    EntityReader::new(bytes)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("read")
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn reads_a_managed_export() {
+    let entities = ExportReader::open(EXPORT)
+        .expect("open")
+        .collect::<Result<Vec<_>, _>>()
+        .expect("read");
+    let keys = entities
+        .iter()
+        .map(|x| x.key.clone().expect("key"))
+        .collect::<Vec<_>>();
+    assert_eq!(keys, vec![
+        parent_key().child_name("Thing", "a"),
+        parent_key().child_name("Thing", "a-copy"),
+        Key::id("Thing", 7).with_namespace("ns1"),
+        Key::id("Thing", 1007).with_namespace("ns1"),
+    ]);
+    assert_eq!(entities[0], exported_entity());
+}
+
+#[test]
+fn reads_models() {
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Thing {
+        s: String,
+        i: i64,
+        arr: Vec<serde_json::Value>,
+    }
+    let metadata = "tests/data/export/default_namespace/kind_Thing/default_namespace_kind_Thing.export_metadata";
+    let things = ExportReader::open(metadata)
+        .expect("open")
+        .models::<Thing>()
+        .collect::<Result<Vec<_>, _>>()
+        .expect("read");
+    assert_eq!(things.len(), 2);
+    assert_eq!(things[0], Thing {
+        s: String::from("héllo"),
+        i: -5,
+        arr: vec![serde_json::json!(1), serde_json::json!("x")],
+    });
+}
+
+#[test]
+fn written_entities_read_back() {
+    let mut large = Entity::new(Key::id("Thing", 1).with_namespace("ns1"));
+    // SPANS SEVERAL BLOCKS
+    large.set_unindexed("blob", (0..100_000).map(|x| x as u8).collect::<Vec<_>>());
+    let mut keyless = exported_entity();
+    keyless.set("e", Value::Entity(exported_entity()));
+    let entities = (0..50)
+        .map(|x| {
+            let mut entity = exported_entity();
+            entity.key = Some(Key::id("Thing", x));
+            entity
+        })
+        .chain(vec![large, keyless])
+        .collect::<Vec<_>>();
+    assert_eq!(read_all(&write_all(&entities)), entities);
+}
+
+#[test]
+fn writes_what_the_export_contains() {
+    let bytes = std::fs::read("tests/data/export/default_namespace/kind_Thing/output-0").expect("read");
+    let entities = read_all(&bytes);
+    assert_eq!(read_all(&write_all(&entities)), entities);
+}
+
+#[test]
+fn corruption_is_an_error() {
+    let mut bytes = write_all(&[exported_entity()]);
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    let results = EntityReader::new(bytes.as_slice()).collect::<Vec<_>>();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].is_err());
+}
+
+#[test]
+fn entities_need_complete_keys() {
+    let mut writer = EntityWriter::new(Vec::new(), "dev");
+    assert!(writer.write(&Entity::default()).is_err());
+}
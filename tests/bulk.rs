@@ -0,0 +1,185 @@
+#![allow(clippy::result_large_err)]
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::Serialize;
+use ddb::{DatastoreClient, EntityKey, Error};
+use ddb::bulk::BulkWriter;
+use ddb::transport::{Cassette, HttpRequest, HttpResponse, Transport};
+
+/// Three batches of 10, 10 and 5 rows, recorded by `records_the_batches`.
+static CASSETTE: &str = "tests/data/cassettes/bulk.json";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Serialize)]
+struct Row {
+    id: u64,
+    label: String,
+}
+
+impl EntityKey for Row {
+    fn entity_kind_key() -> String {
+        String::from("BulkRow")
+    }
+    fn entity_name_key(&self) -> String {
+        format!("row-{:02}", self.id)
+    }
+}
+
+fn rows() -> impl Iterator<Item = Row> {
+    (0..25).map(|id| Row {id, label: format!("label {}", id)})
+}
+
+fn replay(cassette: &str) -> BulkWriter<'_> {
+    BulkWriter::new(move || DatastoreClient::new_replay(cassette, "dev"))
+        .workers(3)
+        .batch_size(10)
+}
+
+/// A fresh path in the temp directory.
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ddb-bulk-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn checkpoint_rows(path: &PathBuf) -> u64 {
+    let json = std::fs::read_to_string(path).expect("checkpoint");
+    serde_json::from_str::<serde_json::Value>(&json).expect("json")["rows"]
+        .as_u64()
+        .expect("rows")
+}
+
+/// Keeps the labels of every committed row, in commit order. Commits with
+/// a `slow` row take a while.
+struct Commits(Arc<Mutex<Vec<String>>>);
+
+impl Transport for Commits {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Error> {
+        let body = serde_json::from_str::<serde_json::Value>(&request.body).expect("json body");
+        let labels = body["mutations"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|x| x["upsert"]["properties"]["label"]["stringValue"].as_str())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        if labels.iter().any(|x| x == "slow") {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        self.0.lock().expect("lock").extend(labels);
+        Ok(HttpResponse {status: 200, body: String::from("{}")})
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Run with `cargo test --test bulk -- --ignored` and
+/// `DATASTORE_EMULATOR_HOST` set to re-record the cassette.
+#[test]
+#[ignore]
+fn records_the_batches() {
+    let host = std::env::var("DATASTORE_EMULATOR_HOST").expect("DATASTORE_EMULATOR_HOST");
+    // ONE WORKER, SO ONE RECORDER HAS EVERY BATCH
+    let factory = move || DatastoreClient::new_emulator(&host, "dev").map(|x| x.with_recording(CASSETTE));
+    let progress = BulkWriter::new(factory)
+        .workers(1)
+        .batch_size(10)
+        .run(rows())
+        .expect("run");
+    assert_eq!(progress.written, 25);
+}
+
+#[test]
+fn writes_every_batch() {
+    let mut reports = 0;
+    let progress = replay(CASSETTE)
+        .on_progress(|_| reports += 1)
+        .run(rows())
+        .expect("run");
+    assert_eq!(progress.written, 25);
+    assert_eq!(progress.batches, 3);
+    assert_eq!(progress.checkpoint, 25);
+    assert_eq!(reports, 3);
+}
+
+#[test]
+fn resumes_from_the_checkpoint() {
+    let checkpoint = temp_path("resume.json");
+    std::fs::write(&checkpoint, r#"{"rows": 10}"#).expect("write");
+    let progress = replay(CASSETTE)
+        .checkpoint(&checkpoint)
+        .run(rows())
+        .expect("run");
+    assert_eq!(progress.resumed, 10);
+    assert_eq!(progress.written, 15);
+    assert_eq!(checkpoint_rows(&checkpoint), 25);
+}
+
+#[test]
+fn failed_batches_stop_the_checkpoint() {
+    // THE SECOND BATCH WAS NEVER RECORDED, SO IT CAN'T BE REPLAYED
+    let mut cassette = Cassette::open(CASSETTE).expect("cassette");
+    cassette.interactions.remove(1);
+    let cassette_path = temp_path("missing-batch.json");
+    cassette.save(&cassette_path).expect("save");
+    let checkpoint = temp_path("failed.json");
+    let cassette_path = cassette_path.to_str().expect("path");
+    let result = replay(cassette_path)
+        .checkpoint(&checkpoint)
+        .run(rows());
+    assert!(matches!(result, Err(Error::BatchFailed {first_row: 10, ..})), "{:?}", result);
+    assert_eq!(checkpoint_rows(&checkpoint), 10);
+}
+
+#[test]
+fn retries_failed_batches() {
+    // THE FIRST BATCH IS THROTTLED ONCE BEFORE IT GOES THROUGH
+    let mut cassette = Cassette::open(CASSETTE).expect("cassette");
+    let mut throttled = cassette.interactions[0].clone();
+    throttled.status = 503;
+    throttled.response = serde_json::json!({"error": {"code": 503, "message": "unavailable", "status": "UNAVAILABLE"}});
+    cassette.interactions.insert(0, throttled);
+    let cassette_path = temp_path("throttled.json");
+    cassette.save(&cassette_path).expect("save");
+    let cassette_path = cassette_path.to_str().expect("path");
+    let progress = replay(cassette_path)
+        .retry_delay(Duration::from_millis(1))
+        .run(rows())
+        .expect("run");
+    assert_eq!(progress.retries, 1);
+    assert_eq!(progress.written, 25);
+}
+
+#[test]
+fn later_rows_win_across_batches() {
+    let commits = Arc::new(Mutex::new(Vec::new()));
+    let log = commits.clone();
+    let factory = move || {
+        let transport = Rc::new(Commits(log.clone()));
+        DatastoreClient::new_emulator("localhost:8081", "dev").map(|x| x.with_transport(transport))
+    };
+    // ROW 0 IS IN THE FIRST AND THIRD BATCH, THE FIRST COMMIT IS SLOW
+    let rows = vec![
+        Row {id: 0, label: String::from("slow")},
+        Row {id: 1, label: String::from("b")},
+        Row {id: 2, label: String::from("c")},
+        Row {id: 0, label: String::from("last")},
+    ];
+    let progress = BulkWriter::new(factory)
+        .workers(3)
+        .batch_size(1)
+        .run(rows)
+        .expect("run");
+    assert_eq!(progress.written, 4);
+    let commits = commits.lock().expect("lock").clone();
+    let position = |label: &str| commits.iter().position(|x| x == label);
+    assert!(position("slow") < position("last"), "{:?}", commits);
+}
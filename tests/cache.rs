@@ -0,0 +1,205 @@
+#![allow(clippy::result_large_err)]
+mod common;
+
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use ddb::{Cache, EntityKey, Key, LruCache};
+use ddb::migrate::{Change, Migration, Migrator, WriteMode};
+use common::Script;
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+fn put(cache: &mut LruCache, key: Key, entity: serde_json::Value) {
+    cache.put(key, serde_json::from_value(entity).expect("entity"));
+}
+
+/// The `name` of the cached entity.
+fn get(cache: &mut LruCache, key: &Key) -> Option<String> {
+    cache.get(key)?.properties?.remove("name")?.string_value
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Flag {
+    name: String,
+    on: bool,
+}
+
+impl EntityKey for Flag {
+    fn entity_kind_key() -> String {
+        String::from("Flag")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+fn flag(name: &str) -> serde_json::Value {
+    json!({
+        "key": {"path": [{"kind": "Flag", "name": name}]},
+        "properties": {
+            "name": {"stringValue": name},
+            "on": {"booleanValue": true},
+            "note": {"stringValue": "legacy", "meaning": 22, "excludeFromIndexes": true},
+        },
+    })
+}
+
+fn found(names: &[&str]) -> serde_json::Value {
+    json!({"found": names.iter().map(|x| json!({"entity": flag(x)})).collect::<Vec<_>>()})
+}
+
+fn switched_off(name: &str) -> serde_json::Value {
+    let mut entity = flag(name);
+    entity["properties"]["on"] = json!({"booleanValue": false});
+    json!({"found": [{"entity": entity}]})
+}
+
+fn cached(script: &std::rc::Rc<Script>) -> ddb::DatastoreClient {
+    script.client().with_cache(LruCache::new(2000))
+}
+
+/// Number of keys in each lookup.
+fn lookups(script: &Script) -> Vec<usize> {
+    script.bodies("lookup")
+        .iter()
+        .map(|x| x["keys"].as_array().map(Vec::len).unwrap_or_default())
+        .collect()
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn least_recently_used_is_evicted() {
+    let mut cache = LruCache::new(2);
+    put(&mut cache, Key::name("Flag", "a"), flag("a"));
+    put(&mut cache, Key::name("Flag", "b"), flag("b"));
+    assert_eq!(get(&mut cache, &Key::name("Flag", "a")), Some(String::from("a")));
+    put(&mut cache, Key::name("Flag", "c"), flag("c"));
+    assert_eq!(cache.len(), 2);
+    assert!(get(&mut cache, &Key::name("Flag", "b")).is_none());
+    assert!(get(&mut cache, &Key::name("Flag", "a")).is_some());
+    assert!(get(&mut cache, &Key::name("Flag", "c")).is_some());
+}
+
+#[test]
+fn namespaces_are_separate() {
+    let mut cache = LruCache::new(10);
+    put(&mut cache, Key::name("Flag", "a").with_namespace("test"), flag("a"));
+    assert!(get(&mut cache, &Key::name("Flag", "a")).is_none());
+    cache.remove(&Key::name("Flag", "a").with_namespace("test"));
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn entities_expire() {
+    let mut cache = LruCache::new(10).with_ttl(Duration::from_millis(20));
+    put(&mut cache, Key::name("Flag", "a"), flag("a"));
+    assert!(get(&mut cache, &Key::name("Flag", "a")).is_some());
+    std::thread::sleep(Duration::from_millis(30));
+    assert!(get(&mut cache, &Key::name("Flag", "a")).is_none());
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn reads_are_served_from_the_cache() {
+    let script = Script::new(vec![found(&["a"])]);
+    let db = cached(&script);
+    let first = db.get::<Flag, _>("a").expect("get");
+    assert_eq!(db.get::<Flag, _>("a").expect("get"), first);
+    assert_eq!(lookups(&script), vec![1]);
+    assert_eq!(db.cache_stats().map(|x| (x.hits, x.misses)), Some((1, 1)));
+}
+
+#[test]
+fn cached_entities_read_like_lookups() {
+    let script = Script::new(vec![found(&["a"])]);
+    let db = cached(&script);
+    let read = db.get_tracked::<Flag, _>("a").expect("get tracked");
+    let hit = db.get_tracked::<Flag, _>("a").expect("get tracked");
+    assert_eq!(serde_json::to_value(&hit.extra).expect("json"), serde_json::to_value(&read.extra).expect("json"));
+    assert_eq!(hit.extra["note"].meaning, Some(22));
+    assert_eq!(hit.extra["note"].exclude_from_indexes, Some(true));
+    assert_eq!(db.cache_stats().map(|x| x.hits), Some(1));
+}
+
+#[test]
+fn writes_evict_the_written_keys() {
+    let script = Script::new(vec![found(&["a", "b"]), json!({}), found(&["a"])]);
+    let db = cached(&script);
+    db.get_many::<Flag, _, _>(["a", "b"]).expect("get many");
+    db.upsert(Flag {name: String::from("a"), on: false}).expect("upsert");
+    db.get_many::<Flag, _, _>(["a", "b"]).expect("get many");
+    // ONLY THE WRITTEN KEY IS LOOKED UP AGAIN
+    assert_eq!(lookups(&script), vec![2, 1]);
+    assert_eq!(db.cache_stats().map(|x| (x.hits, x.misses)), Some((1, 3)));
+    db.clear_cache();
+    db.get::<Flag, _>("b").expect_err("not found");
+    assert_eq!(lookups(&script), vec![2, 1, 1]);
+}
+
+#[test]
+fn repository_writes_evict_the_client_cache() {
+    let script = Script::new(vec![found(&["a"]), json!({}), switched_off("a"), json!({}), found(&["a"])]);
+    let db = cached(&script);
+    assert!(db.get::<Flag, _>("a").expect("get").on);
+    db.repo::<Flag>().without_cache().put(Flag {name: String::from("a"), on: false}).expect("put");
+    assert!(!db.get::<Flag, _>("a").expect("get").on);
+    // AND THROUGH A REPOSITORY WITH ITS OWN CACHE
+    let flags = db.repo::<Flag>().with_cache(LruCache::new(10));
+    flags.put(Flag {name: String::from("a"), on: true}).expect("put");
+    assert!(db.get::<Flag, _>("a").expect("get").on);
+    assert_eq!(lookups(&script), vec![1, 1, 1]);
+    assert_eq!(flags.client().cache_stats().map(|x| (x.hits, x.misses)), Some((0, 0)));
+}
+
+#[test]
+fn transactional_reads_bypass_the_cache() {
+    let script = Script::new(vec![
+        found(&["a"]),
+        json!({"missing": []}),
+        json!({"batch": {"entityResults": [{"entity": flag("a")}], "moreResults": "NO_MORE_RESULTS"}}),
+        json!({"transaction": "dHg="}),
+        found(&["a"]),
+    ]);
+    let db = cached(&script);
+    db.get_entity(&Key::name("Flag", "a")).expect("get entity");
+    db.get_entity(&Key::name("Flag", "a")).expect("get entity");
+    let hits = db.cache_stats().map(|x| x.hits);
+    // THE MIGRATION READS THE FLAG AGAIN IN ITS TRANSACTION
+    let switch_off = Migration::new(1, "switch off", "Flag", |mut entity| {
+        entity.set("on", false);
+        Ok(Change::Put(entity))
+    });
+    Migrator::new(&db)
+        .migration(switch_off)
+        .mode(WriteMode::Transactional)
+        .run()
+        .expect("run");
+    let transactional = script.bodies("lookup")
+        .into_iter()
+        .filter(|x| x["readOptions"]["transaction"] == "dHg=")
+        .collect::<Vec<_>>();
+    assert_eq!(transactional.len(), 1);
+    assert_eq!(transactional[0]["keys"][0]["path"][0]["name"], "a");
+    assert_eq!(db.cache_stats().map(|x| x.hits), hits);
+}
+
+#[test]
+fn misses_are_looked_up_1000_keys_at_a_time() {
+    let script = Script::new(vec![found(&["0"])]);
+    let db = cached(&script);
+    db.get::<Flag, _>("0").expect("get");
+    // DUPLICATES ARE LOOKED UP (AND COUNTED) ONCE
+    let names = (0..1500).chain(0..10).map(|x| x.to_string()).collect::<Vec<_>>();
+    let flags = db.get_many::<Flag, _, _>(&names).expect("get many");
+    assert_eq!(flags.len(), 1510);
+    assert!(flags[0].is_some() && flags[1500].is_some());
+    assert_eq!(lookups(&script), vec![1, 1000, 499]);
+    assert_eq!(db.cache_stats().map(|x| (x.hits, x.misses)), Some((1, 1500)));
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use proptest::prelude::*;
+use ddb::{codec, Key};
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Encodes and decodes through the same JSON the hub sends over the wire.
+fn round_trip<T: Serialize + serde::de::DeserializeOwned>(value: &T) -> T {
+    let entity = codec::to_entity(value, Key::name("Test", "test")).expect("to_entity");
+    let wire = serde_json::to_string(&entity).expect("entity to json");
+    let entity: google_datastore1::Entity = serde_json::from_str(&wire).expect("entity from json");
+    codec::from_entity(entity).expect("from_entity")
+}
+
+fn arb_json() -> impl Strategy<Value = serde_json::Value> {
+    let leaf = prop_oneof![
+        Just(serde_json::Value::Null),
+        any::<bool>().prop_map(serde_json::Value::Bool),
+        any::<i64>().prop_map(serde_json::Value::from),
+        any::<f64>()
+            .prop_filter("finite", |x| x.is_finite())
+            .prop_map(serde_json::Value::from),
+        ".*".prop_map(serde_json::Value::String),
+    ];
+    leaf.prop_recursive(4, 64, 8, |inner| prop_oneof![
+        prop::collection::vec(inner.clone(), 0..8).prop_map(serde_json::Value::Array),
+        prop::collection::btree_map("[a-z]{1,8}", inner, 0..8)
+            .prop_map(|xs| serde_json::Value::Object(xs.into_iter().collect())),
+    ])
+}
+
+fn arb_object() -> impl Strategy<Value = serde_json::Value> {
+    prop::collection::btree_map("[a-z]{1,8}", arb_json(), 0..8)
+        .prop_map(|xs| serde_json::Value::Object(xs.into_iter().collect()))
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// MODELS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Address {
+    city: String,
+    zip: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Person {
+    name: String,
+    tags: Vec<String>,
+    scores: Option<Vec<i64>>,
+    matrix: Vec<Vec<i32>>,
+    addresses: Vec<Address>,
+    nicknames: HashMap<String, Vec<String>>,
+}
+
+prop_compose! {
+    fn arb_address()(city in ".*", zip in proptest::option::of("[0-9]{5}")) -> Address {
+        Address {city, zip}
+    }
+}
+
+prop_compose! {
+    fn arb_person()(
+        name in ".*",
+        tags in prop::collection::vec(".*", 0..8),
+        scores in proptest::option::of(prop::collection::vec(any::<i64>(), 0..8)),
+        matrix in prop::collection::vec(prop::collection::vec(any::<i32>(), 0..4), 0..4),
+        addresses in prop::collection::vec(arb_address(), 0..4),
+        nicknames in prop::collection::hash_map("[a-z]{1,8}", prop::collection::vec(".*", 0..4), 0..4),
+    ) -> Person {
+        Person {name, tags, scores, matrix, addresses, nicknames}
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+proptest! {
+    #[test]
+    fn json_round_trips(value in arb_object()) {
+        prop_assert_eq!(round_trip(&value), value);
+    }
+
+    #[test]
+    fn model_round_trips(person in arb_person()) {
+        prop_assert_eq!(round_trip(&person), person);
+    }
+}
+
+#[test]
+fn empty_array_without_values_decodes() {
+    // DATASTORE OMITS `values` FOR EMPTY ARRAYS
+    let wire = r#"{"properties": {"tags": {"arrayValue": {}}}}"#;
+    let entity: google_datastore1::Entity = serde_json::from_str(wire).unwrap();
+    let value: serde_json::Value = codec::from_entity(entity).unwrap();
+    assert_eq!(value, serde_json::json!({"tags": []}));
+}
+
+#[test]
+fn nested_arrays_are_not_stored_directly() {
+    let value = serde_json::json!({"matrix": [[1, 2], []]});
+    let entity = codec::to_entity(&value, Key::name("Test", "test")).unwrap();
+    let matrix = entity.properties.unwrap().remove("matrix").unwrap();
+    for x in matrix.array_value.unwrap().values.unwrap() {
+        assert!(x.array_value.is_none());
+    }
+}
+
+#[test]
+fn large_integers() {
+    let value = serde_json::json!({"n": u64::MAX});
+    assert!(codec::to_entity(&value, Key::name("Test", "test")).is_err());
+    let options = codec::Options {
+        large_integers: codec::LargeIntegers::Double,
+        ..codec::Options::default()
+    };
+    assert!(codec::to_entity_with(&value, Key::name("Test", "test"), &options).is_ok());
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Payment {
+    Cash,
+    Card {last4: String},
+    Voucher(Address),
+    Split(i64, i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Order {
+    payment: Payment,
+    refunds: Vec<Payment>,
+    fallback: Option<Payment>,
+}
+
+fn order() -> Order {
+    Order {
+        payment: Payment::Card {last4: String::from("4242")},
+        refunds: vec![Payment::Cash, Payment::Voucher(Address {city: String::from("Oslo"), zip: None})],
+        fallback: None,
+    }
+}
+
+fn round_trip_with<T: Serialize + serde::de::DeserializeOwned>(value: &T, options: &codec::Options) -> (serde_json::Value, T) {
+    let entity = codec::to_entity_with(value, Key::name("Test", "test"), options).expect("to_entity");
+    let stored = codec::from_entity::<serde_json::Value>(entity.clone()).expect("stored");
+    (stored, codec::from_entity_with(entity, options).expect("from_entity"))
+}
+
+#[test]
+fn enums_can_be_adjacently_tagged() {
+    let options = codec::Options {
+        enums: codec::Enums::Adjacent {tag: String::from("type"), content: String::from("data")},
+        ..codec::Options::default()
+    };
+    let (stored, order) = round_trip_with(&order(), &options);
+    assert_eq!(order, self::order());
+    assert_eq!(stored["payment"], serde_json::json!({"type": "Card", "data": {"last4": "4242"}}));
+    assert_eq!(stored["refunds"][0], serde_json::json!({"type": "Cash"}));
+    let (stored, split) = round_trip_with(&Order {payment: Payment::Split(1, 2), ..self::order()}, &options);
+    assert_eq!(stored["payment"], serde_json::json!({"type": "Split", "data": [1, 2]}));
+    assert_eq!(split.payment, Payment::Split(1, 2));
+}
+
+#[test]
+fn enums_can_be_internally_tagged() {
+    let options = codec::Options {
+        enums: codec::Enums::Internal {tag: String::from("type")},
+        ..codec::Options::default()
+    };
+    let (stored, order) = round_trip_with(&order(), &options);
+    assert_eq!(order, self::order());
+    assert_eq!(stored["payment"], serde_json::json!({"type": "Card", "last4": "4242"}));
+    assert_eq!(stored["refunds"][1], serde_json::json!({"type": "Voucher", "city": "Oslo", "zip": null}));
+    // ONLY STRUCTS AND MAPS HAVE SOMEWHERE TO PUT THE TAG
+    let split = Order {payment: Payment::Split(1, 2), ..self::order()};
+    assert!(codec::to_entity_with(&split, Key::name("Test", "test"), &options).is_err());
+}
+
+#[test]
+fn enums_written_by_serde_still_read() {
+    let entity = codec::to_entity(&order(), Key::name("Test", "test")).expect("to_entity");
+    let options = codec::Options {
+        enums: codec::Enums::Internal {tag: String::from("type")},
+        ..codec::Options::default()
+    };
+    assert_eq!(codec::from_entity_with::<Order>(entity, &options).expect("from_entity"), order());
+}
+
+#[test]
+fn map_keys_keep_their_type_when_retagging() {
+    let options = codec::Options {
+        enums: codec::Enums::Internal {tag: String::from("type")},
+        ..codec::Options::default()
+    };
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Scores {
+        scores: HashMap<u32, Option<Payment>>,
+    }
+    let scores: HashMap<u32, Option<Payment>> = vec![(7, Some(Payment::Cash)), (8, None)].into_iter().collect();
+    let (stored, decoded) = round_trip_with(&Scores {scores: scores.clone()}, &options);
+    assert_eq!(stored["scores"]["7"], serde_json::json!({"type": "Cash"}));
+    assert_eq!(decoded.scores, scores);
+}
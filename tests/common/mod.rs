@@ -0,0 +1,104 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code, unused_imports, unused_macros)]
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use ddb::{DatastoreClient, Error};
+use ddb::transport::{HttpRequest, HttpResponse, Transport};
+
+///////////////////////////////////////////////////////////////////////////////
+// CASSETTES
+///////////////////////////////////////////////////////////////////////////////
+
+/// A client answering from the recorded cassette.
+pub fn replay(cassette: &str) -> DatastoreClient {
+    DatastoreClient::new_replay(cassette, "dev").expect("replay")
+}
+
+/// A client for the emulator at `DATASTORE_EMULATOR_HOST`, recording to
+/// the cassette.
+pub fn record(cassette: &str) -> DatastoreClient {
+    let host = std::env::var("DATASTORE_EMULATOR_HOST").expect("DATASTORE_EMULATOR_HOST");
+    DatastoreClient::new_emulator(host, "dev")
+        .expect("emulator")
+        .with_recording(cassette)
+}
+
+/// `replays_the_session`, running `session` against the cassette, and
+/// `records_the_session` to re-record it: run that one with
+/// `cargo test --test <file> -- --ignored` and `DATASTORE_EMULATOR_HOST`
+/// set.
+macro_rules! session_tests {
+    ($cassette:expr, $session:expr) => {
+        #[test]
+        fn replays_the_session() {
+            $session(crate::common::replay($cassette));
+        }
+
+        #[test]
+        #[ignore]
+        fn records_the_session() {
+            $session(crate::common::record($cassette));
+        }
+    };
+}
+
+pub(crate) use session_tests;
+
+
+///////////////////////////////////////////////////////////////////////////////
+// SCRIPTED TRANSPORT
+///////////////////////////////////////////////////////////////////////////////
+
+/// Answers requests with canned responses, in order, and keeps what was
+/// sent. Once the responses run out, every request gets an empty `200`.
+#[derive(Default)]
+pub struct Script {
+    responses: RefCell<VecDeque<(u16, serde_json::Value)>>,
+    /// The method (e.g. `commit`) and body of every request.
+    pub requests: RefCell<Vec<(String, serde_json::Value)>>,
+}
+
+impl Script {
+    pub fn new(responses: Vec<serde_json::Value>) -> Rc<Self> {
+        Script::with_status(responses.into_iter().map(|x| (200, x)).collect())
+    }
+    pub fn with_status(responses: Vec<(u16, serde_json::Value)>) -> Rc<Self> {
+        Rc::new(Script {
+            responses: RefCell::new(responses.into()),
+            ..Script::default()
+        })
+    }
+    /// A client sending everything here.
+    pub fn client(self: &Rc<Self>) -> DatastoreClient {
+        DatastoreClient::new_emulator("localhost:8081", "dev")
+            .expect("client")
+            .with_transport(self.clone())
+    }
+    pub fn methods(&self) -> Vec<String> {
+        self.requests.borrow().iter().map(|(x, _)| x.clone()).collect()
+    }
+    /// Bodies of the requests to `method`.
+    pub fn bodies(&self, method: &str) -> Vec<serde_json::Value> {
+        self.requests
+            .borrow()
+            .iter()
+            .filter(|(x, _)| x == method)
+            .map(|(_, x)| x.clone())
+            .collect()
+    }
+}
+
+impl Transport for Script {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Error> {
+        let method = request.url.rsplit(':').next().unwrap_or_default();
+        let body = serde_json::from_str(&request.body).expect("json body");
+        self.requests.borrow_mut().push((String::from(method), body));
+        let (status, body) = self.responses
+            .borrow_mut()
+            .pop_front()
+            .unwrap_or((200, serde_json::json!({})));
+        Ok(HttpResponse {status, body: body.to_string()})
+    }
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use serde_json::json;
+use ddb::{DatastoreClient, Entity, Error, Key};
+use common::Script;
+
+/// Recorded by `records_the_session` against the emulator (started with
+/// `--consistency=1.0`, so kind queries see every write).
+static CASSETTE: &str = "tests/data/cassettes/delete.json";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+fn put(db: &DatastoreClient, key: Key) {
+    let mut entity = Entity::new(key);
+    entity.set("n", 1);
+    db.put_entity(entity).expect("put");
+}
+
+fn account() -> Key {
+    Key::name("Account", "acme")
+}
+
+fn other_order(id: i64) -> Key {
+    Key::name("Account", "other").child_id("Order", id)
+}
+
+/// A page of `Order` keys.
+fn page(ids: &[i64], more: &str, cursor: &str) -> serde_json::Value {
+    let results = ids
+        .iter()
+        .map(|x| json!({"entity": {"key": {"path": [{"kind": "Order", "id": x.to_string()}]}}}))
+        .collect::<Vec<_>>();
+    json!({"batch": {"entityResults": results, "moreResults": more, "endCursor": cursor}})
+}
+
+fn session(db: DatastoreClient) {
+    put(&db, account());
+    put(&db, account().child_id("Order", 1));
+    put(&db, account().child_id("Order", 1).child_name("Line", "a"));
+    put(&db, account().child_id("Order", 2));
+    for id in 3..6 {
+        put(&db, other_order(id));
+    }
+    // DRY RUNS DELETE NOTHING
+    let report = db.delete_subtree(&account()).dry_run().run().expect("dry run");
+    assert!(report.dry_run);
+    assert_eq!(report.keys, vec![
+        account(),
+        account().child_id("Order", 1),
+        account().child_id("Order", 1).child_name("Line", "a"),
+        account().child_id("Order", 2),
+    ]);
+    let kinds = report.kinds();
+    assert_eq!((kinds["Account"], kinds["Order"], kinds["Line"]), (1, 2, 1));
+    assert!(db.get_entity(&account()).is_ok());
+    assert_eq!(db.delete_subtree(&account()).run().expect("delete").count(), 4);
+    assert!(db.get_entity(&account().child_id("Order", 2)).is_err());
+    // THE OTHER ACCOUNT'S ORDERS ARE LEFT, OVER TWO PAGES
+    let report = db.delete_kind("Order").batch_size(2).run().expect("delete");
+    assert_eq!(report.keys, vec![other_order(3), other_order(4), other_order(5)]);
+    assert_eq!(db.delete_kind("Order").dry_run().run().expect("dry run").count(), 0);
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+common::session_tests!(CASSETTE, session);
+
+#[test]
+fn subtrees_need_complete_keys() {
+    let db = common::replay(CASSETTE);
+    let incomplete = Key {namespace: None, path: Vec::new()};
+    assert!(matches!(db.delete_subtree(&incomplete).run(), Err(Error::IncompleteKey)));
+    assert!(matches!(db.put_entity(Entity::default()), Err(Error::IncompleteKey)));
+}
+
+#[test]
+fn empty_pages_that_are_not_finished_keep_going() {
+    let script = Script::new(vec![
+        page(&[1], "NOT_FINISHED", "c1"),
+        json!({}),
+        // THE SCAN STOPPED ON TOMBSTONES
+        page(&[], "NOT_FINISHED", "c2"),
+        page(&[2], "MORE_RESULTS_AFTER_LIMIT", "c3"),
+        json!({}),
+        page(&[], "MORE_RESULTS_AFTER_LIMIT", "c4"),
+    ]);
+    let db = script.client();
+    let report = db.delete_kind("Order").run().expect("delete");
+    assert_eq!(report.keys, vec![Key::id("Order", 1), Key::id("Order", 2)]);
+    assert_eq!(script.methods(), vec!["runQuery", "commit", "runQuery", "runQuery", "commit", "runQuery"]);
+}
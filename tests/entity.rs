@@ -0,0 +1,144 @@
+mod common;
+
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use ddb::{Entity, Key, KeyId, Value};
+use common::Script;
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Account {
+    name: String,
+    owner: Key,
+    seats: i64,
+}
+
+/// A stored entity with one of every value type.
+fn stored() -> serde_json::Value {
+    json!({
+        "key": {"partitionId": {"namespaceId": "test"}, "path": [{"kind": "Parent", "name": "p"}, {"kind": "Row", "id": "7"}]},
+        "properties": {
+            "name": {"stringValue": "row"},
+            "count": {"integerValue": "42"},
+            "ratio": {"doubleValue": 0.5},
+            "done": {"booleanValue": true},
+            "nothing": {"nullValue": "NULL_VALUE"},
+            "created": {"timestampValue": "2020-01-01T00:00:00Z"},
+            "owner": {"keyValue": {"path": [{"kind": "Account", "name": "acme"}]}},
+            "avatar": {"blobValue": "AJ//", "excludeFromIndexes": true},
+            "location": {"geoPointValue": {"latitude": 59.9, "longitude": 10.7}},
+            "tags": {"arrayValue": {"values": [{"stringValue": "a"}, {"integerValue": "1"}]}},
+            "address": {"entityValue": {"properties": {"city": {"stringValue": "Oslo"}}}},
+        },
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn keys_know_their_kind_and_parent() {
+    let key = Key::name("Parent", "p").child_id("Row", 7).with_namespace("test");
+    assert_eq!(key.kind(), Some("Row"));
+    assert_eq!(key.parent(), Some(Key::name("Parent", "p").with_namespace("test")));
+    assert_eq!(Key::name("Parent", "p").parent(), None);
+    assert!(key.is_complete());
+    assert_eq!(key.path[1].id, Some(KeyId::Id(7)));
+    let mut incomplete = Key::name("Parent", "p");
+    incomplete.path[0].id = None;
+    assert!(!incomplete.is_complete());
+}
+
+#[test]
+fn setting_a_property_again_resets_its_index_flag() {
+    let mut entity = Entity::new(Key::name("Row", "a"));
+    entity.set_unindexed("note", "long").set("count", 1_i64);
+    assert_eq!(entity.kind(), Some("Row"));
+    assert!(entity.unindexed.contains("note"));
+    entity.set("note", "short");
+    assert!(entity.unindexed.is_empty());
+    entity.set_unindexed("note", "long");
+    assert_eq!(entity.remove("note"), Some(Value::from("long")));
+    assert!(entity.unindexed.is_empty());
+    assert_eq!(entity.get("count"), Some(&Value::Integer(1)));
+}
+
+#[test]
+fn models_convert_like_the_typed_api() {
+    let account = Account {
+        name: String::from("acme"),
+        owner: Key::name("User", "ada"),
+        seats: 3,
+    };
+    let entity = Entity::from_model(Key::name("Account", "acme"), &account).expect("from model");
+    // KEY FIELDS ARE STORED AS REAL KEYS
+    assert_eq!(entity.get("owner"), Some(&Value::Key(Key::name("User", "ada"))));
+    assert_eq!(entity.to_model::<Account>().expect("to model"), account);
+    assert!(Entity::from_model(Key::name("Account", "acme"), &3).is_err());
+}
+
+#[test]
+fn json_is_plain_and_lossy() {
+    let mut entity = Entity::from_json(Key::name("Row", "a"), json!({"name": "a", "tags": [1, 2]})).expect("from json");
+    assert_eq!(entity.get("tags"), Some(&Value::Array(vec![Value::Integer(1), Value::Integer(2)])));
+    entity.set("created", Value::Timestamp(String::from("2020-01-01T00:00:00Z")));
+    assert_eq!(entity.to_json(), json!({"name": "a", "tags": [1, 2], "created": "2020-01-01T00:00:00Z"}));
+}
+
+#[test]
+fn entities_read_every_value_type() {
+    let script = Script::new(vec![json!({"found": [{"entity": stored()}]})]);
+    let db = script.client();
+    let key = Key::name("Parent", "p").child_id("Row", 7).with_namespace("test");
+    let entity = db.get_entity(&key).expect("get entity");
+    assert_eq!(entity.key, Some(key));
+    assert_eq!(entity.get("count"), Some(&Value::Integer(42)));
+    assert_eq!(entity.get("nothing"), Some(&Value::Null));
+    assert_eq!(entity.get("created"), Some(&Value::Timestamp(String::from("2020-01-01T00:00:00Z"))));
+    assert_eq!(entity.get("owner"), Some(&Value::Key(Key::name("Account", "acme"))));
+    assert_eq!(entity.get("avatar"), Some(&Value::Blob(vec![0, 159, 255])));
+    assert_eq!(entity.get("location"), Some(&Value::GeoPoint {latitude: 59.9, longitude: 10.7}));
+    assert_eq!(entity.get("tags"), Some(&Value::Array(vec![Value::from("a"), Value::from(1_i64)])));
+    match entity.get("address") {
+        Some(Value::Entity(address)) => assert_eq!(address.get("city"), Some(&Value::from("Oslo"))),
+        other => panic!("expecting an embedded entity, got {:?}", other),
+    }
+    assert_eq!(entity.unindexed.iter().collect::<Vec<_>>(), vec!["avatar"]);
+}
+
+#[test]
+fn entities_are_written_as_read() {
+    let script = Script::new(vec![json!({"found": [{"entity": stored()}]})]);
+    let db = script.client();
+    let key = Key::name("Parent", "p").child_id("Row", 7).with_namespace("test");
+    let entity = db.get_entity(&key).expect("get entity");
+    db.put_entity(entity).expect("put entity");
+    let written = &script.bodies("commit")[0]["mutations"][0]["upsert"];
+    assert_eq!(written, &stored());
+}
+
+#[test]
+fn missing_entities_are_not_found() {
+    let script = Script::new(vec![json!({"missing": [{"entity": {"key": {"path": [{"kind": "Row", "name": "a"}]}}}]})]);
+    let db = script.client();
+    assert!(db.get_entity(&Key::name("Row", "a")).is_err());
+    assert_eq!(db.get_entities(&[Key::name("Row", "a")]).expect("get entities"), vec![None]);
+}
+
+#[test]
+fn kinds_are_queried_whole() {
+    let script = Script::new(vec![json!({"batch": {
+        "entityResults": [{"entity": stored()}],
+        "moreResults": "NO_MORE_RESULTS",
+    }})]);
+    let db = script.client();
+    let entities = db.query_kind("Row").expect("query kind");
+    assert_eq!(entities.len(), 1);
+    assert_eq!(entities[0].get("name"), Some(&Value::from("row")));
+    assert_eq!(script.bodies("runQuery")[0]["query"]["kind"], json!([{"name": "Row"}]));
+}
@@ -0,0 +1,121 @@
+use serde::{Serialize, Deserialize};
+use ddb::{Fields, Filter};
+
+///////////////////////////////////////////////////////////////////////////////
+// MODELS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize, Deserialize, Fields)]
+#[serde(rename_all = "camelCase")]
+struct Address {
+    street_name: String,
+    city: String,
+}
+
+#[derive(Serialize, Deserialize, Fields)]
+struct Audit {
+    created_by: String,
+}
+
+#[derive(Serialize, Deserialize, Fields)]
+struct Person {
+    name: String,
+    tags: Vec<String>,
+    #[serde(rename = "years")]
+    age: i64,
+    #[ddb(nested)]
+    address: Address,
+    #[ddb(nested)]
+    previous: Option<Address>,
+    #[serde(flatten)]
+    audit: Audit,
+    #[serde(skip)]
+    #[allow(dead_code)]
+    scratch: String,
+}
+
+#[derive(Serialize, Deserialize, Fields)]
+#[serde(rename_all(serialize = "camelCase", deserialize = "snake_case"))]
+struct Legacy {
+    #[serde(rename(serialize = "displayName", deserialize = "display_name"))]
+    display_name: String,
+    created_by: String,
+    /// Still written, only never read back.
+    #[serde(skip_deserializing)]
+    score: i64,
+    #[serde(skip_serializing)]
+    #[allow(dead_code)]
+    scratch: String,
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn names_follow_serde() {
+    assert_eq!(Person::fields().name().name(), "name");
+    assert_eq!(Person::fields().age().name(), "years");
+    assert_eq!(Address::fields().street_name().name(), "streetName");
+}
+
+#[test]
+fn names_are_the_stored_ones() {
+    let legacy = Legacy {
+        display_name: String::from("x"),
+        created_by: String::from("y"),
+        score: 1,
+        scratch: String::new(),
+    };
+    let stored = serde_json::to_value(&legacy).expect("json");
+    assert_eq!(Legacy::fields().display_name().name(), "displayName");
+    assert_eq!(Legacy::fields().created_by().name(), "createdBy");
+    assert!(stored.get("displayName").is_some());
+    assert!(stored.get("createdBy").is_some());
+}
+
+#[test]
+fn skipped_fields_follow_what_is_written() {
+    let legacy = Legacy {
+        display_name: String::from("x"),
+        created_by: String::from("y"),
+        score: 1,
+        scratch: String::from("z"),
+    };
+    let stored = serde_json::to_value(&legacy).expect("json");
+    assert_eq!(Legacy::fields().score().name(), "score");
+    assert_eq!(stored["score"], 1);
+    assert!(stored.get("scratch").is_none());
+}
+
+#[test]
+fn nested_and_flattened_paths() {
+    assert_eq!(Person::fields().address().city().name(), "address.city");
+    assert_eq!(Person::fields().address().street_name().name(), "address.streetName");
+    assert_eq!(Person::fields().previous().city().name(), "previous.city");
+    assert_eq!(Person::fields().audit().created_by().name(), "created_by");
+}
+
+#[test]
+fn typed_filters_match_untyped() {
+    let typed = Person::fields().age().ge(18)
+        .and(Person::fields().address().city().eq("Paris"));
+    let untyped = Filter::ge("years", 18i64)
+        .and(Filter::eq("address.city", "Paris"));
+    assert_eq!(format!("{:?}", typed), format!("{:?}", untyped));
+    assert_eq!(Person::fields().age().to_string(), "years");
+}
+
+#[test]
+fn typed_operators_match_untyped() {
+    let typed = Person::fields().tags().contains("admin")
+        .or(Person::fields().age().is_in(vec![18, 21]))
+        .or(Person::fields().name().ne("root"));
+    let untyped = Filter::any(vec![
+        Filter::eq("tags", "admin"),
+        Filter::is_in("years", vec![18i64, 21]),
+        Filter::ne("name", "root"),
+    ]);
+    assert_eq!(format!("{:?}", typed), format!("{:?}", untyped));
+}
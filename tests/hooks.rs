@@ -0,0 +1,234 @@
+#![allow(clippy::result_large_err)]
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use ddb::{DatastoreClient, Entity, EntityHooks, EntityKey, Error, Filter, GqlParams, Hooks, Key, KeyId, Value};
+use ddb::bulk::BulkWriter;
+use ddb::transport::{Cassette, Replayer};
+use common::Script;
+
+/// Recorded by `records_the_session` against the emulator.
+static CASSETTE: &str = "tests/data/cassettes/hooks.json";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+thread_local! {
+    static LOADS: Cell<usize> = const { Cell::new(0) };
+    static DELETES: Cell<usize> = const { Cell::new(0) };
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Account {
+    name: String,
+    email: String,
+}
+
+impl EntityKey for Account {
+    fn entity_kind_key() -> String {
+        String::from("Account")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+    fn entity_hooks() -> Option<Hooks> {
+        Some(Hooks::of::<Self>())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Invalid {
+    Email(String),
+    Protected(String),
+}
+
+impl std::fmt::Display for Invalid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Invalid::Email(x) => write!(f, "invalid email: {}", x),
+            Invalid::Protected(x) => write!(f, "{} can't be deleted", x),
+        }
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+impl EntityHooks for Account {
+    type Error = Invalid;
+    fn before_save(&mut self) -> Result<(), Invalid> {
+        self.email = self.email.trim().to_lowercase();
+        if !self.email.contains('@') {
+            return Err(Invalid::Email(self.email.clone()));
+        }
+        Ok(())
+    }
+    fn after_load(&mut self) -> Result<(), Invalid> {
+        LOADS.with(|x| x.set(x.get() + 1));
+        Ok(())
+    }
+    fn before_delete(key: &Key) -> Result<(), Invalid> {
+        DELETES.with(|x| x.set(x.get() + 1));
+        match key.path.last().and_then(|x| x.id.as_ref()) {
+            Some(KeyId::Name(x)) if x == "root" => Err(Invalid::Protected(x.clone())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Drafts are never saved.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Profile {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    draft: Option<String>,
+}
+
+impl EntityKey for Profile {
+    fn entity_kind_key() -> String {
+        String::from("Profile")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+    fn entity_hooks() -> Option<Hooks> {
+        Some(Hooks::of::<Self>())
+    }
+}
+
+impl EntityHooks for Profile {
+    type Error = Invalid;
+    fn before_save(&mut self) -> Result<(), Invalid> {
+        self.draft = None;
+        Ok(())
+    }
+}
+
+fn account(name: &str, email: &str) -> Account {
+    Account {name: String::from(name), email: String::from(email)}
+}
+
+fn hook_error(result: Result<(), Error>) -> Invalid {
+    match result {
+        Err(Error::Hook(e)) => match e.downcast::<Invalid>() {
+            Ok(e) => *e,
+            Err(e) => panic!("unexpected hook error: {}", e),
+        },
+        other => panic!("expected a hook error, got {:?}", other),
+    }
+}
+
+fn loads() -> usize {
+    LOADS.with(Cell::get)
+}
+
+fn session(db: DatastoreClient) {
+    let db = db.with_namespace("hooks");
+    db.delete_all::<Account>().run().expect("delete all");
+    db.upsert(account("a", " A@Example.com ")).expect("upsert");
+    assert_eq!(db.get::<Account, _>("a").expect("get"), account("a", "a@example.com"));
+    let error = hook_error(db.insert(account("b", "nope")));
+    assert_eq!(error, Invalid::Email(String::from("nope")));
+    assert!(matches!(db.get::<Account, _>("b"), Err(Error::NoPayload)));
+    // UNTYPED WRITES TOO, KEEPING WHAT THE MODEL DOESN'T KNOW
+    let mut raw = Entity::new(Key::name("Account", "b"));
+    raw.set("name", "b")
+        .set("email", "B@EXAMPLE.COM")
+        .set("legacy", 42_i64);
+    db.put_entity(raw).expect("put entity");
+    let raw = db.get_entity(&Key::name("Account", "b")).expect("get entity");
+    assert_eq!(raw.get("email"), Some(&Value::from("b@example.com")));
+    assert_eq!(raw.get("legacy"), Some(&Value::from(42_i64)));
+    // EVERY READ, INCLUDING QUERIES
+    let before = loads();
+    let found = db.query::<Account>()
+        .filter(Filter::eq("email", "b@example.com"))
+        .fetch()
+        .expect("query");
+    assert_eq!(found, vec![account("b", "b@example.com")]);
+    db.get_many::<Account, _, _>(vec!["a", "b", "missing"]).expect("get many");
+    assert_eq!(loads(), before + 3);
+    let error = hook_error(db.delete::<Account, _>("root"));
+    assert_eq!(error, Invalid::Protected(String::from("root")));
+    // BULK DELETES GO THROUGH THE HOOK, ONE CALL PER KEY
+    let deletes = DELETES.with(Cell::get);
+    assert_eq!(db.delete_all::<Account>().run().expect("delete all").count(), 2);
+    assert_eq!(DELETES.with(Cell::get), deletes + 2);
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+common::session_tests!(CASSETTE, session);
+
+/// Any request to it fails with `Error::Io`.
+fn offline() -> DatastoreClient {
+    let replayer = Rc::new(Replayer::new(Cassette {interactions: Vec::new()}));
+    DatastoreClient::new_replay("tests/data/cassettes/session.json", "dev")
+        .expect("replay")
+        .with_transport(replayer)
+}
+
+#[test]
+fn aborted_writes_never_reach_datastore() {
+    let db = offline();
+    assert!(matches!(hook_error(db.upsert(account("a", "nope"))), Invalid::Email(_)));
+    assert!(matches!(hook_error(db.delete::<Account, _>("root")), Invalid::Protected(_)));
+}
+
+#[test]
+fn bulk_writes_run_hooks_without_registering() {
+    let result = BulkWriter::new(|| Ok(offline()))
+        .workers(1)
+        .retries(0)
+        .run(vec![account("a", "nope")]);
+    match result {
+        Err(Error::BatchFailed {msg, ..}) => assert!(msg.contains("invalid email"), "{}", msg),
+        other => panic!("expected a failed batch, got {:?}", other.map(|x| x.written)),
+    }
+}
+
+#[test]
+fn untyped_writes_run_registered_hooks() {
+    let db = offline();
+    let mut raw = Entity::new(Key::name("Account", "a"));
+    raw.set("name", "a").set("email", "nope");
+    assert!(matches!(db.put_entity(raw.clone()), Err(Error::Io(_))));
+    db.register_hooks::<Account>();
+    assert!(matches!(hook_error(db.clone().put_entity(raw)), Invalid::Email(_)));
+}
+
+#[test]
+fn hooks_can_remove_properties() {
+    let script = Script::new(Vec::new());
+    let db = script.client();
+    db.register_hooks::<Profile>();
+    let mut raw = Entity::new(Key::name("Profile", "a"));
+    raw.set("name", "a").set("draft", "wip").set("legacy", 1_i64);
+    db.put_entity(raw).expect("put entity");
+    let commits = script.bodies("commit");
+    let properties = &commits[0]["mutations"][0]["upsert"]["properties"];
+    assert!(properties.get("draft").is_none());
+    assert_eq!(properties["legacy"]["integerValue"], "1");
+}
+
+#[test]
+fn gql_reads_run_hooks_without_registering() {
+    let script = Script::new(vec![json!({"batch": {
+        "entityResults": [{"entity": {
+            "key": {"path": [{"kind": "Account", "name": "a"}]},
+            "properties": {"name": {"stringValue": "a"}, "email": {"stringValue": "a@example.com"}},
+        }}],
+        "moreResults": "NO_MORE_RESULTS",
+    }})]);
+    let db = script.client();
+    let before = loads();
+    let found = db.gql::<Account>("SELECT * FROM Account", GqlParams::new()).expect("gql");
+    assert_eq!(found, vec![account("a", "a@example.com")]);
+    assert_eq!(loads(), before + 1);
+}
@@ -0,0 +1,105 @@
+use ddb::{Direction, IndexManifest};
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// A query in the JSON wire format.
+fn required(queries: &[serde_json::Value]) -> IndexManifest {
+    let mut manifest = IndexManifest::new();
+    for query in queries {
+        let query: google_datastore1::Query = serde_json::from_value(query.clone()).expect("query");
+        manifest.add_raw(&query);
+    }
+    manifest
+}
+
+fn eq(name: &str) -> serde_json::Value {
+    serde_json::json!({"propertyFilter": {"property": {"name": name}, "op": "EQUAL", "value": {"booleanValue": true}}})
+}
+
+fn gt(name: &str) -> serde_json::Value {
+    serde_json::json!({"propertyFilter": {"property": {"name": name}, "op": "GREATER_THAN", "value": {"integerValue": "1"}}})
+}
+
+fn composite(op: &str, filters: Vec<serde_json::Value>) -> serde_json::Value {
+    serde_json::json!({"compositeFilter": {"op": op, "filters": filters}})
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn built_in_indexes_need_nothing() {
+    let manifest = required(&[
+        serde_json::json!({"kind": [{"name": "Task"}]}),
+        serde_json::json!({"kind": [{"name": "Task"}], "filter": composite("AND", vec![eq("done"), eq("owner")])}),
+        serde_json::json!({"kind": [{"name": "Task"}], "filter": gt("priority"), "order": [
+            {"property": {"name": "priority"}, "direction": "DESCENDING"},
+        ]}),
+        serde_json::json!({"kind": [{"name": "Task"}], "order": [{"property": {"name": "created"}}]}),
+    ]);
+    assert!(manifest.is_empty(), "{}", manifest.to_yaml());
+}
+
+#[test]
+fn composite_indexes_are_derived() {
+    let manifest = required(&[
+        serde_json::json!({"kind": [{"name": "Task"}], "filter": composite("AND", vec![eq("owner"), eq("done"), gt("priority")]), "order": [
+            {"property": {"name": "priority"}, "direction": "DESCENDING"},
+            {"property": {"name": "created"}},
+        ]}),
+    ]);
+    let indexes = manifest.indexes();
+    assert_eq!(indexes.len(), 1);
+    let properties = indexes[0].properties
+        .iter()
+        .map(|x| (x.name.as_str(), x.direction))
+        .collect::<Vec<_>>();
+    assert_eq!(properties, vec![
+        ("done", Direction::Asc),
+        ("owner", Direction::Asc),
+        ("priority", Direction::Desc),
+        ("created", Direction::Asc),
+    ]);
+}
+
+#[test]
+fn or_branches_need_their_own_index() {
+    let filter = composite("OR", vec![
+        composite("AND", vec![eq("owner"), gt("priority")]),
+        composite("AND", vec![eq("team"), gt("priority")]),
+    ]);
+    let manifest = required(&[serde_json::json!({"kind": [{"name": "Task"}], "filter": filter})]);
+    assert_eq!(manifest.indexes().len(), 2);
+}
+
+#[test]
+fn yaml_round_trips_and_reports_missing() {
+    let needed = required(&[
+        serde_json::json!({"kind": [{"name": "Task"}], "filter": composite("AND", vec![eq("owner"), eq("done"), gt("priority")])}),
+        serde_json::json!({"kind": [{"name": "Task"}], "filter": eq("owner"), "order": [
+            {"property": {"name": "created"}, "direction": "DESCENDING"},
+        ]}),
+    ]);
+    // EQUALITY PROPERTIES MAY BE LISTED IN ANY ORDER
+    let deployed = IndexManifest::from_yaml("
+indexes:
+
+- kind: Task
+  ancestor: no
+  properties:
+  - name: owner
+  - name: done
+  - name: priority
+    direction: asc
+").unwrap();
+    let missing = deployed.missing(&needed);
+    assert_eq!(missing.len(), 1);
+    assert_eq!(missing[0].properties[1].name, "created");
+    let reparsed = IndexManifest::from_yaml(&needed.to_yaml()).unwrap();
+    assert!(reparsed.missing(&needed).is_empty());
+    assert_eq!(reparsed.indexes(), needed.indexes());
+}
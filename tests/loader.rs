@@ -0,0 +1,109 @@
+mod common;
+
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use ddb::{EntityKey, Error, Key};
+use common::Script;
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+}
+
+impl EntityKey for User {
+    fn entity_kind_key() -> String {
+        String::from("User")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+fn found(names: &[&str]) -> serde_json::Value {
+    let found = names
+        .iter()
+        .map(|x| json!({"entity": {
+            "key": {"path": [{"kind": "User", "name": x}]},
+            "properties": {"name": {"stringValue": x}},
+        }}))
+        .collect::<Vec<_>>();
+    json!({"found": found})
+}
+
+/// Names of the keys in each lookup.
+fn lookups(script: &Script) -> Vec<Vec<String>> {
+    script.bodies("lookup")
+        .iter()
+        .map(|x| {
+            x["keys"].as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|x| x["path"][0]["name"].as_str())
+                .map(String::from)
+                .collect()
+        })
+        .collect()
+}
+
+fn name(user: Result<User, Error>) -> String {
+    user.expect("get").name
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn queued_keys_are_looked_up_together() {
+    let script = Script::new(vec![found(&["alice", "bob"])]);
+    let db = script.client();
+    let users = db.loader::<User>();
+    let author = users.load("alice");
+    let reviewers = vec![users.load("bob"), users.load("alice"), users.load("carol")];
+    assert_eq!(author.key(), &Key::name("User", "alice"));
+    assert_eq!(name(author.get()), "alice");
+    let mut reviewers = reviewers.into_iter();
+    assert_eq!(name(reviewers.next().expect("bob").get()), "bob");
+    assert_eq!(name(reviewers.next().expect("alice").get()), "alice");
+    assert!(matches!(reviewers.next().expect("carol").get(), Err(Error::NoPayload)));
+    // DUPLICATES ONCE, AND LOADED KEYS NOT AGAIN
+    assert_eq!(name(users.load("bob").get()), "bob");
+    assert_eq!(lookups(&script), vec![vec!["alice", "bob", "carol"]]);
+}
+
+#[test]
+fn dispatch_looks_up_without_a_get() {
+    let script = Script::new(vec![found(&["alice"]), found(&["alice"])]);
+    let db = script.client();
+    let users = db.loader::<User>();
+    let alice = users.load("alice");
+    users.dispatch().expect("dispatch");
+    users.dispatch().expect("dispatch");
+    assert_eq!(lookups(&script).len(), 1);
+    assert_eq!(name(alice.get()), "alice");
+    // CLEARED, SO LOOKED UP AGAIN
+    users.clear();
+    assert_eq!(name(users.load("alice").get()), "alice");
+    assert_eq!(lookups(&script).len(), 2);
+}
+
+#[test]
+fn failed_lookups_are_tried_again() {
+    let script = Script::with_status(vec![
+        (503, json!({"error": {"code": 503, "message": "unavailable", "status": "UNAVAILABLE"}})),
+        (200, found(&["alice", "bob"])),
+    ]);
+    let db = script.client();
+    let users = db.loader::<User>();
+    let alice = users.load("alice");
+    let bob = users.load("bob");
+    assert_eq!(users.dispatch().err().and_then(|e| e.http_status()), Some(503));
+    assert_eq!(name(alice.get()), "alice");
+    assert_eq!(name(bob.get()), "bob");
+    assert_eq!(lookups(&script), vec![vec!["alice", "bob"], vec!["alice", "bob"]]);
+}
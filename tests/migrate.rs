@@ -0,0 +1,176 @@
+#![allow(clippy::result_large_err)]
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+use serde_json::json;
+use ddb::{DatastoreClient, Entity, Error, Key, Value};
+use ddb::migrate::{Change, Migration, Migrator, WriteMode, MIGRATIONS_KIND};
+use common::Script;
+
+/// Recorded by `records_the_session` against the emulator.
+static CASSETTE: &str = "tests/data/cassettes/migrate.json";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+static NAMES: [&str; 5] = ["a", "b", "c", "d", "e"];
+
+/// Renames `title` to `summary`, failing at `fail_at` (if set) and counting
+/// the entities it sees.
+fn rename(seen: Rc<Cell<u32>>, fail_at: Option<&'static str>) -> Migration {
+    Migration::new(1, "rename title", "Todo", move |mut entity| {
+        seen.set(seen.get() + 1);
+        if fail_at.is_some() && entity.get("name") == fail_at.map(Value::from).as_ref() {
+            return Err(Error::Deserialization {msg: String::from("unreadable title")});
+        }
+        match entity.remove("title") {
+            Some(title) => {
+                entity.set("summary", title);
+                Ok(Change::Put(entity))
+            }
+            None => Ok(Change::Skip),
+        }
+    })
+}
+
+fn session(db: DatastoreClient) {
+    let db = db.with_namespace("migrate");
+    db.delete_kind(MIGRATIONS_KIND).run().expect("delete records");
+    db.delete_kind("Todo").run().expect("delete todos");
+    for name in &NAMES {
+        let mut entity = Entity::new(Key::name("Todo", name));
+        entity.set("name", *name).set("title", format!("Title {}", name));
+        db.put_entity(entity).expect("put entity");
+    }
+    // INTERRUPTED ON THE SECOND PAGE, THE FIRST IS RECORDED
+    let seen = Rc::new(Cell::new(0));
+    let result = Migrator::new(&db)
+        .migration(rename(seen.clone(), Some("c")))
+        .batch_size(2)
+        .run();
+    assert!(result.is_err());
+    let status = Migrator::new(&db).migration(rename(seen.clone(), None)).status().expect("status");
+    assert_eq!((status[0].processed, status[0].done), (2, false));
+    // RESUMES FROM THE SECOND PAGE
+    seen.set(0);
+    let mut pages = 0;
+    let progress = Migrator::new(&db)
+        .migration(rename(seen.clone(), None))
+        .batch_size(2)
+        .on_progress(|_| pages += 1)
+        .run()
+        .expect("run");
+    assert_eq!(seen.get(), 3);
+    assert!(pages >= 2);
+    assert_eq!((progress[0].processed, progress[0].written, progress[0].done), (5, 5, true));
+    for name in &NAMES {
+        let entity = db.get_entity(&Key::name("Todo", name)).expect("get entity");
+        assert_eq!(entity.get("summary"), Some(&Value::from(format!("Title {}", name))));
+        assert_eq!(entity.get("title"), None);
+    }
+    // ALREADY APPLIED
+    seen.set(0);
+    let progress = Migrator::new(&db).migration(rename(seen.clone(), None)).run().expect("run");
+    assert!(progress.is_empty());
+    assert_eq!(seen.get(), 0);
+}
+
+fn todo(name: &str) -> serde_json::Value {
+    json!({
+        "key": {"path": [{"kind": "Todo", "name": name}]},
+        "properties": {
+            "name": {"stringValue": name},
+            "title": {"stringValue": "Title"},
+        },
+    })
+}
+
+fn contention() -> (u16, serde_json::Value) {
+    (409, json!({"error": {"code": 409, "message": "too much contention", "status": "ABORTED"}}))
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+common::session_tests!(CASSETTE, session);
+
+#[test]
+fn transactional_writes_retry_contention() {
+    let script = Script::with_status(vec![
+        (200, json!({"missing": []})),
+        (200, json!({"batch": {"entityResults": [{"entity": todo("a")}], "moreResults": "NO_MORE_RESULTS"}})),
+        (200, json!({"transaction": "dHg="})),
+        (200, json!({"found": [{"entity": todo("a")}]})),
+        contention(),
+        (200, json!({"transaction": "dHg="})),
+        (200, json!({"found": [{"entity": todo("a")}]})),
+    ]);
+    let db = script.client();
+    let progress = Migrator::new(&db)
+        .migration(rename(Rc::new(Cell::new(0)), None))
+        .mode(WriteMode::Transactional)
+        .retry_delay(Duration::ZERO)
+        .run()
+        .expect("run");
+    assert_eq!((progress[0].processed, progress[0].written), (1, 1));
+    assert_eq!(script.methods(), vec![
+        "lookup", "runQuery",
+        "beginTransaction", "lookup", "commit",
+        "beginTransaction", "lookup", "commit",
+        "commit",
+    ]);
+}
+
+#[test]
+fn transactional_writes_give_up_after_the_retries() {
+    let script = Script::with_status(vec![
+        (200, json!({"missing": []})),
+        (200, json!({"batch": {"entityResults": [{"entity": todo("a")}], "moreResults": "NO_MORE_RESULTS"}})),
+        (200, json!({"transaction": "dHg="})),
+        (200, json!({"found": [{"entity": todo("a")}]})),
+        contention(),
+        (200, json!({"transaction": "dHg="})),
+        (200, json!({"found": [{"entity": todo("a")}]})),
+        contention(),
+    ]);
+    let db = script.client();
+    let result = Migrator::new(&db)
+        .migration(rename(Rc::new(Cell::new(0)), None))
+        .mode(WriteMode::Transactional)
+        .retries(1)
+        .retry_delay(Duration::ZERO)
+        .run();
+    assert_eq!(result.err().and_then(|e| e.http_status()), Some(409));
+    // NO PROGRESS RECORDED
+    assert_eq!(script.bodies("commit").len(), 2);
+}
+
+#[test]
+fn empty_pages_that_are_not_finished_are_not_the_end() {
+    let page = |names: &[&str], more: &str| json!({"batch": {
+        "entityResults": names.iter().map(|x| json!({"entity": todo(x)})).collect::<Vec<_>>(),
+        "moreResults": more,
+        "endCursor": "Y3Vyc29y",
+    }});
+    let script = Script::new(vec![
+        json!({"missing": []}),
+        page(&["a"], "NOT_FINISHED"),
+        json!({}),
+        json!({}),
+        page(&[], "NOT_FINISHED"),
+        json!({}),
+        page(&["b"], "NO_MORE_RESULTS"),
+    ]);
+    let db = script.client();
+    let progress = Migrator::new(&db)
+        .migration(rename(Rc::new(Cell::new(0)), None))
+        .run()
+        .expect("run");
+    assert_eq!((progress[0].processed, progress[0].written, progress[0].done), (2, 2, true));
+    assert_eq!(script.bodies("runQuery").len(), 3);
+}
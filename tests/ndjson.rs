@@ -0,0 +1,119 @@
+mod common;
+
+use serde_json::json;
+use ddb::{DatastoreClient, Entity, Key, Value};
+use common::Script;
+
+/// Recorded by `records_the_session` against the emulator.
+static CASSETTE: &str = "tests/data/cassettes/ndjson.json";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+fn keys() -> Vec<Key> {
+    vec![
+        Key::name("Row", "plain"),
+        Key::id("Row", 7),
+        Key::name("Parent", "p").child_name("Row", "child"),
+    ]
+}
+
+/// Every value type, indexed and not.
+fn row(key: Key) -> Entity {
+    let mut address = Entity::default();
+    address.set("city", "Oslo").set_unindexed("note", "embedded");
+    let mut entity = Entity::new(key);
+    entity
+        .set("name", "row")
+        .set("count", 42_i64)
+        .set("ratio", 0.5)
+        .set("done", true)
+        .set("nothing", Value::Null)
+        .set("created", Value::Timestamp(String::from("2020-01-01T00:00:00.5Z")))
+        .set("owner", Key::name("Account", "acme"))
+        .set("avatar", vec![0_u8, 159, 255])
+        .set("location", Value::GeoPoint {latitude: 59.9, longitude: 10.7})
+        .set("tags", Value::Array(vec![Value::from("a"), Value::from(1_i64)]))
+        .set("address", Value::Entity(address))
+        .set_unindexed("description", "x".repeat(2000));
+    entity
+}
+
+fn session(db: DatastoreClient) {
+    let db = db.with_namespace("ndjson");
+    db.delete_kind("Row").run().expect("delete rows");
+    for key in keys() {
+        db.put_entity(row(key)).expect("put entity");
+    }
+    let before = db.get_entities(&keys()).expect("get entities");
+    let mut exported = Vec::new();
+    assert_eq!(db.export_kind("Row", &mut exported).expect("export"), 3);
+    let text = String::from_utf8(exported.clone()).expect("utf-8");
+    assert_eq!(text.lines().count(), 3);
+    assert!(!text.contains("projectId"));
+    db.delete_kind("Row").run().expect("delete rows");
+    assert_eq!(db.get_entities(&keys()).expect("get entities"), vec![None, None, None]);
+    assert_eq!(db.import(&exported[..]).expect("import"), 3);
+    // THE SAME ENTITIES, AND THE SAME FILE WHEN EXPORTED AGAIN
+    assert_eq!(db.get_entities(&keys()).expect("get entities"), before);
+    let mut again = Vec::new();
+    db.export_kind("Row", &mut again).expect("export");
+    assert_eq!(String::from_utf8(again).expect("utf-8"), text);
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+common::session_tests!(CASSETTE, session);
+
+#[test]
+fn lines_without_a_complete_key_are_rejected() {
+    let db = common::replay(CASSETTE);
+    let lines = "\n{\"key\": {\"path\": [{\"kind\": \"Row\"}]}, \"properties\": {}}\n";
+    let error = db.import(lines.as_bytes()).expect_err("import");
+    assert!(error.to_string().contains("line 2"), "{}", error);
+}
+
+#[test]
+fn imports_commit_at_most_500_entities_at_a_time() {
+    let script = Script::new(Vec::new());
+    let db = script.client();
+    let lines = (0..501)
+        .map(|x| format!("{{\"key\": {{\"path\": [{{\"kind\": \"Row\", \"id\": \"{}\"}}]}}}}\n", x + 1))
+        .collect::<String>();
+    assert_eq!(db.import(lines.as_bytes()).expect("import"), 501);
+    let sizes = script.bodies("commit")
+        .iter()
+        .map(|x| x["mutations"].as_array().map(Vec::len).unwrap_or_default())
+        .collect::<Vec<_>>();
+    assert_eq!(sizes, vec![500, 1]);
+}
+
+#[test]
+fn exports_continue_past_empty_pages() {
+    let page = |ids: &[i64], more: &str, cursor: &str| json!({"batch": {
+        "entityResults": ids
+            .iter()
+            .map(|x| json!({"entity": {"key": {"path": [{"kind": "Row", "id": x.to_string()}]}}}))
+            .collect::<Vec<_>>(),
+        "moreResults": more,
+        "endCursor": cursor,
+    }});
+    let script = Script::new(vec![
+        page(&[1], "NOT_FINISHED", "YzE="),
+        page(&[], "NOT_FINISHED", "YzI="),
+        page(&[2], "NO_MORE_RESULTS", "YzM="),
+    ]);
+    let db = script.client();
+    let mut exported = Vec::new();
+    assert_eq!(db.export_kind("Row", &mut exported).expect("export"), 2);
+    assert_eq!(String::from_utf8(exported).expect("utf-8").lines().count(), 2);
+    let cursors = script.bodies("runQuery")
+        .iter()
+        .map(|x| x["query"]["startCursor"].as_str().map(String::from))
+        .collect::<Vec<_>>();
+    assert_eq!(cursors, vec![None, Some(String::from("YzE=")), Some(String::from("YzI="))]);
+}
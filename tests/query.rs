@@ -0,0 +1,163 @@
+mod common;
+
+use std::rc::Rc;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use ddb::{DatastoreClient, EntityKey, Error, GqlParams, Key};
+use common::Script;
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Todo {
+    name: String,
+}
+
+impl EntityKey for Todo {
+    fn entity_kind_key() -> String {
+        String::from("Todo")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+fn script(responses: Vec<serde_json::Value>) -> (DatastoreClient, Rc<Script>) {
+    let script = Script::new(responses);
+    (script.client(), script)
+}
+
+fn batch(names: &[&str], skipped: i32, more: &str, cursor: &str) -> serde_json::Value {
+    let results = names
+        .iter()
+        .map(|x| json!({
+            "entity": {
+                "key": {"path": [{"kind": "Todo", "name": x}]},
+                "properties": {"name": {"stringValue": x}},
+            }
+        }))
+        .collect::<Vec<_>>();
+    json!({
+        "entityResults": results,
+        "skippedResults": skipped,
+        "moreResults": more,
+        "endCursor": cursor,
+    })
+}
+
+/// Limit, offset and cursor of each `runQuery` request.
+fn pages(script: &Script) -> Vec<(Option<i64>, Option<i64>, Option<String>)> {
+    script.bodies("runQuery")
+        .iter()
+        .filter_map(|x| x.get("query"))
+        .map(|x| (
+            x["limit"].as_i64(),
+            x["offset"].as_i64(),
+            x["startCursor"].as_str().map(String::from),
+        ))
+        .collect()
+}
+
+fn names(todos: Vec<Todo>) -> Vec<String> {
+    todos.into_iter().map(|x| x.name).collect()
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn pages_carry_the_rest_of_the_limit_and_offset() {
+    let (db, script) = script(vec![
+        // ONLY PART OF THE OFFSET SKIPPED, NOTHING RETURNED YET
+        json!({"batch": batch(&[], 1, "NOT_FINISHED", "c1")}),
+        json!({"batch": batch(&["a", "b"], 1, "NOT_FINISHED", "c2")}),
+        json!({"batch": batch(&["c"], 0, "MORE_RESULTS_AFTER_LIMIT", "c3")}),
+    ]);
+    let todos = db.query::<Todo>().offset(2).limit(3).fetch().expect("query");
+    assert_eq!(names(todos), vec!["a", "b", "c"]);
+    assert_eq!(pages(&script), vec![
+        (Some(3), Some(2), None),
+        (Some(3), Some(1), Some(String::from("c1"))),
+        (Some(1), None, Some(String::from("c2"))),
+    ]);
+}
+
+#[test]
+fn paging_stops_at_the_limit() {
+    let (db, script) = script(vec![
+        json!({"batch": batch(&["a", "b"], 0, "NOT_FINISHED", "c1")}),
+    ]);
+    let todos = db.query::<Todo>().limit(2).fetch().expect("query");
+    assert_eq!(names(todos), vec!["a", "b"]);
+    assert_eq!(pages(&script).len(), 1);
+}
+
+#[test]
+fn gql_pages_the_parsed_query() {
+    let (db, script) = script(vec![
+        json!({
+            "batch": batch(&["a"], 2, "NOT_FINISHED", "c1"),
+            "query": {"kind": [{"name": "Todo"}], "limit": 2, "offset": 2},
+        }),
+        json!({"batch": batch(&["b"], 0, "NO_MORE_RESULTS", "c2")}),
+    ]);
+    let todos = db.gql::<Todo>("SELECT * FROM Todo LIMIT 2 OFFSET 2", GqlParams::new()).expect("gql");
+    assert_eq!(names(todos), vec!["a", "b"]);
+    assert_eq!(pages(&script), vec![(Some(1), None, Some(String::from("c1")))]);
+}
+
+#[test]
+fn gql_binds_named_and_positional_parameters() {
+    let (db, script) = script(vec![json!({"batch": batch(&["a"], 0, "NO_MORE_RESULTS", "c1")})]);
+    let params = GqlParams::new()
+        .bind("owner", Key::name("User", "ada"))
+        .push("open")
+        .push(3);
+    let todos = db.with_namespace("test")
+        .gql::<Todo>("SELECT * FROM Todo WHERE owner = @owner AND state = @1 LIMIT @2", params)
+        .expect("gql");
+    assert_eq!(names(todos), vec!["a"]);
+    let body = &script.bodies("runQuery")[0];
+    assert_eq!(body["partitionId"]["namespaceId"], "test");
+    assert_eq!(body["gqlQuery"], json!({
+        "queryString": "SELECT * FROM Todo WHERE owner = @owner AND state = @1 LIMIT @2",
+        "namedBindings": {"owner": {"value": {"keyValue": {"path": [{"kind": "User", "name": "ada"}]}}}},
+        "positionalBindings": [
+            {"value": {"stringValue": "open"}},
+            {"value": {"integerValue": "3"}},
+        ],
+        "allowLiterals": true,
+    }));
+}
+
+#[test]
+fn gql_rejects_unrepresentable_parameters() {
+    let (db, script) = script(Vec::new());
+    let params = GqlParams::new().push("open").push(std::collections::HashMap::from([((1, 2), 3)]));
+    let result = db.gql_entities("SELECT * FROM Todo WHERE a = @1 AND b = @2", params);
+    match result {
+        Err(Error::Serialization {msg}) => assert!(msg.contains("@2"), "{}", msg),
+        other => panic!("expecting a serialization error, got {:?}", other),
+    }
+    assert!(script.methods().is_empty());
+}
+
+#[test]
+fn gql_without_a_parsed_query_returns_a_finished_batch() {
+    let (db, script) = script(vec![json!({"batch": batch(&["a", "b"], 0, "NO_MORE_RESULTS", "c1")})]);
+    let entities = db.gql_entities("SELECT * FROM Todo", GqlParams::new()).expect("gql");
+    assert_eq!(entities.len(), 2);
+    assert_eq!(script.methods(), vec!["runQuery"]);
+}
+
+#[test]
+fn gql_without_a_parsed_query_fails_on_more_results() {
+    let (db, script) = script(vec![json!({"batch": batch(&["a", "b"], 0, "NOT_FINISHED", "c1")})]);
+    let result = db.gql_entities("SELECT * FROM Todo", GqlParams::new());
+    assert!(matches!(result, Err(Error::Deserialization {..})), "{:?}", result);
+    assert_eq!(script.methods(), vec!["runQuery"]);
+}
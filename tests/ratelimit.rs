@@ -0,0 +1,50 @@
+use std::time::Duration;
+use ddb::{Access, RateLimiter, Schedule, MAX_WAIT};
+
+fn minutes(x: u64) -> Duration {
+    Duration::from_secs(x * 60)
+}
+
+#[test]
+fn ramp_up_is_500_50_5() {
+    let schedule = Schedule::ramp_up();
+    assert_eq!(schedule.rate_at(Duration::from_secs(0)), 500.0);
+    assert_eq!(schedule.rate_at(minutes(5) - Duration::from_millis(1)), 500.0);
+    assert_eq!(schedule.rate_at(minutes(5)), 750.0);
+    assert_eq!(schedule.rate_at(minutes(12)), 1125.0);
+    assert_eq!(schedule.with_max(1000.0).rate_at(minutes(12)), 1000.0);
+}
+
+#[test]
+fn bursts_then_waits() {
+    let limiter = RateLimiter::new().with_writes(Schedule::per_second(100.0));
+    assert_eq!(limiter.reserve(Access::Write, 100), Duration::from_secs(0));
+    let wait = limiter.reserve(Access::Write, 50);
+    assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500), "{:?}", wait);
+    // READS ARE A SEPARATE BUDGET
+    assert_eq!(limiter.reserve(Access::Read, 10_000), Duration::from_secs(0));
+    assert_eq!(limiter.rate(Access::Read), None);
+}
+
+#[test]
+fn rates_that_are_not_positive_wait_the_longest() {
+    for schedule in [Schedule::Constant(0.0), Schedule::Constant(f64::NAN), Schedule::Constant(-1.0)] {
+        let limiter = RateLimiter::new().with_writes(schedule);
+        assert_eq!(limiter.reserve(Access::Write, 1), MAX_WAIT, "{:?}", schedule);
+    }
+    // A TINY RATE WAITS AT MOST AS LONG
+    let limiter = RateLimiter::new().with_writes(Schedule::per_second(1e-300));
+    assert_eq!(limiter.reserve(Access::Write, 1), MAX_WAIT);
+}
+
+#[test]
+#[should_panic(expected = "rate must be positive")]
+fn zero_rates_are_rejected() {
+    Schedule::per_second(0.0);
+}
+
+#[test]
+#[should_panic(expected = "rate must be positive")]
+fn nan_caps_are_rejected() {
+    Schedule::ramp_up().with_max(f64::NAN);
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use serde::{Serialize, Deserialize};
+use ddb::{DatastoreClient, EntityKey, Filter, Key, LruCache};
+
+/// Recorded by `records_the_session` against the emulator.
+static CASSETTE: &str = "tests/data/cassettes/repository.json";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Note {
+    name: String,
+    tags: Vec<String>,
+    body: String,
+    pinned: bool,
+}
+
+impl EntityKey for Note {
+    fn entity_kind_key() -> String {
+        String::from("Note")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+fn note(name: &str, pinned: bool) -> Note {
+    Note {
+        name: String::from(name),
+        tags: vec![String::from("a"), String::from("b")],
+        body: "lorem ipsum ".repeat(200),
+        pinned,
+    }
+}
+
+fn session(db: DatastoreClient) {
+    let notes = db.repo::<Note>()
+        .with_namespace("repo")
+        .with_unindexed(&["body", "tags"])
+        .with_cache(LruCache::new(10));
+    notes.delete("a").expect("delete");
+    notes.delete("b").expect("delete");
+    notes.insert(note("a", true)).expect("insert");
+    notes.put(note("b", false)).expect("put");
+    assert!(notes.insert(note("a", false)).is_err());
+    assert_eq!(notes.get("a").expect("get"), note("a", true));
+    assert!(notes.exists("b").expect("exists"));
+    assert!(!notes.exists("c").expect("exists"));
+    // THE BODY IS OVER THE INDEX LIMIT, SO THIS ONLY WORKS UNINDEXED
+    let entity = db.get_entity(&Key::name("Note", "a").with_namespace("repo")).expect("entity");
+    assert_eq!(entity.unindexed.iter().collect::<Vec<_>>(), vec!["body", "tags"]);
+    // NOTHING IN THE CLIENT'S NAMESPACE
+    assert!(!db.repo::<Note>().exists("a").expect("exists"));
+    let pinned = notes.query().filter(Filter::eq("pinned", true)).fetch().expect("query");
+    assert_eq!(pinned, vec![note("a", true)]);
+    assert_eq!(notes.count(None).expect("count"), 2);
+    let mut updated = note("b", true);
+    updated.body = String::from("short");
+    notes.update(updated.clone()).expect("update");
+    assert_eq!(notes.get("b").expect("get"), updated);
+    assert_eq!(notes.client().cache_stats().expect("stats").hits, 0);
+    notes.get("b").expect("get");
+    assert_eq!(notes.client().cache_stats().expect("stats").hits, 1);
+    notes.delete("a").expect("delete");
+    assert!(notes.clone().without_cache().get("a").is_err());
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+common::session_tests!(CASSETTE, session);
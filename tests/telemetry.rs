@@ -0,0 +1,45 @@
+use std::time::Duration;
+use ddb::telemetry::{Histogram, Metrics, MetricsRecorder, Operation, Outcome, RpcEvent};
+
+fn event(operation: Operation, millis: u64, outcome: Outcome) -> RpcEvent {
+    RpcEvent {
+        operation,
+        kind: Some(String::from("TodoItem")),
+        keys: 1,
+        namespace: None,
+        latency: Duration::from_millis(millis),
+        outcome,
+    }
+}
+
+#[test]
+fn recorder_counts_per_operation() {
+    let metrics = MetricsRecorder::new();
+    metrics.record(&event(Operation::Lookup, 3, Outcome::Ok));
+    metrics.record(&event(Operation::Lookup, 40, Outcome::Ok));
+    metrics.record(&event(Operation::Commit, 12, Outcome::Error(Some(409))));
+    let lookup = metrics.get(Operation::Lookup);
+    assert_eq!((lookup.calls, lookup.errors), (2, 0));
+    assert_eq!(lookup.latency.mean(), Some(Duration::from_micros(21_500)));
+    let commit = metrics.get(Operation::Commit);
+    assert_eq!((commit.calls, commit.errors), (1, 1));
+    assert_eq!(metrics.snapshot().len(), 2);
+    metrics.reset();
+    assert_eq!(metrics.get(Operation::Lookup).calls, 0);
+}
+
+#[test]
+fn histogram_quantiles_are_bucket_bounds() {
+    let mut histogram = Histogram::default();
+    assert_eq!(histogram.quantile(0.5), None);
+    for millis in &[1, 2, 3, 4, 20, 20, 20, 20, 20, 60_000] {
+        histogram.observe(Duration::from_millis(*millis));
+    }
+    assert_eq!(histogram.count, 10);
+    assert_eq!(histogram.quantile(0.4), Some(Duration::from_millis(5)));
+    assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(25)));
+    // IN THE UNBOUNDED BUCKET
+    assert_eq!(histogram.quantile(1.0), None);
+    let total = histogram.buckets().iter().map(|x| x.1).sum::<u64>();
+    assert_eq!(total, 10);
+}
@@ -0,0 +1,161 @@
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use ddb::{DatastoreClient, EntityKey, Filter, Key, Timestamps, Value};
+use common::Script;
+
+/// Recorded by `records_the_session` against the emulator.
+static CASSETTE: &str = "tests/data/cassettes/timestamps.json";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Post {
+    name: String,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+}
+
+impl EntityKey for Post {
+    fn entity_kind_key() -> String {
+        String::from("Post")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl Timestamps for Post {}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Edit {
+    name: String,
+    modified: Option<String>,
+}
+
+impl EntityKey for Edit {
+    fn entity_kind_key() -> String {
+        String::from("Edit")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl Timestamps for Edit {
+    const CREATED_AT: Option<&'static str> = None;
+    const UPDATED_AT: Option<&'static str> = Some("modified");
+}
+
+fn scripted() -> (DatastoreClient, Rc<Script>) {
+    let script = Script::new(Vec::new());
+    let db = script.client();
+    db.register_timestamps::<Post>();
+    db.register_timestamps::<Edit>();
+    (db, script)
+}
+
+/// The properties of the last committed entity.
+fn written(script: &Script) -> serde_json::Value {
+    let commits = script.bodies("commit");
+    let mutation = &commits.last().expect("a commit")["mutations"][0];
+    let entity = ["insert", "upsert", "update"]
+        .iter()
+        .find_map(|x| mutation.get(*x))
+        .expect("a write");
+    entity["properties"].clone()
+}
+
+thread_local! {
+    static TICKS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A second later on every call, from 2024-01-01, so cassettes replay.
+fn clock() -> SystemTime {
+    let tick = TICKS.with(|x| x.replace(x.get() + 1));
+    UNIX_EPOCH + Duration::from_secs(1_704_067_200 + tick)
+}
+
+/// Range queries and ordering need real timestamps.
+fn session(db: DatastoreClient) {
+    let db = db.with_namespace("timestamps").with_clock(clock);
+    db.register_timestamps::<Post>();
+    db.delete_all::<Post>().run().expect("delete all");
+    db.insert(post("a", None)).expect("insert");
+    db.insert(post("b", None)).expect("insert");
+    let a = db.get::<Post, _>("a").expect("get");
+    assert!(a.created_at.is_some());
+    db.update(a.clone()).expect("update");
+    let updated = db.get::<Post, _>("a").expect("get");
+    assert_eq!(updated.created_at, a.created_at);
+    let raw = db.get_entity(&Key::name("Post", "a")).expect("get entity");
+    assert!(matches!(raw.get("created_at"), Some(Value::Timestamp(_))));
+    assert!(matches!(raw.get("updated_at"), Some(Value::Timestamp(_))));
+    let since = Value::Timestamp(a.created_at.clone().expect("created_at"));
+    let recent = db.query::<Post>()
+        .filter(Filter::gt("updated_at", since))
+        .order_by_desc("updated_at")
+        .fetch()
+        .expect("query");
+    let names = recent.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["a", "b"]);
+    let oldest = db.query::<Post>().order_by("created_at").limit(1).fetch().expect("query");
+    assert_eq!(oldest[0].name, "a");
+}
+
+fn post(name: &str, created_at: Option<&str>) -> Post {
+    Post {
+        name: String::from(name),
+        created_at: created_at.map(String::from),
+        updated_at: None,
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn inserts_set_both() {
+    let (db, script) = scripted();
+    db.insert(post("a", Some("2020-01-01T00:00:00Z"))).expect("insert");
+    let properties = written(&script);
+    let created = properties["created_at"]["timestampValue"].as_str().expect("timestamp");
+    assert_ne!(created, "2020-01-01T00:00:00Z");
+    assert_eq!(properties["updated_at"]["timestampValue"].as_str(), Some(created));
+}
+
+#[test]
+fn updates_keep_created_at() {
+    let (db, script) = scripted();
+    db.update(post("a", Some("2020-01-01T00:00:00.5+01:00"))).expect("update");
+    let properties = written(&script);
+    assert_eq!(properties["created_at"]["timestampValue"], "2019-12-31T23:00:00.500000Z");
+    assert!(properties["updated_at"]["timestampValue"].as_str().expect("timestamp") > "2020");
+    // ONLY INSERTS SET IT
+    db.upsert(post("b", None)).expect("upsert");
+    let properties = written(&script);
+    assert!(properties["created_at"]["timestampValue"].is_null());
+    assert!(properties["updated_at"]["timestampValue"].is_string());
+}
+
+#[test]
+fn fields_can_be_renamed_or_left_out() {
+    let (db, script) = scripted();
+    db.insert(Edit {name: String::from("a"), modified: None}).expect("insert");
+    let properties = written(&script);
+    assert!(properties["modified"]["timestampValue"].is_string());
+    assert!(properties.get("created_at").is_none());
+    // NOT REGISTERED
+    let db = script.client();
+    db.upsert(Edit {name: String::from("c"), modified: None}).expect("upsert");
+    assert!(written(&script)["modified"]["timestampValue"].is_null());
+}
+
+common::session_tests!(CASSETTE, session);
@@ -0,0 +1,93 @@
+mod common;
+
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use ddb::{EntityKey, Tracked};
+use common::Script;
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Profile {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nickname: Option<String>,
+}
+
+impl EntityKey for Profile {
+    fn entity_kind_key() -> String {
+        String::from("Profile")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Written by a newer version of `Profile`, with a `joined` timestamp and
+/// an unindexed `bio`.
+fn stored(nickname: serde_json::Value) -> serde_json::Value {
+    json!({"found": [{"entity": {
+        "key": {"path": [{"kind": "Profile", "name": "ada"}]},
+        "properties": {
+            "name": {"stringValue": "ada"},
+            "nickname": nickname,
+            "joined": {"timestampValue": "2020-01-01T00:00:00Z"},
+            "bio": {"stringValue": "first programmer", "excludeFromIndexes": true},
+        },
+    }}]})
+}
+
+fn written(script: &Script) -> serde_json::Value {
+    script.bodies("commit")[0]["mutations"][0]["upsert"]["properties"].clone()
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn unknown_properties_are_written_back_unchanged() {
+    let script = Script::new(vec![stored(json!({"stringValue": "countess"}))]);
+    let db = script.client();
+    let profile = db.get_tracked::<Profile, _>("ada").expect("get tracked");
+    let mut extra = profile.extra.keys().cloned().collect::<Vec<_>>();
+    extra.sort();
+    assert_eq!(extra, vec!["bio", "joined"]);
+    db.upsert(profile).expect("upsert");
+    let properties = written(&script);
+    assert_eq!(properties["joined"], json!({"timestampValue": "2020-01-01T00:00:00Z"}));
+    assert_eq!(properties["bio"], json!({"stringValue": "first programmer", "excludeFromIndexes": true}));
+    assert_eq!(properties["nickname"]["stringValue"], "countess");
+}
+
+#[test]
+fn fields_skipped_on_write_are_not_extra() {
+    for nickname in [json!({"stringValue": "countess"}), json!({"nullValue": "NULL_VALUE"})] {
+        let script = Script::new(vec![stored(nickname)]);
+        let db = script.client();
+        let mut profile = db.get_tracked::<Profile, _>("ada").expect("get tracked");
+        assert!(!profile.extra.contains_key("nickname"));
+        // SKIPPED BY `skip_serializing_if`, SO THE STORED VALUE MUST NOT COME BACK
+        profile.nickname = None;
+        db.upsert(profile).expect("upsert");
+        let properties = written(&script);
+        assert!(properties.get("nickname").is_none());
+        assert!(properties.get("joined").is_some());
+    }
+}
+
+#[test]
+fn fields_take_precedence_over_extra() {
+    let script = Script::new(Vec::new());
+    let db = script.client();
+    let mut profile = Tracked::new(Profile {name: String::from("ada"), nickname: Some(String::from("new"))});
+    profile.extra.insert(String::from("nickname"), google_datastore1::Value {
+        string_value: Some(String::from("old")),
+        ..google_datastore1::Value::default()
+    });
+    db.upsert(profile).expect("upsert");
+    assert_eq!(written(&script)["nickname"]["stringValue"], "new");
+}
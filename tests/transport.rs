@@ -0,0 +1,127 @@
+mod common;
+
+use std::rc::Rc;
+use serde::{Serialize, Deserialize};
+use ddb::{DatastoreClient, EntityKey, Error, Filter, LruCache};
+use ddb::telemetry::{MetricsRecorder, Operation};
+use ddb::transport::{Cassette, HttpRequest, Interaction, Replayer, Transport};
+
+/// Recorded by `records_the_session` against the emulator.
+static CASSETTE: &str = "tests/data/cassettes/session.json";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Todo {
+    name: String,
+    done: bool,
+}
+
+impl EntityKey for Todo {
+    fn entity_kind_key() -> String {
+        String::from("Todo")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+fn todo(name: &str, done: bool) -> Todo {
+    Todo {name: String::from(name), done}
+}
+
+/// Everything here goes through the client as usual, so the cassette
+/// covers writes, lookups, queries, errors, the cache and the loader.
+fn session(db: DatastoreClient) {
+    let metrics = Rc::new(MetricsRecorder::new());
+    let db = db.with_metrics(metrics.clone());
+    for name in &["a", "b", "c"] {
+        db.delete::<Todo, _>(name).expect("delete");
+    }
+    db.upsert(todo("a", false)).expect("upsert");
+    db.upsert(todo("b", true)).expect("upsert");
+    db.upsert(todo("c", false)).expect("upsert");
+    let error = db.insert(todo("a", true)).expect_err("insert existing");
+    assert!(error.http_status().is_some());
+    assert_eq!(db.get::<Todo, _>("a").expect("get"), todo("a", false));
+    let many = db.get_many::<Todo, _, _>(vec!["b", "missing", "a"]).expect("get many");
+    assert_eq!(many, vec![Some(todo("b", true)), None, Some(todo("a", false))]);
+    let open = db.query::<Todo>()
+        .filter(Filter::eq("done", false))
+        .order_by("name")
+        .fetch()
+        .expect("query");
+    assert_eq!(open, vec![todo("a", false), todo("c", false)]);
+    assert_eq!(db.count::<Todo, _>(None).expect("count"), 3);
+    // ONE LOOKUP FOR BOTH
+    let lookups = metrics.get(Operation::Lookup).calls;
+    let loader = db.loader::<Todo>();
+    let (a, c) = (loader.load("a"), loader.load("c"));
+    assert_eq!(c.get().expect("load"), todo("c", false));
+    assert_eq!(a.get().expect("load"), todo("a", false));
+    assert_eq!(metrics.get(Operation::Lookup).calls, lookups + 1);
+    let cached = db.clone().with_cache(LruCache::new(10));
+    cached.get::<Todo, _>("c").expect("get");
+    cached.get::<Todo, _>("c").expect("get");
+    assert_eq!(cached.cache_stats().expect("stats").hits, 1);
+    db.delete::<Todo, _>("b").expect("delete");
+    assert!(matches!(db.get::<Todo, _>("b"), Err(Error::NoPayload)));
+}
+
+fn interaction(response: serde_json::Value) -> Interaction {
+    Interaction {
+        path: String::from("/v1/projects/dev:lookup"),
+        request: serde_json::json!({"keys": []}),
+        status: 200,
+        response,
+    }
+}
+
+fn lookup(host: &str, body: &str) -> HttpRequest {
+    HttpRequest {
+        url: format!("{}/v1/projects/dev:lookup", host),
+        headers: Vec::new(),
+        body: String::from(body),
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+common::session_tests!(CASSETTE, session);
+
+#[test]
+fn repeated_requests_replay_in_order() {
+    let replayer = Replayer::new(Cassette {
+        interactions: vec![
+            interaction(serde_json::json!({"missing": []})),
+            interaction(serde_json::json!({"found": []})),
+        ],
+    });
+    let first = replayer.send(&lookup("http://localhost:8081", r#"{"keys":[]}"#)).expect("first");
+    let second = replayer.send(&lookup("https://datastore.googleapis.com", r#"{ "keys": [] }"#)).expect("second");
+    assert_eq!(first.body, r#"{"missing":[]}"#);
+    assert_eq!(second.body, r#"{"found":[]}"#);
+    assert_eq!(replayer.remaining(), 0);
+    assert!(matches!(replayer.send(&lookup("http://localhost:8081", r#"{"keys":[]}"#)), Err(Error::Io(_))));
+}
+
+#[test]
+fn unrecorded_requests_fail() {
+    let replayer = Replayer::new(Cassette {
+        interactions: vec![interaction(serde_json::json!({}))],
+    });
+    let result = replayer.send(&lookup("http://localhost:8081", r#"{"keys":[{"path":[]}]}"#));
+    assert!(matches!(result, Err(Error::Io(_))));
+    assert_eq!(replayer.remaining(), 1);
+}
+
+#[test]
+fn missing_cassettes_fail_to_open() {
+    let result = DatastoreClient::new_replay("tests/data/cassettes/missing.json", "dev");
+    assert!(matches!(result, Err(Error::Io(_))));
+}
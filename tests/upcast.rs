@@ -0,0 +1,143 @@
+mod common;
+
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use ddb::{DatastoreClient, Entity, EntityKey, Error, Filter, Key, Upcast, Value, SCHEMA_VERSION_PROPERTY};
+use common::Script;
+
+/// Recorded by `records_the_session` against the emulator.
+static CASSETTE: &str = "tests/data/cassettes/upcast.json";
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Todo {
+    name: String,
+    summary: String,
+    done: bool,
+}
+
+impl EntityKey for Todo {
+    fn entity_kind_key() -> String {
+        String::from("Todo")
+    }
+    fn entity_name_key(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Version 0 had a `title`, version 1 a `state` of "open" or "done".
+impl Upcast for Todo {
+    const VERSION: u32 = 2;
+    fn upcast(version: u32, mut raw: Entity) -> Result<Entity, Error> {
+        match version {
+            0 => {
+                let title = raw.remove("title").unwrap_or(Value::Null);
+                raw.set("summary", title);
+            }
+            _ => {
+                let done = raw.remove("state") == Some(Value::from("done"));
+                raw.set("done", done);
+            }
+        }
+        Ok(raw)
+    }
+}
+
+fn todo(name: &str, summary: &str, done: bool) -> Todo {
+    Todo {name: String::from(name), summary: String::from(summary), done}
+}
+
+fn session(db: DatastoreClient) {
+    let db = db.with_namespace("upcast");
+    for name in &["old", "new"] {
+        db.delete_entity(&Key::name("Todo", name)).expect("delete");
+    }
+    let mut old = Entity::new(Key::name("Todo", "old"));
+    old.set("name", "old").set("title", "Write tests").set("state", "done");
+    db.put_entity(old).expect("put entity");
+    db.register_upcast::<Todo>(true);
+    // STAMPED WITH THE CURRENT VERSION
+    db.upsert(todo("new", "Ship it", false)).expect("upsert");
+    let stored = db.get_entity(&Key::name("Todo", "new")).expect("get entity");
+    assert_eq!(stored.get(SCHEMA_VERSION_PROPERTY), Some(&Value::from(2_i64)));
+    // UPCAST FROM 0 TO 1 TO 2
+    assert_eq!(db.get::<Todo, _>("old").expect("get"), todo("old", "Write tests", true));
+    // AND WRITTEN BACK, SO THE STORED VERSION MATCHES (KEYS-ONLY QUERIES AREN'T UPCAST)
+    let current = db.query_entities("Todo")
+        .filter(Filter::eq(SCHEMA_VERSION_PROPERTY, 2))
+        .keys_only()
+        .expect("query");
+    assert_eq!(current.len(), 2);
+}
+
+fn found(entity: serde_json::Value) -> serde_json::Value {
+    json!({"found": [{"entity": entity}]})
+}
+
+fn version_0(title: &str) -> serde_json::Value {
+    json!({
+        "key": {"path": [{"kind": "Todo", "name": "old"}]},
+        "properties": {
+            "name": {"stringValue": "old"},
+            "title": {"stringValue": title},
+            "state": {"stringValue": "open"},
+        },
+    })
+}
+
+fn scripted(responses: Vec<(u16, serde_json::Value)>) -> (DatastoreClient, std::rc::Rc<Script>) {
+    let script = Script::with_status(responses);
+    let db = script.client();
+    db.register_upcast::<Todo>(true);
+    (db, script)
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+common::session_tests!(CASSETTE, session);
+
+#[test]
+fn writes_back_in_a_transaction() {
+    let (db, script) = scripted(vec![
+        (200, found(version_0("a"))),
+        (200, json!({"transaction": "dHg="})),
+        (200, found(version_0("a"))),
+        (200, json!({})),
+    ]);
+    assert_eq!(db.get::<Todo, _>("old").expect("get"), todo("old", "a", false));
+    assert_eq!(script.methods(), vec!["lookup", "beginTransaction", "lookup", "commit"]);
+    let commit = &script.bodies("commit")[0];
+    assert_eq!(commit["transaction"], "dHg=");
+    assert_eq!(commit["mode"], "TRANSACTIONAL");
+    let written = &commit["mutations"][0]["upsert"]["properties"];
+    assert_eq!(written["summary"]["stringValue"], "a");
+    assert_eq!(written[SCHEMA_VERSION_PROPERTY]["integerValue"], "2");
+}
+
+#[test]
+fn skips_entities_written_in_between() {
+    let (db, script) = scripted(vec![
+        (200, found(version_0("a"))),
+        (200, json!({"transaction": "dHg="})),
+        (200, found(version_0("b"))),
+        (200, json!({})),
+    ]);
+    assert_eq!(db.get::<Todo, _>("old").expect("get"), todo("old", "a", false));
+    assert_eq!(script.methods(), vec!["lookup", "beginTransaction", "lookup", "rollback"]);
+}
+
+#[test]
+fn failed_write_backs_keep_the_read() {
+    let (db, script) = scripted(vec![
+        (200, found(version_0("a"))),
+        (503, json!({"error": {"code": 503, "message": "unavailable", "status": "UNAVAILABLE"}})),
+    ]);
+    assert_eq!(db.get::<Todo, _>("old").expect("get"), todo("old", "a", false));
+    assert_eq!(script.methods(), vec!["lookup", "beginTransaction"]);
+}